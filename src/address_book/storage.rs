@@ -2,7 +2,16 @@ use std::{collections::HashMap, sync::Arc};
 
 use spin::RwLock;
 
-use crate::protocol::{AppInfo, PhoneNumber};
+use atm0s_small_p2p::now_ms;
+use ezk_sip_types::uri::sip::SipUri;
+
+use crate::{
+    hook::{validate_hook_headers, HookCredentialsRef},
+    protocol::{AddressBookStatus, AppInfo, HookAuth, HookFallback, HookSecret, PhoneNumber},
+};
+
+/// How long hooks are also signed with the previous app secret after it changed
+const APP_SECRET_ROTATION_MS: u64 = 24 * 3600 * 1000;
 
 #[derive(Clone)]
 pub struct AddressBookStorage {
@@ -16,10 +25,20 @@ impl AddressBookStorage {
                 root_app: AppInfo {
                     app_id: "".to_owned(),
                     app_secret: root_secret.to_owned(),
+                    max_calls: None,
+                    subscriber_grace_ms: None,
+                    previous_app_secret: None,
+                    previous_expires_at: None,
                 },
+                synced_apps: Default::default(),
+                static_apps: Default::default(),
+                synced_numbers: Default::default(),
+                static_numbers: Default::default(),
                 app_ids: Default::default(),
                 app_secrets: Default::default(),
                 numbers: Default::default(),
+                last_sync_at: None,
+                last_sync_error: None,
             })),
         }
     }
@@ -32,6 +51,24 @@ impl AddressBookStorage {
         self.internal.read().validate_phone(remote, from, to)
     }
 
+    pub fn app(&self, app_id: &str) -> Option<AppInfo> {
+        self.internal.read().app(app_id)
+    }
+
+    /// Current signing secrets of a queued hook, empty if its app or number is gone
+    pub fn hook_secrets(&self, app_id: &str, credentials: &HookCredentialsRef) -> Vec<HookSecret> {
+        self.internal.read().hook_secrets(app_id, credentials)
+    }
+
+    /// Current headers and auth of a queued hook to a number endpoint, None for other hooks
+    pub fn hook_headers(&self, app_id: &str, credentials: &HookCredentialsRef, endpoint: &str) -> Option<(HashMap<String, String>, Option<HookAuth>)> {
+        self.internal.read().hook_headers(app_id, credentials, endpoint)
+    }
+
+    pub fn is_root_secret(&self, app_secret: &str) -> bool {
+        self.internal.read().root_app.app_secret.eq(app_secret)
+    }
+
     pub fn sync_apps(&self, new_apps: Vec<AppInfo>) {
         self.internal.write().sync_apps(new_apps);
     }
@@ -39,13 +76,51 @@ impl AddressBookStorage {
     pub fn sync_numbers(&self, new_numbers: Vec<PhoneNumber>) {
         self.internal.write().sync_numbers(new_numbers);
     }
+
+    /// Replace entries from the config file, they are kept across syncs
+    pub fn set_static(&self, apps: Vec<AppInfo>, numbers: Vec<PhoneNumber>) {
+        let mut internal = self.internal.write();
+        internal.static_apps = apps;
+        internal.static_numbers = numbers;
+        internal.rebuild_apps();
+        internal.rebuild_numbers();
+    }
+
+    /// Result of a sync round, the last error is kept until the next successful sync
+    pub fn set_sync_result(&self, res: Result<(), String>) {
+        let mut internal = self.internal.write();
+        match res {
+            Ok(()) => {
+                internal.last_sync_at = Some(now_ms());
+                internal.last_sync_error = None;
+            }
+            Err(e) => internal.last_sync_error = Some(e),
+        }
+    }
+
+    pub fn status(&self) -> AddressBookStatus {
+        let internal = self.internal.read();
+        AddressBookStatus {
+            apps: internal.app_ids.len() as u32,
+            numbers: internal.numbers.len() as u32,
+            last_sync_at: internal.last_sync_at,
+            last_error: internal.last_sync_error.clone(),
+        }
+    }
 }
 
 struct AddressBookStorageInternal {
     root_app: AppInfo,
+    synced_apps: Vec<AppInfo>,
+    static_apps: Vec<AppInfo>,
+    synced_numbers: Vec<PhoneNumber>,
+    static_numbers: Vec<PhoneNumber>,
+    /// Indexes of static and synced entries, synced entries override static entries with the same key
     app_ids: HashMap<String, AppInfo>,
     app_secrets: HashMap<String, AppInfo>,
     numbers: HashMap<String, PhoneNumber>,
+    last_sync_at: Option<u64>,
+    last_sync_error: Option<String>,
 }
 
 impl AddressBookStorageInternal {
@@ -56,6 +131,13 @@ impl AddressBookStorageInternal {
         self.app_secrets.get(app_secret).cloned()
     }
 
+    pub fn app(&self, app_id: &str) -> Option<AppInfo> {
+        if app_id == self.root_app.app_id {
+            return Some(self.root_app.clone());
+        }
+        self.app_ids.get(app_id).cloned()
+    }
+
     pub fn validate_phone(&self, remote: std::net::SocketAddr, _from: &str, to: &str) -> Option<(AppInfo, PhoneNumber)> {
         let number = self.numbers.get(to)?;
         let app = if number.app_id == self.root_app.app_id {
@@ -71,27 +153,284 @@ impl AddressBookStorageInternal {
         None
     }
 
+    pub fn hook_secrets(&self, app_id: &str, credentials: &HookCredentialsRef) -> Vec<HookSecret> {
+        let app_secret = || self.app(app_id).map(|app| app.hook_secrets()).unwrap_or_default();
+        match credentials {
+            HookCredentialsRef::Call => app_secret(),
+            HookCredentialsRef::Number { number } => match self.numbers.get(number) {
+                Some(number) if number.app_id != app_id => vec![],
+                Some(number) if !number.hook_secrets.is_empty() => number.hook_secrets.clone(),
+                _ => app_secret(),
+            },
+        }
+    }
+
+    pub fn hook_headers(&self, app_id: &str, credentials: &HookCredentialsRef, endpoint: &str) -> Option<(HashMap<String, String>, Option<HookAuth>)> {
+        let HookCredentialsRef::Number { number } = credentials else {
+            return None;
+        };
+        let number = self.numbers.get(number).filter(|number| number.app_id == app_id)?;
+        if number.hook.as_deref() == Some(endpoint) {
+            return Some((number.hook_headers.clone(), number.hook_auth.clone()));
+        }
+        let sub = number.hook_subscriptions.iter().find(|sub| sub.url == endpoint)?;
+        Some((sub.headers.clone(), sub.auth.clone()))
+    }
+
     pub fn sync_apps(&mut self, new_apps: Vec<AppInfo>) {
+        self.synced_apps = new_apps;
+        self.rebuild_apps();
+    }
+
+    pub fn sync_numbers(&mut self, new_numbers: Vec<PhoneNumber>) {
+        self.synced_numbers = new_numbers;
+        self.rebuild_numbers();
+    }
+
+    fn rebuild_apps(&mut self) {
         let pre_len = self.app_ids.len();
-        self.app_ids.clear();
+        let pre_apps = std::mem::take(&mut self.app_ids);
         self.app_secrets.clear();
-        for app in new_apps {
+        for app in self.static_apps.iter().chain(self.synced_apps.iter()) {
+            self.app_ids.insert(app.app_id.clone(), app.clone());
+        }
+        // hooks are also signed with the replaced secret for a while, so receivers can switch to the new one
+        let now = now_ms();
+        for app in self.app_ids.values_mut().filter(|app| app.previous_app_secret.is_none()) {
+            let Some(pre) = pre_apps.get(&app.app_id) else {
+                continue;
+            };
+            if pre.app_secret != app.app_secret {
+                app.previous_app_secret = Some(pre.app_secret.clone());
+                app.previous_expires_at = Some(now + APP_SECRET_ROTATION_MS);
+            } else if pre.previous_expires_at.is_some_and(|expires_at| expires_at > now) {
+                app.previous_app_secret = pre.previous_app_secret.clone();
+                app.previous_expires_at = pre.previous_expires_at;
+            }
+        }
+        // built from the merged apps, so secrets of overridden static apps are not accepted
+        for app in self.app_ids.values() {
             self.app_secrets.insert(app.app_secret.clone(), app.clone());
-            self.app_ids.insert(app.app_id.clone(), app);
         }
         if self.app_ids.len() != pre_len {
             log::info!("[AddressBookStorage] apps len changed from {} to {}", pre_len, self.app_ids.len());
         }
     }
 
-    pub fn sync_numbers(&mut self, new_numbers: Vec<PhoneNumber>) {
+    fn rebuild_numbers(&mut self) {
         let pre_len = self.numbers.len();
         self.numbers.clear();
-        for number in new_numbers {
-            self.numbers.insert(number.number.clone(), number);
+        for number in self.static_numbers.iter().chain(self.synced_numbers.iter()) {
+            if let Err(e) = validate_number(number) {
+                log::warn!("[AddressBookStorage] skip number {}: {e}", number.number);
+                continue;
+            }
+            self.numbers.insert(number.number.clone(), number.clone());
         }
         if self.numbers.len() != pre_len {
-            log::info!("[AddressBookStorage] numbers len changed from {} to {:?}", pre_len, self.numbers);
+            log::info!("[AddressBookStorage] numbers len changed from {} to {}", pre_len, self.numbers.len());
         }
     }
 }
+
+/// Hook headers must be valid and the hook fallback must be usable, so calls to the number don't fail late
+fn validate_number(number: &PhoneNumber) -> Result<(), String> {
+    let headers = std::iter::once(&number.hook_headers).chain(number.hook_subscriptions.iter().map(|sub| &sub.headers));
+    headers.map(validate_hook_headers).collect::<Result<(), _>>()?;
+    match &number.hook_fallback {
+        Some(HookFallback::Reject { code }) if !(300..=699).contains(code) => Err(format!("invalid fallback reject code {code}")),
+        Some(HookFallback::Forward { uri }) if uri.parse::<SipUri>().is_err() => Err(format!("invalid fallback forward uri {uri}")),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(app_id: &str, app_secret: &str) -> AppInfo {
+        AppInfo {
+            app_id: app_id.to_owned(),
+            app_secret: app_secret.to_owned(),
+            max_calls: None,
+            subscriber_grace_ms: None,
+            previous_app_secret: None,
+            previous_expires_at: None,
+        }
+    }
+
+    fn number(number: &str, app_id: &str, hook_secrets: &[&str]) -> PhoneNumber {
+        serde_json::from_value(serde_json::json!({
+            "number": number,
+            "subnets": [],
+            "auth": null,
+            "app_id": app_id,
+            "hook_content_type": "Json",
+            "hook_secrets": hook_secrets.iter().map(|secret| serde_json::json!({ "secret": secret })).collect::<Vec<_>>(),
+        }))
+        .expect("should parse number")
+    }
+
+    fn secrets(storage: &AddressBookStorage, app_id: &str, credentials: &HookCredentialsRef) -> Vec<String> {
+        storage.hook_secrets(app_id, credentials).into_iter().map(|s| s.secret).collect()
+    }
+
+    #[test]
+    fn hook_secrets_are_resolved_from_current_entries() {
+        let storage = AddressBookStorage::new("root");
+        storage.set_static(vec![app("app1", "secret1")], vec![number("100", "app1", &[]), number("200", "app1", &["number-secret"])]);
+
+        assert_eq!(secrets(&storage, "app1", &HookCredentialsRef::Call), vec!["secret1"]);
+        assert_eq!(secrets(&storage, "app1", &HookCredentialsRef::Number { number: "100".to_owned() }), vec!["secret1"]);
+        assert_eq!(secrets(&storage, "app1", &HookCredentialsRef::Number { number: "200".to_owned() }), vec!["number-secret"]);
+        // a number moved to another app doesn't sign with its secrets
+        assert!(secrets(&storage, "app2", &HookCredentialsRef::Number { number: "200".to_owned() }).is_empty());
+
+        // rotated app secret is used by queued hooks, together with the previous one
+        storage.sync_apps(vec![app("app1", "secret2")]);
+        assert_eq!(secrets(&storage, "app1", &HookCredentialsRef::Call), vec!["secret2", "secret1"]);
+        storage.set_static(vec![], vec![]);
+        storage.sync_apps(vec![]);
+        assert!(secrets(&storage, "app1", &HookCredentialsRef::Call).is_empty());
+    }
+
+    #[test]
+    fn rotated_app_secret_signs_until_expired() {
+        let storage = AddressBookStorage::new("root");
+        storage.sync_apps(vec![app("app1", "secret1")]);
+        assert_eq!(secrets(&storage, "app1", &HookCredentialsRef::Call), vec!["secret1"]);
+
+        storage.sync_apps(vec![app("app1", "secret2")]);
+        let rotated = storage.hook_secrets("app1", &HookCredentialsRef::Call);
+        assert_eq!(rotated.len(), 2);
+        assert_eq!(rotated[0].expires_at, None);
+        assert_eq!(rotated[1].secret, "secret1");
+        assert!(rotated[1].expires_at.is_some_and(|expires_at| expires_at > now_ms()));
+        // only the current secret is accepted by the api
+        assert!(storage.validate_app("secret1").is_none());
+
+        // the window is kept across syncs of the same secret
+        storage.sync_apps(vec![app("app1", "secret2")]);
+        assert_eq!(secrets(&storage, "app1", &HookCredentialsRef::Call), vec!["secret2", "secret1"]);
+
+        // the expired previous secret is dropped at the next sync
+        storage.internal.write().app_ids.get_mut("app1").expect("should have app").previous_expires_at = Some(now_ms() - 1);
+        storage.sync_apps(vec![app("app1", "secret2")]);
+        assert_eq!(secrets(&storage, "app1", &HookCredentialsRef::Call), vec!["secret2"]);
+
+        // a previous secret provided by the source is kept as is
+        let mut provided = app("app1", "secret3");
+        provided.previous_app_secret = Some("secret0".to_owned());
+        storage.sync_apps(vec![provided]);
+        assert_eq!(secrets(&storage, "app1", &HookCredentialsRef::Call), vec!["secret3", "secret0"]);
+    }
+
+    #[test]
+    fn synced_apps_override_static_apps() {
+        let storage = AddressBookStorage::new("root");
+        let app_id = |app_secret: &str| storage.validate_app(app_secret).map(|app| app.app_id);
+        storage.sync_apps(vec![app("app1", "synced1"), app("app3", "synced3")]);
+        storage.set_static(vec![app("app1", "static1"), app("app2", "static2")], vec![]);
+
+        assert_eq!(storage.app("app1").map(|app| app.app_secret).as_deref(), Some("synced1"));
+        assert_eq!(app_id("synced1").as_deref(), Some("app1"));
+        assert_eq!(app_id("static1"), None);
+        assert_eq!(app_id("static2").as_deref(), Some("app2"));
+        assert_eq!(app_id("synced3").as_deref(), Some("app3"));
+        assert_eq!(storage.status().apps, 3);
+
+        // the static app is back when the sync drops it
+        storage.sync_apps(vec![]);
+        assert_eq!(app_id("static1").as_deref(), Some("app1"));
+        assert_eq!(app_id("synced1"), None);
+        assert_eq!(app_id("synced3"), None);
+    }
+
+    #[test]
+    fn hook_headers_are_resolved_by_endpoint() {
+        let storage = AddressBookStorage::new("root");
+        let number: PhoneNumber = serde_json::from_value(serde_json::json!({
+            "number": "100",
+            "subnets": [],
+            "auth": null,
+            "app_id": "app1",
+            "hook": "http://hook",
+            "hook_content_type": "Json",
+            "hook_headers": { "X-Tenant": "main" },
+            "hook_subscriptions": [{ "url": "http://sub", "content_type": "Json", "headers": { "X-Tenant": "sub" } }],
+        }))
+        .expect("should parse number");
+        storage.set_static(vec![app("app1", "secret1")], vec![number]);
+
+        let credentials = HookCredentialsRef::Number { number: "100".to_owned() };
+        let tenant = |endpoint: &str| storage.hook_headers("app1", &credentials, endpoint).map(|(headers, _)| headers["X-Tenant"].clone());
+        assert_eq!(tenant("http://hook").as_deref(), Some("main"));
+        assert_eq!(tenant("http://sub").as_deref(), Some("sub"));
+        assert_eq!(tenant("http://other"), None);
+        assert!(storage.hook_headers("app1", &HookCredentialsRef::Call, "http://hook").is_none());
+    }
+
+    #[test]
+    fn number_debug_redacts_hook_credentials() {
+        let mut number = number("100", "app1", &["number-secret"]);
+        number.hook_headers.insert("Authorization".to_owned(), "Bearer header-token".to_owned());
+        number.hook_subscriptions = serde_json::from_value(serde_json::json!([{
+            "url": "http://sub",
+            "content_type": "Json",
+            "headers": { "X-Api-Key": "sub-key" },
+            "auth": { "type": "Bearer", "token": "sub-token" },
+        }]))
+        .expect("should parse subscriptions");
+
+        let debug = format!("{number:?}");
+        assert!(debug.contains("Authorization") && debug.contains("X-Api-Key"));
+        for secret in ["header-token", "sub-key", "sub-token", "number-secret"] {
+            assert!(!debug.contains(secret), "{secret} in {debug}");
+        }
+    }
+
+    #[test]
+    fn numbers_with_invalid_hook_headers_are_skipped() {
+        let storage = AddressBookStorage::new("root");
+        let mut reserved = number("100", "app1", &[]);
+        reserved.hook_headers.insert("X-Hook-Timestamp".to_owned(), "0".to_owned());
+        let mut invalid = number("200", "app1", &[]);
+        invalid.hook_headers.insert("X Tenant".to_owned(), "tenant1".to_owned());
+        storage.set_static(vec![app("app1", "secret1")], vec![reserved, invalid, number("300", "app1", &[])]);
+
+        let internal = storage.internal.read();
+        assert!(!internal.numbers.contains_key("100"));
+        assert!(!internal.numbers.contains_key("200"));
+        assert!(internal.numbers.contains_key("300"));
+    }
+
+    #[test]
+    fn numbers_with_invalid_hook_fallback_are_skipped() {
+        let with_fallback = |num: &str, fallback: HookFallback| {
+            let mut number = number(num, "app1", &[]);
+            number.hook_fallback = Some(fallback);
+            number
+        };
+        let storage = AddressBookStorage::new("root");
+        storage.set_static(
+            vec![app("app1", "secret1")],
+            vec![
+                with_fallback("100", HookFallback::Reject { code: 200 }),
+                with_fallback("200", HookFallback::Reject { code: 700 }),
+                with_fallback("300", HookFallback::Reject { code: 503 }),
+                with_fallback("400", HookFallback::Forward { uri: "not a uri".to_owned() }),
+                with_fallback(
+                    "500",
+                    HookFallback::Forward {
+                        uri: "sip:backup@pbx.example.com".to_owned(),
+                    },
+                ),
+            ],
+        );
+
+        let internal = storage.internal.read();
+        let mut numbers: Vec<_> = internal.numbers.keys().cloned().collect();
+        numbers.sort();
+        assert_eq!(numbers, vec!["300", "500"]);
+    }
+}
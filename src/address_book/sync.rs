@@ -1,8 +1,12 @@
 use std::time::Duration;
 
+use atm0s_small_p2p::now_ms;
 use tokio::time::sleep;
 
-use crate::protocol::{AppsSyncResponse, PhoneNumbersSyncResponse};
+use crate::{
+    metrics,
+    protocol::{AppsSyncResponse, PhoneNumbersSyncResponse},
+};
 
 use super::AddressBookStorage;
 
@@ -49,8 +53,17 @@ impl AddressBookSync {
 
     pub async fn run_loop(&mut self) {
         loop {
-            if let Err(e) = self.sync().await {
-                log::error!("[AddressBookSync] sync error {e:?}");
+            match self.sync().await {
+                Ok(()) => {
+                    metrics::ADDRESS_BOOK_SYNCS.with_label_values(&["ok"]).inc();
+                    metrics::ADDRESS_BOOK_LAST_SYNC.set(now_ms() as f64 / 1000.0);
+                    self.storage.set_sync_result(Ok(()));
+                }
+                Err(e) => {
+                    log::error!("[AddressBookSync] sync error {e:?}");
+                    metrics::ADDRESS_BOOK_SYNCS.with_label_values(&["error"]).inc();
+                    self.storage.set_sync_result(Err(e.to_string()));
+                }
             }
             sleep(self.interval).await;
         }
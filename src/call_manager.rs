@@ -2,25 +2,48 @@ use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::Duration,
 };
 
 use atm0s_small_p2p::pubsub_service::PubsubServiceRequester;
+use call_meta::CallMeta;
+use cdr::CdrBuilder;
 use incoming_call::IncomingCall;
 use outgoing_call::OutgoingCall;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    watch,
+};
 
 use crate::{
     address_book::AddressBookStorage,
-    hook::HttpHook,
-    protocol::{protobuf::sip_gateway::CallEvent, CallApiError, CallDirection, CreateCallRequest, CreateCallResponse, InternalCallId},
+    cdr::CdrWriter,
+    cluster::ClusterCallCounter,
+    config::CallLimits,
+    drain::DrainState,
+    hook::{validate_hook_headers, HookCredentialsRef, HttpHook, HttpHookTarget},
+    metrics,
+    protocol::{
+        protobuf::sip_gateway::{CallDetailRecord, CallEvent},
+        AppId, CallApiError, CallDirection, CallInfo, CreateCallRequest, CreateCallResponse, InternalCallId,
+    },
     secure::{CallToken, SecureContext},
-    sip::{MediaApi, SipServer},
+    sip::{MediaApi, SipServer, SipTrace},
     utils::select2,
 };
 
+mod call_meta;
+mod cdr;
+mod history;
 pub mod incoming_call;
 pub mod outgoing_call;
 
+/// Timeout of the incoming call notify hook when the number doesn't set it
+const DEFAULT_HOOK_TIMEOUT_MS: u64 = 5_000;
+
+/// Retry-After of incoming calls which are rejected while the node is draining
+const DRAIN_RETRY_AFTER_SECS: u32 = 30;
+
 pub enum CallManagerOut {
     Continue,
     IncomingCall(),
@@ -32,14 +55,23 @@ pub struct CallManager {
     http_hook: HttpHook<CallEvent>,
     out_calls: HashMap<InternalCallId, OutgoingCall>,
     in_calls: HashMap<InternalCallId, IncomingCall>,
-    destroy_tx: UnboundedSender<InternalCallId>,
-    destroy_rx: UnboundedReceiver<InternalCallId>,
+    destroy_tx: UnboundedSender<(InternalCallId, CallDetailRecord)>,
+    destroy_rx: UnboundedReceiver<(InternalCallId, CallDetailRecord)>,
     secure_ctx: Arc<SecureContext>,
     address_book: AddressBookStorage,
+    call_counter: ClusterCallCounter,
+    cdr_writer: CdrWriter,
     media_gateway: String,
+    subscriber_grace: Duration,
+    sip_trace: SipTrace,
+    drain: DrainState,
+    limits: CallLimits,
+    /// Set when calls must end before the node exits, each call watches it
+    force_end_tx: watch::Sender<bool>,
 }
 
 impl CallManager {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         call_pubsub: PubsubServiceRequester,
         sip_listen: SocketAddr,
@@ -47,10 +79,17 @@ impl CallManager {
         address_book: AddressBookStorage,
         secure_ctx: Arc<SecureContext>,
         http_hook: HttpHook<CallEvent>,
+        call_counter: ClusterCallCounter,
+        cdr_writer: CdrWriter,
         media_gateway: &str,
+        subscriber_grace: Duration,
+        sip_trace: SipTrace,
+        drain: DrainState,
+        limits: CallLimits,
     ) -> Self {
-        let sip = SipServer::new(sip_listen, public_ip).await.expect("should create sip-server");
+        let sip = SipServer::new(sip_listen, public_ip, sip_trace.clone()).await.expect("should create sip-server");
         let (destroy_tx, destroy_rx) = unbounded_channel();
+        let (force_end_tx, _) = watch::channel(false);
         Self {
             call_pubsub,
             sip,
@@ -61,18 +100,77 @@ impl CallManager {
             destroy_rx,
             secure_ctx,
             address_book,
+            call_counter,
+            cdr_writer,
             media_gateway: media_gateway.to_owned(),
+            subscriber_grace,
+            sip_trace,
+            drain,
+            limits,
+            force_end_tx,
         }
     }
 
-    pub fn create_call(&mut self, req: CreateCallRequest, media_api: MediaApi) -> Result<CreateCallResponse, CallApiError> {
-        let hook_sender = self.http_hook.new_sender(&req.hook, HashMap::new());
+    #[tracing::instrument(name = "create_call", skip_all, fields(app_id = %app_id, call_id))]
+    pub fn create_call(&mut self, app_id: AppId, req: CreateCallRequest, media_api: MediaApi) -> Result<CreateCallResponse, CallApiError> {
+        if self.drain.is_draining() {
+            log::warn!("[CallManager] node is draining => reject create call of app {app_id}");
+            return Err(CallApiError::NodeDraining);
+        }
+
+        if self.is_node_full() {
+            log::warn!("[CallManager] node reached call limit {:?} => reject create call of app {app_id}", self.limits.node_max_calls);
+            return Err(CallApiError::CallLimitReached);
+        }
+
+        let app = self.address_book.app(&app_id);
+        let max_calls = self.app_max_calls(app.as_ref().and_then(|app| app.max_calls));
+        if self.is_limit_reached(&app_id, max_calls) {
+            log::warn!("[CallManager] app {app_id} reached call limit {max_calls:?} => reject create call");
+            return Err(CallApiError::CallLimitReached);
+        }
+
+        let headers = req.hook_headers.iter().chain(req.hook_subscriptions.iter().flatten().map(|sub| &sub.headers));
+        if let Err(e) = headers.map(validate_hook_headers).collect::<Result<(), _>>() {
+            log::warn!("[CallManager] reject create call of app {app_id}: {e}");
+            return Err(CallApiError::BadRequest("invalid hook headers"));
+        }
+
+        let subscriber_grace = req
+            .subscriber_grace_ms
+            .or(app.as_ref().and_then(|app| app.subscriber_grace_ms))
+            .map(Duration::from_millis)
+            .unwrap_or(self.subscriber_grace);
         let from = format!("sip:{}@{}", req.from_number, req.sip_server);
         let to = format!("sip:{}@{}", req.to_number, req.sip_server);
         let proxy_url = req.sip_proxy.map(|p| format!("sip:{}@{}", req.to_number, p));
+        let stream = req.streaming.clone();
         match self.sip.make_call(media_api, &from, &to, proxy_url.as_deref(), req.sip_auth, req.streaming) {
             Ok(call) => {
                 let call_id = call.call_id();
+                tracing::Span::current().record("call_id", tracing::field::display(&call_id));
+                let hook_secrets = app.map(|app| app.hook_secrets()).unwrap_or_default();
+                let primary = HttpHookTarget {
+                    endpoint: req.hook.clone(),
+                    content_type: req.hook_content_type,
+                    headers: req.hook_headers.unwrap_or_default(),
+                    auth: req.hook_auth,
+                    secrets: hook_secrets.clone(),
+                    credentials_ref: HookCredentialsRef::Call,
+                    events: vec![],
+                };
+                let subscriptions = req
+                    .hook_subscriptions
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|sub| HttpHookTarget::from_subscription(sub, hook_secrets.clone(), HookCredentialsRef::Call))
+                    .collect();
+                let hook_sender = self.http_hook.new_sender(&call_id, &app_id, Some(primary), subscriptions);
+                let mut cdr = CdrBuilder::new(&call_id, &app_id, CallDirection::Outgoing, &req.from_number, &req.to_number, &req.sip_server);
+                cdr.set_stream(&stream);
+                self.set_sip_trace(&call_id, &app_id, &mut cdr);
+                let meta = CallMeta::new(&call_id, &app_id, CallDirection::Outgoing, call.state(), &req.from_number, &req.to_number);
+                meta.set_stream(&stream);
                 let call_token = self.secure_ctx.encode_call_token(
                     CallToken {
                         direction: CallDirection::Outgoing,
@@ -80,9 +178,21 @@ impl CallManager {
                     },
                     3600,
                 );
+                self.call_counter.increase(&app_id);
+                metrics::CALLS_CREATED.with_label_values(&[metrics::direction_label(CallDirection::Outgoing)]).inc();
                 self.out_calls.insert(
                     call_id.clone(),
-                    OutgoingCall::new(call, self.destroy_tx.clone(), req.hook_content_type, hook_sender, self.call_pubsub.clone()),
+                    OutgoingCall::new(
+                        app_id,
+                        meta,
+                        call,
+                        cdr,
+                        self.destroy_tx.clone(),
+                        hook_sender,
+                        self.call_pubsub.clone(),
+                        subscriber_grace,
+                        self.force_end_tx.subscribe(),
+                    ),
                 );
                 Ok(CreateCallResponse {
                     call_ws: format!("/call/outgoing/{call_id}?token={call_token}"),
@@ -94,21 +204,114 @@ impl CallManager {
         }
     }
 
+    /// List active calls on this node, None app_id means all apps
+    pub fn list_calls(&self, app_id: Option<&AppId>) -> Vec<CallInfo> {
+        let out_calls = self.out_calls.values().filter(|c| app_id.is_none_or(|app_id| c.app_id() == app_id)).map(|c| c.meta().info());
+        let in_calls = self.in_calls.values().filter(|c| app_id.is_none_or(|app_id| c.app_id() == app_id)).map(|c| c.meta().info());
+        out_calls.chain(in_calls).collect()
+    }
+
+    /// Get an active call on this node, None app_id means any app
+    pub fn get_call(&self, call_id: &InternalCallId, app_id: Option<&AppId>) -> Option<CallInfo> {
+        let (call_app, meta) = if let Some(call) = self.out_calls.get(call_id) {
+            (call.app_id(), call.meta())
+        } else {
+            let call = self.in_calls.get(call_id)?;
+            (call.app_id(), call.meta())
+        };
+        if app_id.is_some_and(|app_id| app_id != call_app) {
+            return None;
+        }
+        Some(meta.info())
+    }
+
+    /// Number of active calls on this node
+    pub fn calls(&self) -> usize {
+        self.out_calls.len() + self.in_calls.len()
+    }
+
+    /// No active calls and no pending hook events, so the node can exit without losing them
+    pub fn is_idle(&self) -> bool {
+        self.calls() == 0 && self.http_hook.queue_stats().iter().all(|stats| stats.depth == 0 && stats.in_flight == 0)
+    }
+
+    /// End all active calls, used when the node can't wait for them anymore
+    pub fn end_all_calls(&self) {
+        log::warn!("[CallManager] force end {} calls", self.calls());
+        self.force_end_tx.send_replace(true);
+    }
+
+    /// Write pending records to CDR sinks, called before the node exits
+    pub async fn shutdown(self) {
+        self.cdr_writer.close().await;
+    }
+
     pub async fn recv(&mut self) -> Option<CallManagerOut> {
         let out = select2::or(self.destroy_rx.recv(), self.sip.recv()).await;
         match out {
-            select2::OrOutput::Left(call_id) => {
-                let call_id = call_id?;
-                if self.out_calls.remove(&call_id).is_none() && self.in_calls.remove(&call_id).is_none() {
+            select2::OrOutput::Left(destroyed) => {
+                let (call_id, record) = destroyed?;
+                self.cdr_writer.write(&record);
+                metrics::on_call_ended(&record);
+                if let Some(call) = self.out_calls.remove(&call_id) {
+                    self.call_counter.decrease(call.app_id());
+                } else if let Some(call) = self.in_calls.remove(&call_id) {
+                    self.call_counter.decrease(call.app_id());
+                } else {
                     log::warn!("[CallManager] got Destroyed event for {call_id} but not found");
                 }
                 Some(CallManagerOut::Continue)
             }
             select2::OrOutput::Right(event) => match event? {
                 crate::sip::SipServerOut::Incoming(call) => {
+                    if self.drain.is_draining() {
+                        log::warn!(
+                            "[CallManager] node is draining => reject call from server {} with number {} => {}",
+                            call.remote(),
+                            call.from(),
+                            call.to()
+                        );
+                        call.kill_because_draining(DRAIN_RETRY_AFTER_SECS);
+                        return Some(CallManagerOut::Continue);
+                    }
+                    if self.is_node_full() {
+                        log::warn!("[CallManager] node reached call limit {:?} => reject call {} => {}", self.limits.node_max_calls, call.from(), call.to());
+                        call.kill_because_overloaded();
+                        return Some(CallManagerOut::Continue);
+                    }
                     if let Some((app, number)) = self.address_book.validate_phone(call.remote(), call.from(), call.to()) {
-                        let hook_sender = self.http_hook.new_sender(&number.hook, HashMap::new());
+                        let app_id: AppId = app.app_id.clone().into();
+                        let max_calls = self.app_max_calls(app.max_calls);
+                        if self.is_limit_reached(&app_id, max_calls) {
+                            log::warn!("[CallManager] app {app_id} reached call limit {max_calls:?} => reject call {} => {}", call.from(), call.to());
+                            self.sip_trace.set_app(&call.call_id(), &app_id);
+                            call.kill_because_limit_reached();
+                            return Some(CallManagerOut::Continue);
+                        }
+
+                        let subscriber_grace = app.subscriber_grace_ms.map(Duration::from_millis).unwrap_or(self.subscriber_grace);
                         let call_id = call.call_id();
+                        let hook_secrets = if number.hook_secrets.is_empty() {
+                            app.hook_secrets()
+                        } else {
+                            number.hook_secrets.clone()
+                        };
+                        let credentials_ref = HookCredentialsRef::Number { number: number.number.clone() };
+                        let primary = number.hook.as_ref().map(|hook| HttpHookTarget {
+                            endpoint: hook.clone(),
+                            content_type: number.hook_content_type,
+                            headers: number.hook_headers.clone(),
+                            auth: number.hook_auth.clone(),
+                            secrets: hook_secrets.clone(),
+                            credentials_ref: credentials_ref.clone(),
+                            events: vec![],
+                        });
+                        let subscriptions = number
+                            .hook_subscriptions
+                            .iter()
+                            .map(|sub| HttpHookTarget::from_subscription(sub.clone(), hook_secrets.clone(), credentials_ref.clone()))
+                            .collect();
+                        let hook_sender = self.http_hook.new_sender(&call_id, &app_id, primary, subscriptions);
                         let call_token = self.secure_ctx.encode_call_token(
                             CallToken {
                                 direction: CallDirection::Outgoing,
@@ -117,7 +320,26 @@ impl CallManager {
                             3600,
                         );
                         let api: MediaApi = MediaApi::new(&self.media_gateway, &app.app_secret);
-                        let call = IncomingCall::new(api, call, call_token, self.destroy_tx.clone(), number.hook_content_type, hook_sender, self.call_pubsub.clone());
+                        let mut cdr = CdrBuilder::new(&call_id, &app_id, CallDirection::Incoming, call.from(), call.to(), &call.remote().to_string());
+                        self.set_sip_trace(&call_id, &app_id, &mut cdr);
+                        let meta = CallMeta::new(&call_id, &app_id, CallDirection::Incoming, call.state(), call.from(), call.to());
+                        self.call_counter.increase(&app_id);
+                        metrics::CALLS_CREATED.with_label_values(&[metrics::direction_label(CallDirection::Incoming)]).inc();
+                        let call = IncomingCall::new(
+                            app_id,
+                            meta,
+                            api,
+                            call,
+                            call_token,
+                            cdr,
+                            self.destroy_tx.clone(),
+                            hook_sender,
+                            self.call_pubsub.clone(),
+                            subscriber_grace,
+                            number.hook_timeout_ms.map(Duration::from_millis).unwrap_or(Duration::from_millis(DEFAULT_HOOK_TIMEOUT_MS)),
+                            number.hook_fallback.clone(),
+                            self.force_end_tx.subscribe(),
+                        );
                         self.in_calls.insert(call_id, call);
                         Some(CallManagerOut::IncomingCall())
                     } else {
@@ -129,4 +351,34 @@ impl CallManager {
             },
         }
     }
+
+    /// Give the app access to the call SIP trace, CDR points to it when it's kept after the call
+    fn set_sip_trace(&self, call_id: &InternalCallId, app_id: &AppId, cdr: &mut CdrBuilder) {
+        self.sip_trace.set_app(call_id, app_id);
+        if self.sip_trace.retained() {
+            cdr.set_sip_trace(&format!("/call/{call_id}/sip-trace"));
+        }
+    }
+
+    /// Apply reloaded limits, active calls are not affected
+    pub fn set_limits(&mut self, limits: CallLimits) {
+        log::info!("[CallManager] set limits {limits:?}");
+        self.limits = limits;
+    }
+
+    fn is_node_full(&self) -> bool {
+        self.limits.node_max_calls.is_some_and(|max_calls| self.calls() >= max_calls as usize)
+    }
+
+    /// Apps without their own limit use the default limit
+    fn app_max_calls(&self, max_calls: Option<u32>) -> Option<u32> {
+        max_calls.or(self.limits.default_app_max_calls)
+    }
+
+    fn is_limit_reached(&self, app_id: &AppId, max_calls: Option<u32>) -> bool {
+        match max_calls {
+            Some(max_calls) => self.call_counter.total(app_id) >= max_calls,
+            None => false,
+        }
+    }
 }
@@ -0,0 +1,53 @@
+use std::sync::Arc;
+
+use atm0s_small_p2p::now_ms;
+use spin::RwLock;
+
+use crate::protocol::{AppId, CallDirection, CallInfo, CallState, InternalCallId, StreamingInfo};
+
+/// Metadata of an active call, shared between CallManager and the call task
+#[derive(Clone)]
+pub struct CallMeta {
+    info: Arc<RwLock<CallInfo>>,
+}
+
+impl CallMeta {
+    pub fn new(call_id: &InternalCallId, app_id: &AppId, direction: CallDirection, state: CallState, from: &str, to: &str) -> Self {
+        Self {
+            info: Arc::new(RwLock::new(CallInfo {
+                call_id: call_id.to_string(),
+                app_id: app_id.to_string(),
+                direction,
+                state,
+                from_number: from.to_owned(),
+                to_number: to.to_owned(),
+                created_at: now_ms(),
+                answered_at: None,
+                duration_ms: 0,
+                talk_duration_ms: None,
+                streaming: None,
+            })),
+        }
+    }
+
+    pub fn set_state(&self, state: CallState) {
+        let mut info = self.info.write();
+        if state == CallState::Talking && info.answered_at.is_none() {
+            info.answered_at = Some(now_ms());
+        }
+        info.state = state;
+    }
+
+    pub fn set_stream(&self, stream: &StreamingInfo) {
+        self.info.write().streaming = Some(stream.clone());
+    }
+
+    /// Returns a copy of the current info with durations calculated at call time
+    pub fn info(&self) -> CallInfo {
+        let now = now_ms();
+        let mut info = self.info.read().clone();
+        info.duration_ms = now.saturating_sub(info.created_at);
+        info.talk_duration_ms = info.answered_at.map(|answered_at| now.saturating_sub(answered_at));
+        info
+    }
+}
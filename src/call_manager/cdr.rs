@@ -0,0 +1,144 @@
+use atm0s_small_p2p::now_ms;
+
+use crate::protocol::{
+    protobuf::sip_gateway::{call_detail_record, CallDetailRecord, CallEndReason, CallParty},
+    AppId, CallDirection, InternalCallId, StreamingInfo,
+};
+
+/// Collects call lifecycle info from call task for building the final CDR
+pub struct CdrBuilder {
+    record: CallDetailRecord,
+}
+
+impl CdrBuilder {
+    pub fn new(call_id: &InternalCallId, app_id: &AppId, direction: CallDirection, from: &str, to: &str, trunk: &str) -> Self {
+        let direction = match direction {
+            CallDirection::Outgoing => call_detail_record::Direction::Outgoing,
+            CallDirection::Incoming => call_detail_record::Direction::Incoming,
+        };
+        Self {
+            record: CallDetailRecord {
+                call_id: call_id.to_string(),
+                app_id: app_id.to_string(),
+                direction: direction as i32,
+                from: from.to_owned(),
+                to: to.to_owned(),
+                trunk: trunk.to_owned(),
+                created_at: now_ms(),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn set_stream(&mut self, stream: &StreamingInfo) {
+        self.record.room = stream.room.clone();
+        self.record.peer = stream.peer.clone();
+    }
+
+    pub fn set_sip_trace(&mut self, path: &str) {
+        self.record.sip_trace = Some(path.to_owned());
+    }
+
+    pub fn set_sip_code(&mut self, code: u32) {
+        self.record.sip_code = code;
+    }
+
+    pub fn on_ringing(&mut self) {
+        if self.record.ringing_at.is_none() {
+            self.record.ringing_at = Some(now_ms());
+        }
+    }
+
+    pub fn on_answered(&mut self, code: u32) {
+        self.record.sip_code = code;
+        if self.record.answered_at.is_none() {
+            self.record.answered_at = Some(now_ms());
+        }
+    }
+
+    pub fn answered(&self) -> bool {
+        self.record.answered_at.is_some()
+    }
+
+    /// Only the first ending cause is kept, following events are consequences of it
+    pub fn on_ending(&mut self, reason: CallEndReason, party: CallParty) {
+        if self.record.end_reason == CallEndReason::Unspecified as i32 {
+            self.record.end_reason = reason as i32;
+            self.record.hangup_by = party as i32;
+        }
+    }
+
+    pub fn end_reason(&self) -> i32 {
+        self.record.end_reason
+    }
+
+    pub fn build(mut self) -> CallDetailRecord {
+        self.record.ended_at = now_ms();
+        self.record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> CdrBuilder {
+        CdrBuilder::new(
+            &InternalCallId::from("call1".to_owned()),
+            &AppId::from("app1".to_owned()),
+            CallDirection::Outgoing,
+            "100",
+            "200",
+            "sip.example.com",
+        )
+    }
+
+    #[test]
+    fn keep_first_ending_reason() {
+        let mut cdr = builder();
+        assert_eq!(cdr.end_reason(), CallEndReason::Unspecified as i32);
+        cdr.on_ending(CallEndReason::SubscribersLost, CallParty::Gateway);
+        // the bye which follows the gateway hangup must not override the cause
+        cdr.on_ending(CallEndReason::Hangup, CallParty::Sip);
+
+        let record = cdr.build();
+        assert_eq!(record.end_reason, CallEndReason::SubscribersLost as i32);
+        assert_eq!(record.hangup_by, CallParty::Gateway as i32);
+    }
+
+    #[test]
+    fn timestamps_of_answered_call() {
+        let mut cdr = builder();
+        assert!(!cdr.answered());
+        cdr.on_ringing();
+        let ringing_at = cdr.record.ringing_at;
+        cdr.on_ringing();
+        assert_eq!(cdr.record.ringing_at, ringing_at);
+
+        cdr.on_answered(200);
+        let answered_at = cdr.record.answered_at.expect("should set answered_at");
+        assert!(cdr.answered());
+        cdr.on_answered(200);
+        assert_eq!(cdr.record.answered_at, Some(answered_at));
+
+        let record = cdr.build();
+        assert_eq!(record.sip_code, 200);
+        assert_eq!(record.end_reason, CallEndReason::Unspecified as i32);
+        assert!(record.created_at <= ringing_at.expect("should set ringing_at"));
+        assert!(ringing_at <= Some(answered_at));
+        assert!(answered_at <= record.ended_at);
+    }
+
+    #[test]
+    fn sip_failure_keeps_failure_code() {
+        let mut cdr = builder();
+        cdr.set_sip_code(486);
+        cdr.on_ending(CallEndReason::SipFailure, CallParty::Sip);
+
+        let record = cdr.build();
+        assert_eq!(record.sip_code, 486);
+        assert_eq!(record.answered_at, None);
+        assert_eq!(record.end_reason, CallEndReason::SipFailure as i32);
+        assert_eq!(record.hangup_by, CallParty::Sip as i32);
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+
+use atm0s_small_p2p::now_ms;
+
+use crate::protocol::{
+    protobuf::sip_gateway::{call_event, call_snapshot, CallEvent, CallSnapshot},
+    CallInfo, CallState, InternalCallId,
+};
+
+/// Only recent events are kept, it is enough for clients to catch up after a short reconnect
+const MAX_HISTORY_EVENTS: usize = 32;
+
+/// Recent events which a call task published to its call channel
+pub struct CallEventHistory {
+    call_id: InternalCallId,
+    next_seq: u64,
+    events: VecDeque<CallEvent>,
+}
+
+impl CallEventHistory {
+    pub fn new(call_id: &InternalCallId) -> Self {
+        Self {
+            call_id: call_id.clone(),
+            next_seq: 1,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Assigns the next seq to the event and keeps it in history
+    pub fn push(&mut self, event: call_event::Event) -> CallEvent {
+        let event = CallEvent {
+            call_id: self.call_id.to_string(),
+            timestamp: now_ms(),
+            seq: self.next_seq,
+            event: Some(event),
+        };
+        self.next_seq += 1;
+        if self.events.len() == MAX_HISTORY_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event.clone());
+        event
+    }
+
+    pub fn after(&self, seq: u64) -> Vec<CallEvent> {
+        self.events.iter().filter(|event| event.seq > seq).cloned().collect()
+    }
+
+    pub fn snapshot(&self, info: &CallInfo) -> CallSnapshot {
+        let state = match info.state {
+            CallState::Calling => call_snapshot::State::Calling,
+            CallState::Early => call_snapshot::State::Early,
+            CallState::Wait => call_snapshot::State::Wait,
+            CallState::Talking => call_snapshot::State::Talking,
+            CallState::Canceling => call_snapshot::State::Canceling,
+        };
+        CallSnapshot {
+            state: state as i32,
+            created_at: info.created_at,
+            answered_at: info.answered_at,
+            events: self.events.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history_with(count: usize) -> CallEventHistory {
+        let mut history = CallEventHistory::new(&InternalCallId::from("call1".to_owned()));
+        for _ in 0..count {
+            history.push(call_event::Event::Cdr(Default::default()));
+        }
+        history
+    }
+
+    fn seqs(events: Vec<CallEvent>) -> Vec<u64> {
+        events.into_iter().map(|event| event.seq).collect()
+    }
+
+    #[test]
+    fn after_returns_newer_events() {
+        let history = history_with(3);
+        assert_eq!(seqs(history.after(0)), vec![1, 2, 3]);
+        assert_eq!(seqs(history.after(2)), vec![3]);
+        assert!(history.after(3).is_empty());
+        assert!(history.after(10).is_empty());
+    }
+
+    #[test]
+    fn after_only_has_kept_events() {
+        let mut history = history_with(MAX_HISTORY_EVENTS + 5);
+        let events = history.after(0);
+        assert_eq!(events.len(), MAX_HISTORY_EVENTS);
+        assert_eq!(events[0].seq, 6);
+
+        // seq continues after old events are dropped
+        let event = history.push(call_event::Event::Cdr(Default::default()));
+        assert_eq!(event.seq, MAX_HISTORY_EVENTS as u64 + 6);
+        assert_eq!(seqs(history.after(event.seq - 1)), vec![event.seq]);
+    }
+}
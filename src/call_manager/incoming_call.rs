@@ -1,11 +1,16 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, time::Duration};
 
 use anyhow::anyhow;
 use atm0s_small_p2p::{
     now_ms,
     pubsub_service::{PublisherEventOb, PubsubServiceRequester},
 };
-use tokio::sync::mpsc::UnboundedSender;
+use ezk_sip_types::uri::sip::SipUri;
+use tokio::{
+    sync::{mpsc::UnboundedSender, watch},
+    time::Instant,
+};
+use tracing::Instrument;
 
 use crate::{
     error::PrintErrorSimple,
@@ -14,46 +19,96 @@ use crate::{
         is_sip_incoming_cancelled, is_sip_incoming_rejected,
         protobuf::sip_gateway::{
             call_event,
-            incoming_call_data::{incoming_call_event, incoming_call_notify_response, incoming_call_request, incoming_call_response, IncomingCallEvent, IncomingCallNotifyResponse},
+            incoming_call_data::{
+                incoming_call_event::{self, sip_event},
+                incoming_call_notify_response, incoming_call_request, incoming_call_response, IncomingCallEvent, IncomingCallNotifyResponse,
+            },
             incoming_call_notify::{self, CallAccepted, CallArrived, CallCancelled, CallRejected},
-            CallEvent, IncomingCallNotify,
+            CallDetailRecord, CallEndReason, CallEvent, CallParty, IncomingCallNotify,
         },
-        HookContentType, InternalCallId, StreamingInfo,
+        AppId, HookFallback, InternalCallId, StreamingInfo,
+    },
+    sip::{MediaApi, SipIncomingCall, SipIncomingCallError, SipIncomingCallOut},
+    utils::{
+        select2::{self, OrOutput},
+        select3, wait_changed, wait_deadline,
     },
-    sip::{MediaApi, SipIncomingCall, SipIncomingCallOut},
-    utils::select2,
 };
-pub struct IncomingCall {}
+
+use super::{call_meta::CallMeta, cdr::CdrBuilder, history::CallEventHistory};
+
+/// How long a call without hook waits for at least one notify session of the app
+const NOTIFY_SESSIONS_WAIT_MS: u64 = 1000;
+
+pub struct IncomingCall {
+    app_id: AppId,
+    meta: CallMeta,
+}
 
 impl IncomingCall {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        app_id: AppId,
+        meta: CallMeta,
         api: MediaApi,
         sip: SipIncomingCall,
         call_token: String,
-        destroy_tx: UnboundedSender<InternalCallId>,
-        hook_content_type: HookContentType,
+        mut cdr: CdrBuilder,
+        destroy_tx: UnboundedSender<(InternalCallId, CallDetailRecord)>,
         hook: HttpHookSender<CallEvent>,
         call_pubsub: PubsubServiceRequester,
+        subscriber_grace: Duration,
+        hook_timeout: Duration,
+        hook_fallback: Option<HookFallback>,
+        force_end: watch::Receiver<bool>,
     ) -> Self {
-        tokio::spawn(async move {
-            let call_id = sip.call_id();
-            if let Err(e) = run_call_loop(api, sip, call_token, hook_content_type, hook, call_pubsub).await {
-                log::error!("[IncomingCall] call {call_id} error {e:?}");
+        let task_meta = meta.clone();
+        let app_id_c = app_id.clone();
+        let span = tracing::info_span!("call", call_id = %sip.call_id(), app_id = %app_id, direction = "incoming");
+        tokio::spawn(
+            async move {
+                let call_id = sip.call_id();
+                if let Err(e) = run_call_loop(
+                    &app_id_c, api, sip, call_token, &task_meta, &mut cdr, &hook, call_pubsub, subscriber_grace, hook_timeout, hook_fallback, force_end,
+                )
+                .await
+                {
+                    log::error!("[IncomingCall] call {call_id} error {e:?}");
+                    cdr.on_ending(CallEndReason::Error, CallParty::Gateway);
+                }
+                let record = cdr.build();
+                hook.send(build_call_cdr(&call_id, record.clone())).await;
+                destroy_tx.send((call_id, record)).expect("should send destroy request to main loop");
             }
-            destroy_tx.send(call_id).expect("should send destroy request to main loop");
-        });
+            .instrument(span),
+        );
+
+        Self { app_id, meta }
+    }
 
-        Self {}
+    pub fn app_id(&self) -> &AppId {
+        &self.app_id
+    }
+
+    pub fn meta(&self) -> &CallMeta {
+        &self.meta
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_call_loop(
+    app_id: &AppId,
     api: MediaApi,
     mut call: SipIncomingCall,
     call_token: String,
-    hook_content_type: HookContentType,
-    hook: HttpHookSender<CallEvent>,
+    meta: &CallMeta,
+    cdr: &mut CdrBuilder,
+    hook: &HttpHookSender<CallEvent>,
     call_pubsub: PubsubServiceRequester,
+    subscriber_grace: Duration,
+    hook_timeout: Duration,
+    hook_fallback: Option<HookFallback>,
+    mut force_end: watch::Receiver<bool>,
 ) -> anyhow::Result<()> {
     let call_id = call.call_id();
     let from = call.from().to_owned();
@@ -61,59 +116,114 @@ async fn run_call_loop(
 
     let channel_id = call_id.to_pubsub_channel();
     let mut subscribers = HashSet::new();
+    // set when all subscribers left, the call ends if none reconnect before it
+    let mut lost_deadline: Option<Instant> = None;
+    let mut history = CallEventHistory::new(&call_id);
     let call_ws = format!("/call/incoming/{call_id}?token={call_token}");
     log::info!("[IncomingCall] call {call_id} start, ws: {call_ws}, sending hook ...");
 
     // we send trying first
     call.send_trying().await?;
     let mut publisher = call_pubsub.publisher(channel_id).await;
+    let mut notify_publisher = call_pubsub.publisher(app_id.to_notify_channel()).await;
 
-    // feedback hook for info
-    let action = match hook
-        .request::<IncomingCallNotifyResponse>(
-            hook_content_type,
-            &build_call_notify(
-                &call_id,
-                incoming_call_notify::Event::Arrived(CallArrived {
-                    call_token,
-                    call_ws,
-                    call_from: from.clone(),
-                    call_to: to.clone(),
-                }),
-            ),
-        )
-        .await
-    {
-        Ok(action) => match action.action {
-            Some(action) => action,
-            None => {
+    let arrived = build_call_notify(
+        &call_id,
+        incoming_call_notify::Event::Arrived(CallArrived {
+            call_token,
+            call_ws,
+            call_from: from.clone(),
+            call_to: to.clone(),
+        }),
+    );
+
+    let action = if hook.has_endpoint() {
+        notify_publisher.requester().publish_ob(&arrived).await.print_error("[IncomingCall] publish notify");
+        // feedback hook for info
+        let res = hook
+            .request::<IncomingCallNotifyResponse>(&arrived, hook_timeout)
+            .await
+            .and_then(|res| res.action.ok_or(anyhow!("invalid response")));
+        match (res, hook_fallback) {
+            (Ok(action), _) => action,
+            (Err(err), Some(fallback)) => {
+                log::warn!("[IncomingCall] call {call_id} hook error {err:?} => fallback {fallback:?}");
+                match fallback {
+                    HookFallback::Reject { code } => {
+                        cdr.set_sip_code(code as u32);
+                        cdr.on_ending(CallEndReason::HookFallbackRejected, CallParty::Gateway);
+                        call.kill_with_code(code);
+                        return Ok(());
+                    }
+                    HookFallback::Forward { uri } => match uri.parse::<SipUri>() {
+                        Ok(target) => {
+                            cdr.set_sip_code(302);
+                            cdr.on_ending(CallEndReason::HookFallbackForwarded, CallParty::Gateway);
+                            call.redirect(target);
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            log::error!("[IncomingCall] call {call_id} invalid fallback uri {uri} {e:?}");
+                            cdr.set_sip_code(480);
+                            call.kill_because_unavailable();
+                            return Err(err);
+                        }
+                    },
+                    HookFallback::Ring => incoming_call_notify_response::Action::Ring(Default::default()),
+                    HookFallback::Accept { room, peer, record } => incoming_call_notify_response::Action::Accept(incoming_call_notify_response::Accept { room, peer, record }),
+                }
+            }
+            (Err(err), None) => {
+                cdr.set_sip_code(406);
                 call.kill_because_validate_failed();
-                return Err(anyhow!("invalid response"));
+                return Err(err);
             }
-        },
-        Err(err) => {
-            call.kill_because_validate_failed();
-            return Err(err);
         }
+    } else {
+        // without hook, the call is handled by notify sessions over the call channel
+        let wait_sessions = async {
+            loop {
+                match notify_publisher.recv_ob::<CallEvent>().await {
+                    Ok(PublisherEventOb::PeerJoined(_)) => break true,
+                    Ok(_) => {}
+                    Err(_) => break false,
+                }
+            }
+        };
+        let has_sessions = tokio::time::timeout(Duration::from_millis(NOTIFY_SESSIONS_WAIT_MS), wait_sessions).await.unwrap_or(false);
+        if !has_sessions {
+            cdr.set_sip_code(480);
+            call.kill_because_unavailable();
+            return Err(anyhow!("no hook and no notify session"));
+        }
+        notify_publisher.requester().publish_ob(&arrived).await.print_error("[IncomingCall] publish notify");
+        // without the main hook, arrivals still go to subscriptions
+        hook.send(arrived).await;
+        incoming_call_notify_response::Action::Ring(Default::default())
     };
 
     log::info!("[IncomingCall] call {call_id} got hook action {:?}", action);
 
     match action {
-        incoming_call_notify_response::Action::Ring(_ring) => call.send_ringing().await?,
+        incoming_call_notify_response::Action::Ring(_ring) => {
+            call.send_ringing().await?;
+            cdr.on_ringing();
+        }
         incoming_call_notify_response::Action::Accept(accept) => {
-            call.accept(
-                api.clone(),
-                StreamingInfo {
-                    room: accept.room,
-                    peer: accept.peer,
-                    record: accept.record,
-                },
-            )
-            .await?;
+            let stream = StreamingInfo {
+                room: accept.room,
+                peer: accept.peer,
+                record: accept.record,
+            };
+            cdr.set_stream(&stream);
+            meta.set_stream(&stream);
+            call.accept(api.clone(), stream).await?;
+            meta.set_state(call.state());
         }
         incoming_call_notify_response::Action::End(_end) => {
             call.end().await.print_error("[IncomingCall] end call from hook response");
+            cdr.set_sip_code(486);
+            cdr.on_ending(CallEndReason::Rejected, CallParty::App);
             return Ok(());
         }
         incoming_call_notify_response::Action::Continue(_) => {}
@@ -122,44 +232,64 @@ async fn run_call_loop(
     log::info!("[IncomingCall] call {call_id} started loop");
 
     loop {
-        let out = select2::or(call.recv(), publisher.recv_ob::<incoming_call_request::Action>()).await;
+        let out = select3::or(
+            call.recv(),
+            publisher.recv_ob::<incoming_call_request::Action>(),
+            select2::or(notify_publisher.recv_ob::<CallEvent>(), select2::or(wait_deadline(lost_deadline), wait_changed(&mut force_end))),
+        )
+        .await;
         match out {
-            select2::OrOutput::Left(Ok(Some(out))) => match out {
+            select3::OrOutput::Left(Ok(Some(out))) => match out {
                 SipIncomingCallOut::Event(event) => {
+                    meta.set_state(call.state());
+                    update_cdr(cdr, &event);
                     if is_sip_incoming_cancelled(&event.event).is_some() {
-                        hook.send(hook_content_type, build_call_notify_cancel(&call_id, &from, &to));
+                        let notify = build_call_notify_cancel(&call_id, &from, &to);
+                        notify_publisher.requester().publish_ob(&notify).await.print_error("[IncomingCall] publish notify");
+                        hook.send(notify).await;
                     }
                     if is_sip_incoming_rejected(&event.event).is_some() {
-                        hook.send(hook_content_type, build_call_notify_reject(&call_id, &from, &to));
+                        let notify = build_call_notify_reject(&call_id, &from, &to);
+                        notify_publisher.requester().publish_ob(&notify).await.print_error("[IncomingCall] publish notify");
+                        hook.send(notify).await;
                     }
+                    let event = history.push(call_event::Event::Incoming(event));
                     publisher.requester().publish_ob(&event).await.print_error("[IncomingCall] publish event");
-                    hook.send(hook_content_type, build_call_event(&call_id, event));
+                    hook.send(event).await;
                 }
                 SipIncomingCallOut::Continue => {}
             },
-            select2::OrOutput::Left(Ok(None)) => {
+            select3::OrOutput::Left(Ok(None)) => {
                 log::info!("[IncomingCall] call {call_id} end");
                 break;
             }
-            select2::OrOutput::Left(Err(e)) => {
+            select3::OrOutput::Left(Err(e)) => {
                 log::error!("[IncomingCall] call {call_id} error {e:?}");
                 let event = IncomingCallEvent {
                     event: Some(incoming_call_event::Event::Err(incoming_call_event::Error { message: e.to_string() })),
                 };
+                update_cdr(cdr, &event);
+                let event = history.push(call_event::Event::Incoming(event));
                 publisher.requester().publish_ob(&event).await.print_error("[IncomingCall] publish event");
-                hook.send(hook_content_type, build_call_event(&call_id, event));
+                hook.send(event).await;
                 break;
             }
-            select2::OrOutput::Right(Ok(control)) => match control {
+            select3::OrOutput::Middle(Ok(control)) => match control {
                 PublisherEventOb::PeerJoined(peer_src) => {
                     subscribers.insert(peer_src);
+                    if lost_deadline.take().is_some() {
+                        log::info!("[IncomingCall] call {call_id} sub reconnected in grace period => continue call");
+                    }
                 }
                 PublisherEventOb::PeerLeaved(peer_src) => {
                     if subscribers.remove(&peer_src) && subscribers.is_empty() {
-                        log::info!("[IncomingCall] call {call_id} all subs disconnected => end call");
-                        if let Err(e) = call.end().await {
-                            log::error!("[IncomingCall] call {call_id} end error {e:?}");
+                        if !subscriber_grace.is_zero() {
+                            log::info!("[IncomingCall] call {call_id} all subs disconnected => wait {subscriber_grace:?} for reconnect");
+                            lost_deadline = Some(Instant::now() + subscriber_grace);
+                            continue;
                         }
+                        log::info!("[IncomingCall] call {call_id} all subs disconnected => end call");
+                        let _ = finish_call(&mut call, cdr, None, CallParty::Gateway).await;
                         break;
                     }
                 }
@@ -180,23 +310,29 @@ async fn run_call_loop(
                                 peer: accept.peer,
                                 record: accept.record,
                             };
+                            cdr.set_stream(&stream);
+                            meta.set_stream(&stream);
                             if let Err(e) = call.accept(api.clone(), stream).await {
                                 log::error!("[IncomingCall] call {call_id} accept error {e:?}");
                                 incoming_call_response::Response::Error(incoming_call_response::Error { message: e.to_string() })
                             } else {
-                                hook.send(hook_content_type, build_call_notify_accept(&call_id, &from, &to));
+                                meta.set_state(call.state());
+                                let notify = build_call_notify_accept(&call_id, &from, &to);
+                                notify_publisher.requester().publish_ob(&notify).await.print_error("[IncomingCall] publish notify");
+                                hook.send(notify).await;
                                 incoming_call_response::Response::Accept(Default::default())
                             }
                         }
                         incoming_call_request::Action::End(_end) => {
                             log::info!("[IncomingCall] call {call_id} received end request");
-                            if let Err(e) = call.end().await {
-                                log::error!("[IncomingCall] call {call_id} end error {e:?}");
+                            if let Err(e) = finish_call(&mut call, cdr, None, CallParty::App).await {
                                 incoming_call_response::Response::Error(incoming_call_response::Error { message: e.to_string() })
                             } else {
                                 incoming_call_response::Response::End(Default::default())
                             }
                         }
+                        incoming_call_request::Action::History(req) => incoming_call_response::Response::History(incoming_call_response::History { events: history.after(req.after_seq) }),
+                        incoming_call_request::Action::Snapshot(_) => incoming_call_response::Response::Snapshot(history.snapshot(&meta.info())),
                     };
                     publisher.requester().answer_feedback_rpc_ob(rpc_id, peer_src, &res).await.print_error("[IncomingCall] answer rpc");
                 }
@@ -204,7 +340,22 @@ async fn run_call_loop(
                     log::warn!("IncomingCall] invalid pubsub event {control:?}");
                 }
             },
-            select2::OrOutput::Right(Err(_e)) => {
+            select3::OrOutput::Middle(Err(_e)) => {
+                break;
+            }
+            // notify sessions join and leave the app channel, nothing to do with them here
+            select3::OrOutput::Right(OrOutput::Left(Ok(_))) => {}
+            select3::OrOutput::Right(OrOutput::Left(Err(_e))) => {
+                break;
+            }
+            select3::OrOutput::Right(OrOutput::Right(OrOutput::Left(()))) => {
+                log::info!("[IncomingCall] call {call_id} no sub reconnected in grace period => end call");
+                let _ = finish_call(&mut call, cdr, Some(CallEndReason::SubscribersLost), CallParty::Gateway).await;
+                break;
+            }
+            select3::OrOutput::Right(OrOutput::Right(OrOutput::Right(()))) => {
+                log::info!("[IncomingCall] call {call_id} node drain timeout => end call");
+                let _ = finish_call(&mut call, cdr, None, CallParty::Gateway).await;
                 break;
             }
         }
@@ -212,13 +363,46 @@ async fn run_call_loop(
 
     log::info!("[IncomingCall] call {call_id} destroyed");
     let event = IncomingCallEvent {
-        event: Some(incoming_call_event::Event::Ended(Default::default())),
+        event: Some(incoming_call_event::Event::Ended(incoming_call_event::Ended { reason: cdr.end_reason() })),
     };
+    let event = history.push(call_event::Event::Incoming(event));
     publisher.requester().publish_ob(&event).await.print_error("[IncomingCall] publish event");
-    hook.send(hook_content_type, build_call_event(&call_id, event));
+    hook.send(event).await;
     Ok(())
 }
 
+/// End the call, without reason it is Hangup or Rejected depending on whether the call was answered
+async fn finish_call(call: &mut SipIncomingCall, cdr: &mut CdrBuilder, reason: Option<CallEndReason>, party: CallParty) -> Result<(), SipIncomingCallError> {
+    let reason = reason.unwrap_or(if cdr.answered() {
+        CallEndReason::Hangup
+    } else {
+        CallEndReason::Rejected
+    });
+    cdr.on_ending(reason, party);
+    call.end().await.inspect_err(|e| log::error!("[IncomingCall] call {} end error {e:?}", call.call_id()))
+}
+
+fn update_cdr(cdr: &mut CdrBuilder, event: &IncomingCallEvent) {
+    match &event.event {
+        Some(incoming_call_event::Event::Sip(sip)) => match &sip.event {
+            Some(sip_event::Event::Cancelled(_)) => {
+                // the invite transaction is finished with 487 Request Terminated
+                cdr.set_sip_code(487);
+                cdr.on_ending(CallEndReason::Cancelled, CallParty::Sip);
+            }
+            Some(sip_event::Event::Bye(_)) => cdr.on_ending(CallEndReason::Hangup, CallParty::Sip),
+            None => {}
+        },
+        Some(incoming_call_event::Event::Accepted(_)) => cdr.on_answered(200),
+        Some(incoming_call_event::Event::Rejected(_)) => {
+            cdr.set_sip_code(486);
+            cdr.on_ending(CallEndReason::Rejected, CallParty::App);
+        }
+        Some(incoming_call_event::Event::Err(_)) => cdr.on_ending(CallEndReason::Error, CallParty::Gateway),
+        Some(incoming_call_event::Event::Ended(_)) | None => {}
+    }
+}
+
 fn build_call_notify_cancel(call_id: &InternalCallId, from: &str, to: &str) -> CallEvent {
     build_call_notify(
         call_id,
@@ -253,14 +437,16 @@ fn build_call_notify(call_id: &InternalCallId, event: incoming_call_notify::Even
     CallEvent {
         call_id: call_id.clone().into(),
         timestamp: now_ms(),
+        seq: 0,
         event: Some(call_event::Event::Notify(IncomingCallNotify { event: Some(event) })),
     }
 }
 
-fn build_call_event(call_id: &InternalCallId, event: IncomingCallEvent) -> CallEvent {
+fn build_call_cdr(call_id: &InternalCallId, record: CallDetailRecord) -> CallEvent {
     CallEvent {
         call_id: call_id.clone().into(),
         timestamp: now_ms(),
-        event: Some(call_event::Event::Incoming(event)),
+        seq: 0,
+        event: Some(call_event::Event::Cdr(record)),
     }
 }
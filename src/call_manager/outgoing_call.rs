@@ -1,10 +1,14 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, time::Duration};
 
 use atm0s_small_p2p::{
     now_ms,
-    pubsub_service::{PublisherEventOb, PubsubServiceRequester},
+    pubsub_service::{PublisherEventOb, PublisherRequester, PubsubServiceRequester},
 };
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::{
+    sync::{mpsc::UnboundedSender, watch},
+    time::Instant,
+};
+use tracing::Instrument;
 
 use crate::{
     error::PrintErrorSimple,
@@ -12,98 +16,185 @@ use crate::{
     protocol::{
         protobuf::sip_gateway::{
             call_event,
-            outgoing_call_data::{outgoing_call_event, outgoing_call_request, outgoing_call_response, OutgoingCallEvent},
-            CallEvent,
+            outgoing_call_data::{
+                outgoing_call_event::{self, sip_event},
+                outgoing_call_request, outgoing_call_response, OutgoingCallEvent,
+            },
+            CallDetailRecord, CallEndReason, CallEvent, CallParty,
         },
-        HookContentType, InternalCallId,
+        AppId, InternalCallId,
     },
     sip::{SipOutgoingCall, SipOutgoingCallOut},
-    utils::select2,
+    utils::{select2, select3, wait_changed, wait_deadline},
 };
 
-pub struct OutgoingCall {}
+use super::{call_meta::CallMeta, cdr::CdrBuilder, history::CallEventHistory};
+
+/// How long an ended call may take to finish its SIP transactions, like waiting the 487 of a CANCEL
+const END_CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct OutgoingCall {
+    app_id: AppId,
+    meta: CallMeta,
+}
 
 impl OutgoingCall {
-    pub fn new(sip: SipOutgoingCall, destroy_tx: UnboundedSender<InternalCallId>, hook_content_type: HookContentType, hook: HttpHookSender<CallEvent>, call_pubsub: PubsubServiceRequester) -> Self {
-        tokio::spawn(async move { run_call_loop(sip, destroy_tx, hook_content_type, hook, call_pubsub).await });
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        app_id: AppId,
+        meta: CallMeta,
+        sip: SipOutgoingCall,
+        mut cdr: CdrBuilder,
+        destroy_tx: UnboundedSender<(InternalCallId, CallDetailRecord)>,
+        hook: HttpHookSender<CallEvent>,
+        call_pubsub: PubsubServiceRequester,
+        subscriber_grace: Duration,
+        force_end: watch::Receiver<bool>,
+    ) -> Self {
+        let task_meta = meta.clone();
+        let span = tracing::info_span!("call", call_id = %sip.call_id(), app_id = %app_id, direction = "outgoing");
+        tokio::spawn(
+            async move {
+                let call_id = sip.call_id();
+                run_call_loop(sip, &task_meta, &mut cdr, &hook, call_pubsub, subscriber_grace, force_end).await;
+                let record = cdr.build();
+                hook.send(build_call_cdr(&call_id, record.clone())).await;
+                destroy_tx.send((call_id, record)).expect("should send destroy request to main loop");
+            }
+            .instrument(span),
+        );
 
-        Self {}
+        Self { app_id, meta }
+    }
+
+    pub fn app_id(&self) -> &AppId {
+        &self.app_id
+    }
+
+    pub fn meta(&self) -> &CallMeta {
+        &self.meta
     }
 }
 
 async fn run_call_loop(
     mut call: SipOutgoingCall,
-    destroy_tx: UnboundedSender<InternalCallId>,
-    hook_content_type: HookContentType,
-    hook: HttpHookSender<CallEvent>,
+    meta: &CallMeta,
+    cdr: &mut CdrBuilder,
+    hook: &HttpHookSender<CallEvent>,
     call_pubsub: PubsubServiceRequester,
+    subscriber_grace: Duration,
+    mut force_end: watch::Receiver<bool>,
 ) {
     let call_id = call.call_id();
     let channel_id = call_id.to_pubsub_channel();
     let mut subscribers = HashSet::new();
+    // set when all subscribers left, the call ends if none reconnect before it
+    let mut lost_deadline: Option<Instant> = None;
+    let mut history = CallEventHistory::new(&call_id);
     let mut publisher = call_pubsub.publisher(channel_id).await;
 
     log::info!("[OutgoingCall] call starting");
 
     if let Err(e) = call.start().await {
         log::error!("[OutgoingCall] call start error {e:?}");
-        destroy_tx.send(call_id).expect("should send destroy request to main loop");
+        cdr.on_ending(CallEndReason::Error, CallParty::Gateway);
         return;
     }
 
     log::info!("[OutgoingCall] call started");
 
     loop {
-        let out = select2::or(call.recv(), publisher.recv_ob::<outgoing_call_request::Action>()).await;
+        let out = select3::or(
+            call.recv(),
+            publisher.recv_ob::<outgoing_call_request::Action>(),
+            select2::or(wait_deadline(lost_deadline), wait_changed(&mut force_end)),
+        )
+        .await;
         match out {
-            select2::OrOutput::Left(Ok(Some(out))) => match out {
+            select3::OrOutput::Left(Ok(Some(out))) => match out {
                 SipOutgoingCallOut::Event(event) => {
                     log::info!("[OutgoingCall] send event {event:?}");
+                    meta.set_state(call.state());
+                    update_cdr(cdr, &event);
+                    let event = history.push(call_event::Event::Outgoing(event));
                     publisher.requester().publish_ob(&event).await.print_error("[OutgoingCall] send event");
-                    hook.send(hook_content_type, build_call_event(&call_id, event));
+                    hook.send(event).await;
                 }
                 SipOutgoingCallOut::Continue => {}
             },
-            select2::OrOutput::Left(Ok(None)) => {
+            select3::OrOutput::Left(Ok(None)) => {
                 log::info!("[OutgoingCall] call end");
                 break;
             }
-            select2::OrOutput::Left(Err(e)) => {
+            select3::OrOutput::Left(Err(e)) => {
                 log::error!("[OutgoingCall] call error {e:?}");
                 let event = OutgoingCallEvent {
                     event: Some(outgoing_call_event::Event::Err(outgoing_call_event::Error { message: e.to_string() })),
                 };
+                update_cdr(cdr, &event);
+                let event = history.push(call_event::Event::Outgoing(event));
                 publisher.requester().publish_ob(&event).await.print_error("[OutgoingCall] send event");
-                hook.send(hook_content_type, build_call_event(&call_id, event));
+                hook.send(event).await;
                 break;
             }
-            select2::OrOutput::Right(Ok(control)) => match control {
+            select3::OrOutput::Middle(Ok(control)) => match control {
                 PublisherEventOb::PeerJoined(peer_src) => {
                     subscribers.insert(peer_src);
+                    if lost_deadline.take().is_some() {
+                        log::info!("[OutgoingCall] sub reconnected in grace period => continue call");
+                    }
                 }
                 PublisherEventOb::PeerLeaved(peer_src) => {
                     if subscribers.remove(&peer_src) && subscribers.is_empty() {
-                        log::info!("[OutgoingCall] all sub disconnected => end call");
-                        if let Err(e) = call.end().await {
-                            log::error!("[OutgoingCall] end call error {e:?}");
+                        if !subscriber_grace.is_zero() {
+                            log::info!("[OutgoingCall] all sub disconnected => wait {subscriber_grace:?} for reconnect");
+                            lost_deadline = Some(Instant::now() + subscriber_grace);
+                            continue;
                         }
+                        log::info!("[OutgoingCall] all sub disconnected => end call");
+                        finish_call(&mut call, meta, cdr, &mut history, publisher.requester(), hook, None, CallParty::Gateway).await;
+                        break;
                     }
                 }
-                PublisherEventOb::FeedbackRpc(action, rpc_id, _method, peer_src) | PublisherEventOb::GuestFeedbackRpc(action, rpc_id, _method, peer_src) => match action {
-                    outgoing_call_request::Action::End(_end) => {
-                        log::info!("[OutgoingCall] call {call_id} received end request");
-                        let res = if let Err(e) = call.end().await {
-                            log::error!("[OutgoingCall] call {call_id} end error {e:?}");
-                            outgoing_call_response::Response::Error(outgoing_call_response::Error { message: e.to_string() })
-                        } else {
-                            outgoing_call_response::Response::End(Default::default())
-                        };
-                        publisher.requester().answer_feedback_rpc_ob(rpc_id, peer_src, &res).await.print_error("[IncomingCall] answer rpc");
-                    }
-                },
+                PublisherEventOb::FeedbackRpc(action, rpc_id, _method, peer_src) | PublisherEventOb::GuestFeedbackRpc(action, rpc_id, _method, peer_src) => {
+                    let res = match action {
+                        outgoing_call_request::Action::End(_end) => {
+                            log::info!("[OutgoingCall] call {call_id} received end request");
+                            if let Err(e) = call.end().await {
+                                log::error!("[OutgoingCall] call {call_id} end error {e:?}");
+                                outgoing_call_response::Response::Error(outgoing_call_response::Error { message: e.to_string() })
+                            } else {
+                                outgoing_call_response::Response::End(Default::default())
+                            }
+                        }
+                        outgoing_call_request::Action::History(req) => outgoing_call_response::Response::History(outgoing_call_response::History { events: history.after(req.after_seq) }),
+                        outgoing_call_request::Action::Snapshot(_) => outgoing_call_response::Response::Snapshot(history.snapshot(&meta.info())),
+                    };
+                    publisher.requester().answer_feedback_rpc_ob(rpc_id, peer_src, &res).await.print_error("[OutgoingCall] answer rpc");
+                }
                 _ => {}
             },
-            select2::OrOutput::Right(Err(_e)) => {
+            select3::OrOutput::Middle(Err(_e)) => {
+                break;
+            }
+            select3::OrOutput::Right(select2::OrOutput::Left(())) => {
+                log::info!("[OutgoingCall] no sub reconnected in grace period => end call");
+                finish_call(
+                    &mut call,
+                    meta,
+                    cdr,
+                    &mut history,
+                    publisher.requester(),
+                    hook,
+                    Some(CallEndReason::SubscribersLost),
+                    CallParty::Gateway,
+                )
+                .await;
+                break;
+            }
+            select3::OrOutput::Right(select2::OrOutput::Right(())) => {
+                log::info!("[OutgoingCall] node drain timeout => end call");
+                finish_call(&mut call, meta, cdr, &mut history, publisher.requester(), hook, None, CallParty::Gateway).await;
                 break;
             }
         }
@@ -111,17 +202,102 @@ async fn run_call_loop(
 
     log::info!("[OutgoingCall] call destroyed");
     let event = OutgoingCallEvent {
-        event: Some(outgoing_call_event::Event::Ended(Default::default())),
+        event: Some(outgoing_call_event::Event::Ended(outgoing_call_event::Ended { reason: cdr.end_reason() })),
     };
-    publisher.requester().publish_ob(&event).await.print_error("[IncomingCall] publish event");
-    hook.send(hook_content_type, build_call_event(&call_id, event));
-    destroy_tx.send(call_id).expect("should send destroy request to main loop");
+    let event = history.push(call_event::Event::Outgoing(event));
+    publisher.requester().publish_ob(&event).await.print_error("[OutgoingCall] publish event");
+    hook.send(event).await;
+}
+
+/// End the call and publish the events of its ending, without reason it is Hangup or Cancelled depending on whether the call was answered
+#[allow(clippy::too_many_arguments)]
+async fn finish_call(
+    call: &mut SipOutgoingCall,
+    meta: &CallMeta,
+    cdr: &mut CdrBuilder,
+    history: &mut CallEventHistory,
+    publisher: &PublisherRequester,
+    hook: &HttpHookSender<CallEvent>,
+    reason: Option<CallEndReason>,
+    party: CallParty,
+) {
+    let reason = reason.unwrap_or(if cdr.answered() {
+        CallEndReason::Hangup
+    } else {
+        CallEndReason::Cancelled
+    });
+    cdr.on_ending(reason, party);
+    for event in end_call(call, meta).await {
+        update_cdr(cdr, &event);
+        let event = history.push(call_event::Event::Outgoing(event));
+        publisher.publish_ob(&event).await.print_error("[OutgoingCall] send event");
+        hook.send(event).await;
+    }
+}
+
+/// End the call from the gateway side and drive it until its SIP transactions finished, bounded by END_CALL_TIMEOUT.
+/// Returns events of the ending, like Cancelled or Terminated
+async fn end_call(call: &mut SipOutgoingCall, meta: &CallMeta) -> Vec<OutgoingCallEvent> {
+    if let Err(e) = call.end().await {
+        log::error!("[OutgoingCall] end call error {e:?}");
+        return vec![];
+    }
+    let mut events = vec![];
+    let finish = async {
+        loop {
+            match call.recv().await {
+                Ok(Some(SipOutgoingCallOut::Event(event))) => {
+                    meta.set_state(call.state());
+                    events.push(event);
+                }
+                Ok(Some(SipOutgoingCallOut::Continue)) => {}
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("[OutgoingCall] finish ended call error {e:?}");
+                    break;
+                }
+            }
+        }
+    };
+    if tokio::time::timeout(END_CALL_TIMEOUT, finish).await.is_err() {
+        log::warn!("[OutgoingCall] call not finished {END_CALL_TIMEOUT:?} after end => destroy");
+    }
+    events
+}
+
+fn update_cdr(cdr: &mut CdrBuilder, event: &OutgoingCallEvent) {
+    match &event.event {
+        Some(outgoing_call_event::Event::Sip(sip)) => match &sip.event {
+            Some(sip_event::Event::Provisional(provisional)) => {
+                if provisional.code == 180 || provisional.code == 183 {
+                    cdr.on_ringing();
+                }
+            }
+            Some(sip_event::Event::Early(_)) => cdr.on_ringing(),
+            Some(sip_event::Event::Accepted(accepted)) => cdr.on_answered(accepted.code),
+            Some(sip_event::Event::Failure(failure)) => {
+                cdr.set_sip_code(failure.code);
+                cdr.on_ending(CallEndReason::SipFailure, CallParty::Sip);
+            }
+            Some(sip_event::Event::Bye(_)) => cdr.on_ending(CallEndReason::Hangup, CallParty::Sip),
+            None => {}
+        },
+        Some(outgoing_call_event::Event::Cancelled(_)) => {
+            // the invite transaction is finished with 487 Request Terminated
+            cdr.set_sip_code(487);
+            cdr.on_ending(CallEndReason::Cancelled, CallParty::App);
+        }
+        Some(outgoing_call_event::Event::Terminated(_)) => cdr.on_ending(CallEndReason::Hangup, CallParty::App),
+        Some(outgoing_call_event::Event::Err(_)) => cdr.on_ending(CallEndReason::Error, CallParty::Gateway),
+        Some(outgoing_call_event::Event::Ended(_)) | None => {}
+    }
 }
 
-fn build_call_event(call_id: &InternalCallId, event: OutgoingCallEvent) -> CallEvent {
+fn build_call_cdr(call_id: &InternalCallId, record: CallDetailRecord) -> CallEvent {
     CallEvent {
         call_id: call_id.clone().into(),
         timestamp: now_ms(),
-        event: Some(call_event::Event::Outgoing(event)),
+        seq: 0,
+        event: Some(call_event::Event::Cdr(record)),
     }
 }
@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use crate::protocol::protobuf::sip_gateway::CallDetailRecord;
+
+use super::CdrSink;
+
+/// Append each record as a json line to a local file
+pub struct JsonLinesCdrSink {
+    path: PathBuf,
+    name: String,
+}
+
+impl JsonLinesCdrSink {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            name: format!("file:{}", path.display()),
+            path,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CdrSink for JsonLinesCdrSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    async fn write(&mut self, records: &[CallDetailRecord]) -> anyhow::Result<()> {
+        let mut buf = vec![];
+        for record in records {
+            serde_json::to_writer(&mut buf, record)?;
+            buf.push(b'\n');
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        file.write_all(&buf).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
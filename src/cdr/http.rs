@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::protocol::protobuf::sip_gateway::CallDetailRecord;
+
+use super::CdrSink;
+
+#[derive(Serialize)]
+struct CdrBatch<'a> {
+    records: &'a [CallDetailRecord],
+}
+
+/// Post records in batches as json `{ "records": [..] }` to an http endpoint
+pub struct HttpBatchCdrSink {
+    endpoint: String,
+    batch_size: usize,
+    client: reqwest::Client,
+}
+
+impl HttpBatchCdrSink {
+    pub fn new(endpoint: &str, batch_size: usize) -> Self {
+        Self {
+            endpoint: endpoint.to_owned(),
+            batch_size: batch_size.max(1),
+            client: reqwest::ClientBuilder::new().timeout(Duration::from_secs(10)).build().expect("should create client"),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CdrSink for HttpBatchCdrSink {
+    fn name(&self) -> &str {
+        &self.endpoint
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    async fn write(&mut self, records: &[CallDetailRecord]) -> anyhow::Result<()> {
+        self.client.post(&self.endpoint).json(&CdrBatch { records }).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{error::PrintErrorSimple, protocol::protobuf::sip_gateway::CallDetailRecord, utils::select2};
+
+mod file;
+mod http;
+
+pub use file::JsonLinesCdrSink;
+pub use http::HttpBatchCdrSink;
+
+/// Max records kept in memory per sink while it is failing, oldest records are dropped first
+const MAX_PENDING_RECORDS: usize = 10_000;
+/// Delay after the first failed write, doubled after each following failure
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Destination for finished call records
+#[async_trait::async_trait]
+pub trait CdrSink: Send {
+    fn name(&self) -> &str;
+    fn batch_size(&self) -> usize;
+    async fn write(&mut self, records: &[CallDetailRecord]) -> anyhow::Result<()>;
+}
+
+/// Fan out records to all sinks, each sink is running in its own task for avoiding blocking others
+pub struct CdrWriter {
+    sinks: Vec<UnboundedSender<CallDetailRecord>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl CdrWriter {
+    pub fn new(sinks: Vec<Box<dyn CdrSink>>, flush_interval: Duration) -> Self {
+        let (sinks, tasks) = sinks
+            .into_iter()
+            .map(|sink| {
+                let (tx, rx) = unbounded_channel();
+                (tx, tokio::spawn(run_sink(sink, rx, flush_interval)))
+            })
+            .unzip();
+        Self { sinks, tasks }
+    }
+
+    pub fn write(&self, record: &CallDetailRecord) {
+        for sink in &self.sinks {
+            if sink.send(record.clone()).is_err() {
+                log::error!("[CdrWriter] sink worker stopped => drop record of call {}", record.call_id);
+            }
+        }
+    }
+
+    /// Stop sinks after they wrote their pending records, a failed write is not retried
+    pub async fn close(self) {
+        drop(self.sinks);
+        for task in self.tasks {
+            task.await.print_error("[CdrWriter] join sink worker");
+        }
+    }
+}
+
+async fn run_sink(mut sink: Box<dyn CdrSink>, mut rx: UnboundedReceiver<CallDetailRecord>, flush_interval: Duration) {
+    let mut pending = vec![];
+    let mut interval = tokio::time::interval(flush_interval);
+    // failed writes are retried after a backoff delay, records keep buffering meanwhile
+    let mut failures = 0;
+    let mut retry_at: Option<Instant> = None;
+    loop {
+        let out = select2::or(rx.recv(), interval.tick()).await;
+        let closed = match out {
+            select2::OrOutput::Left(Some(record)) => {
+                pending.push(record);
+                if pending.len() < sink.batch_size() {
+                    continue;
+                }
+                false
+            }
+            select2::OrOutput::Left(None) => true,
+            select2::OrOutput::Right(_) => false,
+        };
+
+        let in_backoff = retry_at.is_some_and(|retry_at| retry_at > Instant::now());
+        if !pending.is_empty() && (closed || !in_backoff) {
+            match sink.write(&pending).await {
+                Ok(_) => {
+                    log::info!("[CdrWriter] wrote {} records to {}", pending.len(), sink.name());
+                    pending.clear();
+                    failures = 0;
+                    retry_at = None;
+                }
+                Err(e) => {
+                    failures += 1;
+                    let delay = retry_delay(failures);
+                    retry_at = Some(Instant::now() + delay);
+                    log::error!("[CdrWriter] write {} records to {} error {e:?} => retry in {delay:?}", pending.len(), sink.name());
+                }
+            }
+        }
+
+        if pending.len() > MAX_PENDING_RECORDS {
+            let dropped = pending.len() - MAX_PENDING_RECORDS;
+            log::warn!("[CdrWriter] sink {} pending too much => drop {dropped} oldest records", sink.name());
+            pending.drain(0..dropped);
+        }
+
+        if closed {
+            break;
+        }
+    }
+}
+
+/// Delay before retrying after the given number of consecutive failed writes
+fn retry_delay(failures: u32) -> Duration {
+    RETRY_BASE_DELAY.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(RETRY_MAX_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_until_max() {
+        assert_eq!(retry_delay(1), RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(2), RETRY_BASE_DELAY * 2);
+        assert_eq!(retry_delay(4), RETRY_BASE_DELAY * 8);
+        assert_eq!(retry_delay(100), RETRY_MAX_DELAY);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use atm0s_small_p2p::{
+    now_ms,
+    pubsub_service::{PubsubChannelId, PubsubServiceRequester, SubscriberEventOb},
+};
+use serde::{Deserialize, Serialize};
+use spin::RwLock;
+
+use crate::{error::PrintErrorSimple, protocol::AppId, utils::select3};
+
+/// All nodes publish and subscribe to this channel for sharing active call counters
+const CALL_COUNTER_CHANNEL: u64 = 0x6361_6c6c_636e_7472;
+const SYNC_INTERVAL_MS: u64 = 1000;
+/// Counters from a node which is not refreshed in this window are ignored.
+/// This happens when the node is down or we are partitioned from it.
+const REMOTE_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NodeCallCounts {
+    node: String,
+    apps: HashMap<String, u32>,
+}
+
+struct RemoteCounts {
+    apps: HashMap<String, u32>,
+    updated_at: u64,
+}
+
+#[derive(Default)]
+struct CounterState {
+    local: HashMap<String, u32>,
+    remotes: HashMap<String, RemoteCounts>,
+}
+
+impl CounterState {
+    fn total(&self, app: &str, now: u64) -> u32 {
+        let local = self.local.get(app).copied().unwrap_or(0);
+        let remote: u32 = self
+            .remotes
+            .values()
+            .filter(|remote| now < remote.updated_at + REMOTE_TIMEOUT_MS)
+            .filter_map(|remote| remote.apps.get(app))
+            .sum();
+        local + remote
+    }
+}
+
+/// Active calls per app across the whole cluster.
+///
+/// Each node periodically broadcasts its local counters over pubsub and sums up the latest counters
+/// received from other nodes. When the cluster is partitioned, counters of unreachable nodes expire
+/// after [`REMOTE_TIMEOUT_MS`] and each side enforces limits with the calls it can still see.
+#[derive(Clone)]
+pub struct ClusterCallCounter {
+    state: Arc<RwLock<CounterState>>,
+}
+
+impl ClusterCallCounter {
+    pub fn new(node: String, pubsub: PubsubServiceRequester) -> Self {
+        let state = Arc::new(RwLock::new(CounterState::default()));
+        let state_c = state.clone();
+        tokio::spawn(async move { run_sync_loop(node, state_c, pubsub).await });
+        Self { state }
+    }
+
+    pub fn increase(&self, app: &AppId) {
+        *self.state.write().local.entry(app.to_string()).or_default() += 1;
+    }
+
+    pub fn decrease(&self, app: &AppId) {
+        let mut state = self.state.write();
+        if let Some(count) = state.local.get_mut(app.as_str()) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                state.local.remove(app.as_str());
+            }
+        }
+    }
+
+    pub fn total(&self, app: &AppId) -> u32 {
+        self.state.read().total(app, now_ms())
+    }
+}
+
+async fn run_sync_loop(node: String, state: Arc<RwLock<CounterState>>, pubsub: PubsubServiceRequester) {
+    let channel: PubsubChannelId = CALL_COUNTER_CHANNEL.into();
+    let mut publisher = pubsub.publisher(channel).await;
+    let mut subscriber = pubsub.subscriber(channel).await;
+    let mut interval = tokio::time::interval(Duration::from_millis(SYNC_INTERVAL_MS));
+
+    loop {
+        let out = select3::or(interval.tick(), publisher.recv_ob::<NodeCallCounts>(), subscriber.recv_ob::<NodeCallCounts>()).await;
+        match out {
+            select3::OrOutput::Left(_) => {
+                let now = now_ms();
+                let counts = {
+                    let mut state = state.write();
+                    state.remotes.retain(|_, remote| now < remote.updated_at + REMOTE_TIMEOUT_MS);
+                    NodeCallCounts {
+                        node: node.clone(),
+                        apps: state.local.clone(),
+                    }
+                };
+                publisher.requester().publish_ob(&counts).await.print_error("[ClusterCallCounter] publish counters");
+            }
+            select3::OrOutput::Middle(Ok(_)) => {}
+            select3::OrOutput::Right(Ok(SubscriberEventOb::Publish(counts))) => {
+                if counts.node != node {
+                    state.write().remotes.insert(
+                        counts.node,
+                        RemoteCounts {
+                            apps: counts.apps,
+                            updated_at: now_ms(),
+                        },
+                    );
+                }
+            }
+            select3::OrOutput::Right(Ok(_)) => {}
+            select3::OrOutput::Middle(Err(_)) | select3::OrOutput::Right(Err(_)) => {
+                log::error!("[ClusterCallCounter] pubsub channel closed => stop syncing");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn total_ignores_expired_remotes() {
+        let mut state = CounterState::default();
+        state.local.insert("app1".to_owned(), 2);
+        state.remotes.insert(
+            "node2".to_owned(),
+            RemoteCounts {
+                apps: HashMap::from([("app1".to_owned(), 3)]),
+                updated_at: 1000,
+            },
+        );
+        state.remotes.insert(
+            "node3".to_owned(),
+            RemoteCounts {
+                apps: HashMap::from([("app1".to_owned(), 5), ("app2".to_owned(), 1)]),
+                updated_at: 10000,
+            },
+        );
+
+        assert_eq!(state.total("app1", 2000), 10);
+        assert_eq!(state.total("app2", 2000), 1);
+        // node2 is not refreshed anymore
+        assert_eq!(state.total("app1", 1000 + REMOTE_TIMEOUT_MS), 7);
+        assert_eq!(state.total("app3", 2000), 0);
+    }
+}
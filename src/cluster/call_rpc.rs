@@ -0,0 +1,352 @@
+use std::{
+    collections::HashSet,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+use atm0s_small_p2p::{
+    pubsub_service::{PublisherEventOb, PubsubChannelId, PubsubServiceRequester},
+    PeerId,
+};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use spin::RwLock;
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::{
+    error::PrintErrorSimple,
+    http::HttpCommand,
+    protocol::{
+        protobuf::sip_gateway::{
+            incoming_call_data::{incoming_call_request, incoming_call_response},
+            outgoing_call_data::{outgoing_call_request, outgoing_call_response},
+        },
+        AppId, CallApiError, CallDirection, CallInfo, CallSipTrace, IncomingCallActionResponse, InternalCallId, OutgoingCallActionResponse,
+    },
+    sip::SipTrace,
+};
+
+const RPC_TIMEOUT_SECONDS: u64 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum NodeCallRequest {
+    List(Option<AppId>),
+    Get(InternalCallId, Option<AppId>),
+    Hangup(InternalCallId, Option<AppId>),
+    SipTrace(InternalCallId, Option<AppId>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum NodeCallResponse {
+    List(Vec<CallInfo>),
+    Get(Option<CallInfo>),
+    /// false when the call is not owned by the node
+    Hangup(bool),
+    SipTrace(Option<CallSipTrace>),
+    Error(String),
+}
+
+/// Each node handles call requests on its own channel
+fn node_channel(node: PeerId) -> PubsubChannelId {
+    let mut hasher = DefaultHasher::default();
+    ("call-rpc", node.to_string()).hash(&mut hasher);
+    hasher.finish().into()
+}
+
+/// Cluster-wide call lookup, hangup and SIP traces.
+///
+/// Calls are owned by the node which created them, so requests are sent to all connected nodes
+/// over their node channel, and each node answers with the calls it owns.
+#[derive(Clone)]
+pub struct ClusterCallRpc {
+    peers: Arc<RwLock<HashSet<PeerId>>>,
+    pubsub: PubsubServiceRequester,
+    local: LocalCalls,
+}
+
+impl ClusterCallRpc {
+    pub fn new(node: PeerId, pubsub: PubsubServiceRequester, call_pubsub: PubsubServiceRequester, tx: Sender<HttpCommand>, sip_trace: SipTrace) -> Self {
+        let local = LocalCalls { tx, call_pubsub, sip_trace };
+        let local_c = local.clone();
+        let pubsub_c = pubsub.clone();
+        tokio::spawn(async move { run_rpc_loop(node, local_c, pubsub_c).await });
+        Self {
+            peers: Default::default(),
+            pubsub,
+            local,
+        }
+    }
+
+    pub fn on_peer_connected(&self, peer: PeerId) {
+        self.peers.write().insert(peer);
+    }
+
+    pub fn on_peer_disconnected(&self, peer: PeerId) {
+        self.peers.write().remove(&peer);
+    }
+
+    pub fn peers(&self) -> Vec<PeerId> {
+        self.peers.read().iter().copied().collect()
+    }
+
+    /// List calls of all reachable nodes, nodes which failed to answer are skipped
+    pub async fn list_calls(&self, app_id: Option<AppId>) -> Result<Vec<CallInfo>, CallApiError> {
+        let mut calls = self.local.list_calls(app_id.clone()).await?;
+        for res in self.request_peers(NodeCallRequest::List(app_id)).await {
+            if let NodeCallResponse::List(remote) = res {
+                calls.extend(remote);
+            }
+        }
+        Ok(calls)
+    }
+
+    pub async fn get_call(&self, call_id: InternalCallId, app_id: Option<AppId>) -> Result<Option<CallInfo>, CallApiError> {
+        if let Some(call) = self.local.get_call(call_id.clone(), app_id.clone()).await? {
+            return Ok(Some(call));
+        }
+        let found = self.request_peers(NodeCallRequest::Get(call_id, app_id)).await.into_iter().find_map(|res| match res {
+            NodeCallResponse::Get(call) => call,
+            _ => None,
+        });
+        Ok(found)
+    }
+
+    /// Ends the call on the node which owns it, returns CallNotFound if no node owns it
+    pub async fn hangup(&self, call_id: InternalCallId, app_id: Option<AppId>) -> Result<(), CallApiError> {
+        if self.local.hangup(call_id.clone(), app_id.clone()).await? {
+            return Ok(());
+        }
+        for res in self.request_peers(NodeCallRequest::Hangup(call_id, app_id)).await {
+            match res {
+                NodeCallResponse::Hangup(true) => return Ok(()),
+                NodeCallResponse::Error(e) => return Err(CallApiError::SipError(e)),
+                _ => {}
+            }
+        }
+        Err(CallApiError::CallNotFound)
+    }
+
+    /// SIP trace is kept by the node which handled the call, also after the call ended
+    pub async fn sip_trace(&self, call_id: InternalCallId, app_id: Option<AppId>) -> Result<Option<CallSipTrace>, CallApiError> {
+        if let Some(trace) = self.local.sip_trace.get(&call_id, app_id.as_ref()) {
+            return Ok(Some(trace));
+        }
+        let found = self.request_peers(NodeCallRequest::SipTrace(call_id, app_id)).await.into_iter().find_map(|res| match res {
+            NodeCallResponse::SipTrace(trace) => trace,
+            _ => None,
+        });
+        Ok(found)
+    }
+
+    async fn request_peers(&self, req: NodeCallRequest) -> Vec<NodeCallResponse> {
+        let peers = self.peers();
+        let req = &req;
+        let requests = peers.into_iter().map(|peer| async move {
+            let res = self
+                .pubsub
+                .feedback_rpc_as_guest_ob::<_, NodeCallResponse>(node_channel(peer), "call", req, Duration::from_secs(RPC_TIMEOUT_SECONDS))
+                .await;
+            if let Err(e) = &res {
+                log::warn!("[ClusterCallRpc] request {req:?} to node {peer} error {e:?}");
+            }
+            res.ok()
+        });
+        join_all(requests).await.into_iter().flatten().collect()
+    }
+}
+
+#[derive(Clone)]
+struct LocalCalls {
+    tx: Sender<HttpCommand>,
+    call_pubsub: PubsubServiceRequester,
+    sip_trace: SipTrace,
+}
+
+impl LocalCalls {
+    async fn list_calls(&self, app_id: Option<AppId>) -> Result<Vec<CallInfo>, CallApiError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(HttpCommand::ListCalls(app_id, tx)).await.map_err(|e| CallApiError::InternalChannel(e.to_string()))?;
+        rx.await.map_err(|e| CallApiError::InternalChannel(e.to_string()))
+    }
+
+    async fn get_call(&self, call_id: InternalCallId, app_id: Option<AppId>) -> Result<Option<CallInfo>, CallApiError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(HttpCommand::GetCall(call_id, app_id, tx))
+            .await
+            .map_err(|e| CallApiError::InternalChannel(e.to_string()))?;
+        rx.await.map_err(|e| CallApiError::InternalChannel(e.to_string()))
+    }
+
+    async fn hangup(&self, call_id: InternalCallId, app_id: Option<AppId>) -> Result<bool, CallApiError> {
+        let call = if let Some(call) = self.get_call(call_id.clone(), app_id).await? {
+            call
+        } else {
+            return Ok(false);
+        };
+
+        let channel = call_id.to_pubsub_channel();
+        let timeout = Duration::from_secs(RPC_TIMEOUT_SECONDS);
+        match call.direction {
+            CallDirection::Outgoing => {
+                let req = outgoing_call_request::Action::End(Default::default());
+                let res = self
+                    .call_pubsub
+                    .feedback_rpc_as_guest_ob::<_, outgoing_call_response::Response>(channel, "destroy", &req, timeout)
+                    .await
+                    .map_err(|e| CallApiError::InternalChannel(e.to_string()))?;
+                let _: OutgoingCallActionResponse = res.try_into().map_err(CallApiError::SipError)?;
+            }
+            CallDirection::Incoming => {
+                let req = incoming_call_request::Action::End(Default::default());
+                let res = self
+                    .call_pubsub
+                    .feedback_rpc_as_guest_ob::<_, incoming_call_response::Response>(channel, "destroy", &req, timeout)
+                    .await
+                    .map_err(|e| CallApiError::InternalChannel(e.to_string()))?;
+                let _: IncomingCallActionResponse = res.try_into().map_err(CallApiError::SipError)?;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn handle(&self, req: NodeCallRequest) -> NodeCallResponse {
+        let res = match req {
+            NodeCallRequest::List(app_id) => self.list_calls(app_id).await.map(NodeCallResponse::List),
+            NodeCallRequest::Get(call_id, app_id) => self.get_call(call_id, app_id).await.map(NodeCallResponse::Get),
+            NodeCallRequest::Hangup(call_id, app_id) => self.hangup(call_id, app_id).await.map(NodeCallResponse::Hangup),
+            NodeCallRequest::SipTrace(call_id, app_id) => Ok(NodeCallResponse::SipTrace(self.sip_trace.get(&call_id, app_id.as_ref()))),
+        };
+        res.unwrap_or_else(|e| NodeCallResponse::Error(e.to_string()))
+    }
+}
+
+async fn run_rpc_loop(node: PeerId, local: LocalCalls, pubsub: PubsubServiceRequester) {
+    let mut publisher = pubsub.publisher(node_channel(node)).await;
+    loop {
+        match publisher.recv_ob::<NodeCallRequest>().await {
+            Ok(PublisherEventOb::FeedbackRpc(req, rpc_id, _method, peer_src) | PublisherEventOb::GuestFeedbackRpc(req, rpc_id, _method, peer_src)) => {
+                log::debug!("[ClusterCallRpc] on rpc from {peer_src:?} with payload: {req:?}");
+                let res = local.handle(req).await;
+                publisher.requester().answer_feedback_rpc_ob(rpc_id, peer_src, &res).await.print_error("[ClusterCallRpc] answer rpc");
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("[ClusterCallRpc] rpc loop error {e:?}");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use atm0s_small_p2p::{pubsub_service::PubsubService, P2pNetwork, P2pNetworkConfig, SharedKeyHandshake};
+    use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    use crate::{
+        address_book::AddressBookStorage,
+        protocol::{AppInfo, CallState},
+        secure::SecureContext,
+        DEFAULT_CLUSTER_CERT, DEFAULT_CLUSTER_KEY,
+    };
+
+    use super::*;
+
+    fn app(app_id: &str, app_secret: &str) -> AppInfo {
+        AppInfo {
+            app_id: app_id.to_owned(),
+            app_secret: app_secret.to_owned(),
+            max_calls: None,
+            subscriber_grace_ms: None,
+            previous_app_secret: None,
+            previous_expires_at: None,
+        }
+    }
+
+    /// Answers call lookups like the CallManager does, with a single active call of app1
+    async fn call_manager(mut rx: Receiver<HttpCommand>, call: CallInfo) {
+        while let Some(cmd) = rx.recv().await {
+            if let HttpCommand::GetCall(call_id, app_id, tx) = cmd {
+                let owned = *call_id == call.call_id && app_id.is_none_or(|app_id| *app_id == call.app_id);
+                let _ = tx.send(owned.then(|| call.clone()));
+            }
+        }
+    }
+
+    /// Answers end requests like the outgoing call task does, reports each ended call
+    async fn call_task(pubsub: PubsubServiceRequester, call_id: InternalCallId, ended: Sender<()>) {
+        let mut publisher = pubsub.publisher(call_id.to_pubsub_channel()).await;
+        while let Ok(event) = publisher.recv_ob::<outgoing_call_request::Action>().await {
+            if let PublisherEventOb::FeedbackRpc(_, rpc_id, _, peer_src) | PublisherEventOb::GuestFeedbackRpc(_, rpc_id, _, peer_src) = event {
+                let res = outgoing_call_response::Response::End(Default::default());
+                publisher.requester().answer_feedback_rpc_ob(rpc_id, peer_src, &res).await.expect("should answer");
+                ended.send(()).await.expect("should report end");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn hangup_only_ends_calls_of_the_secret_app() {
+        let node = PeerId::from(1);
+        let mut p2p = P2pNetwork::new(P2pNetworkConfig {
+            peer_id: node,
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            advertise: None,
+            priv_key: PrivatePkcs8KeyDer::from(DEFAULT_CLUSTER_KEY.to_vec()),
+            cert: CertificateDer::from(DEFAULT_CLUSTER_CERT.to_vec()),
+            tick_ms: 100,
+            seeds: vec![],
+            secure: SharedKeyHandshake::from("secret"),
+        })
+        .await
+        .expect("should create p2p");
+        let mut pubsub_call = PubsubService::new(p2p.create_service(0.into()));
+        let mut pubsub_cluster = PubsubService::new(p2p.create_service(1.into()));
+        let call_pubsub = pubsub_call.requester();
+        let cluster_pubsub = pubsub_cluster.requester();
+        tokio::spawn(async move { while p2p.recv().await.is_ok() {} });
+        tokio::spawn(async move { while pubsub_call.run_loop().await.is_ok() {} });
+        tokio::spawn(async move { while pubsub_cluster.run_loop().await.is_ok() {} });
+
+        let address_book = AddressBookStorage::new("root");
+        address_book.set_static(vec![app("app1", "secret1"), app("app2", "secret2")], vec![]);
+        let secure_ctx = SecureContext::new("root", address_book);
+
+        let call_id = InternalCallId::random();
+        let call = CallInfo {
+            call_id: call_id.to_string(),
+            app_id: "app1".to_owned(),
+            direction: CallDirection::Outgoing,
+            state: CallState::Talking,
+            from_number: "100".to_owned(),
+            to_number: "200".to_owned(),
+            created_at: 0,
+            answered_at: None,
+            duration_ms: 0,
+            talk_duration_ms: None,
+            streaming: None,
+        };
+        let (http_tx, http_rx) = channel(10);
+        let (ended_tx, mut ended_rx) = channel(10);
+        tokio::spawn(call_manager(http_rx, call));
+        tokio::spawn(call_task(call_pubsub.clone(), call_id.clone(), ended_tx));
+        let rpc = ClusterCallRpc::new(node, cluster_pubsub, call_pubsub, http_tx, SipTrace::new(10, Duration::ZERO, None));
+
+        // same checks as the DELETE /call/:call_id api
+        let scope = |secret: &str| secure_ctx.check_secret_scope(secret).expect("should be a valid secret");
+        let res = rpc.hangup(call_id.clone(), scope("secret2")).await;
+        assert!(matches!(res, Err(CallApiError::CallNotFound)), "foreign app got {res:?}");
+        assert!(ended_rx.try_recv().is_err());
+
+        rpc.hangup(call_id.clone(), scope("secret1")).await.expect("owner should end the call");
+        ended_rx.recv().await.expect("call should be ended");
+
+        rpc.hangup(call_id, scope("root")).await.expect("root should end the call");
+        ended_rx.recv().await.expect("call should be ended");
+    }
+}
@@ -0,0 +1,5 @@
+mod call_counter;
+mod call_rpc;
+
+pub use call_counter::ClusterCallCounter;
+pub use call_rpc::ClusterCallRpc;
@@ -0,0 +1,277 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::anyhow;
+use clap::{parser::ValueSource, ArgMatches};
+use serde::Deserialize;
+use tokio::time::{interval, Interval, MissedTickBehavior};
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::{
+    protocol::{AppInfo, PhoneNumber},
+    CloudProvider, HepTransport, HttpHookOverflow,
+};
+
+/// How often the config file is checked for changes
+const CONFIG_CHECK_INTERVAL_MS: u64 = 5_000;
+
+/// Settings of the config file, in TOML or YAML depending on the file extension.
+///
+/// Startup keys are the command line flags with underscores, and are overridden by flags and env vars.
+/// `log_level`, `limits` and `address_book` are only set in the file and reloaded on SIGHUP or file change.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub sdn_peer_id: Option<u64>,
+    pub sdn_listener: Option<SocketAddr>,
+    pub sdn_seeds: Option<Vec<String>>,
+    pub sdn_seeds_from_url: Option<String>,
+    pub sdn_secure_code: Option<String>,
+    pub http_listen: Option<SocketAddr>,
+    pub http_public: Option<String>,
+    pub sip_listen: Option<SocketAddr>,
+    pub public_ip: Option<IpAddr>,
+    pub public_ip_cloud: Option<CloudProvider>,
+    pub secret: Option<String>,
+    pub phone_numbers_sync: Option<String>,
+    pub apps_sync: Option<String>,
+    pub sync_interval_ms: Option<u64>,
+    pub http_hook_queues: Option<usize>,
+    pub http_hook_max_retries: Option<usize>,
+    pub http_hook_retry_base_ms: Option<u64>,
+    pub http_hook_retry_max_ms: Option<u64>,
+    pub http_hook_queue_capacity: Option<usize>,
+    pub http_hook_overflow: Option<HttpHookOverflow>,
+    pub http_hook_block_timeout_ms: Option<u64>,
+    pub hook_data_dir: Option<PathBuf>,
+    pub media_gateway: Option<String>,
+    pub media_app_sync: Option<String>,
+    pub cdr_file: Option<PathBuf>,
+    pub cdr_http_endpoint: Option<String>,
+    pub cdr_http_batch_size: Option<usize>,
+    pub cdr_flush_interval_ms: Option<u64>,
+    pub subscriber_grace_ms: Option<u64>,
+    pub sip_trace_capacity: Option<usize>,
+    pub sip_trace_retention_secs: Option<u64>,
+    pub hep_collector: Option<SocketAddr>,
+    pub hep_transport: Option<HepTransport>,
+    pub hep_capture_id: Option<u32>,
+    pub hep_auth_key: Option<String>,
+    pub otlp_endpoint: Option<String>,
+    pub otlp_service_name: Option<String>,
+    pub drain_timeout_secs: Option<u64>,
+
+    /// Level of logs, ex: info or debug
+    pub log_level: Option<String>,
+    pub limits: CallLimits,
+    pub address_book: StaticAddressBook,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::from_content(path, &content)
+    }
+
+    /// Parse the content of a config file, the format is picked from the extension of its path
+    fn from_content(path: &Path, content: &str) -> anyhow::Result<Self> {
+        let cfg = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(content)?,
+            Some("yaml" | "yml") => serde_yaml_ng::from_str(content)?,
+            _ => return Err(anyhow!("unsupported config file {}, expected .toml, .yaml or .yml", path.display())),
+        };
+        Ok(cfg)
+    }
+
+    /// Sections which are applied without restart
+    pub fn reloadable(&self) -> anyhow::Result<ReloadableConfig> {
+        let log_level = match &self.log_level {
+            Some(level) => LevelFilter::from_str(level).map_err(|e| anyhow!("invalid log_level {level}: {e}"))?,
+            None => LevelFilter::INFO,
+        };
+        Ok(ReloadableConfig {
+            log_level,
+            limits: self.limits.clone(),
+            address_book: self.address_book.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReloadableConfig {
+    pub log_level: LevelFilter,
+    pub limits: CallLimits,
+    pub address_book: StaticAddressBook,
+}
+
+/// Call limits on top of the `max_calls` of each app
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CallLimits {
+    /// Max active calls across the cluster of apps which don't set `max_calls`
+    pub default_app_max_calls: Option<u32>,
+    /// Max active calls of this node
+    pub node_max_calls: Option<u32>,
+}
+
+/// Apps and numbers which are not synced from the address book endpoints, synced entries with the same id take precedence
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StaticAddressBook {
+    pub apps: Vec<AppInfo>,
+    pub numbers: Vec<PhoneNumber>,
+}
+
+/// Set a startup arg from the config file when it's not set by a flag or env var, so it only keeps its default without file value
+pub fn merge_config_value<T>(matches: &ArgMatches, id: &str, arg: &mut T, file: Option<T>) {
+    merge_value(matches.value_source(id), arg, file);
+}
+
+fn merge_value<T>(source: Option<ValueSource>, arg: &mut T, file: Option<T>) {
+    let explicit = matches!(source, Some(ValueSource::CommandLine | ValueSource::EnvVariable));
+    if let (false, Some(value)) = (explicit, file) {
+        *arg = value;
+    }
+}
+
+/// Detect changes of the config file by its modified time
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    interval: Interval,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> Self {
+        let mut interval = interval(Duration::from_millis(CONFIG_CHECK_INTERVAL_MS));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            path: path.to_owned(),
+            modified: modified_time(path),
+            interval,
+        }
+    }
+
+    /// Resolve when the file is changed since the last change, it is cancel safe
+    pub async fn changed(&mut self) {
+        loop {
+            self.interval.tick().await;
+            let modified = modified_time(&self.path);
+            if modified.is_some() && modified != self.modified {
+                self.modified = modified;
+                return;
+            }
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{value_parser, Arg, Command};
+
+    use super::*;
+
+    const TOML: &str = r#"
+sip_listen = "0.0.0.0:5060"
+http_hook_overflow = "drop-newest"
+log_level = "debug"
+
+[limits]
+node_max_calls = 10
+
+[[address_book.apps]]
+app_id = "app1"
+app_secret = "secret1"
+"#;
+
+    const YAML: &str = r#"
+sip_listen: "0.0.0.0:5060"
+http_hook_overflow: drop-newest
+log_level: debug
+limits:
+  node_max_calls: 10
+address_book:
+  apps:
+    - app_id: app1
+      app_secret: secret1
+"#;
+
+    fn assert_parsed(cfg: ConfigFile) {
+        assert_eq!(cfg.sip_listen, Some("0.0.0.0:5060".parse().expect("should parse")));
+        assert_eq!(cfg.http_hook_overflow, Some(HttpHookOverflow::DropNewest));
+        assert_eq!(cfg.limits.node_max_calls, Some(10));
+        assert_eq!(cfg.address_book.apps.len(), 1);
+        assert_eq!(cfg.address_book.apps[0].app_id, "app1");
+        assert_eq!(cfg.reloadable().expect("should be valid").log_level, LevelFilter::DEBUG);
+    }
+
+    /// A startup arg with a default value which can be set by flag or env var
+    fn command() -> Command {
+        Command::new("gateway").arg(
+            Arg::new("sip_trace_capacity")
+                .long("sip-trace-capacity")
+                .env("CONFIG_TEST_SIP_TRACE_CAPACITY")
+                .default_value("100")
+                .value_parser(value_parser!(usize)),
+        )
+    }
+
+    fn merged(matches: &ArgMatches, file: Option<usize>) -> usize {
+        let mut arg = *matches.get_one::<usize>("sip_trace_capacity").expect("should have default");
+        merge_config_value(matches, "sip_trace_capacity", &mut arg, file);
+        arg
+    }
+
+    #[test]
+    fn parse_toml_and_yaml() {
+        assert_parsed(ConfigFile::from_content(Path::new("gateway.toml"), TOML).expect("should parse toml"));
+        assert_parsed(ConfigFile::from_content(Path::new("gateway.yaml"), YAML).expect("should parse yaml"));
+        assert_parsed(ConfigFile::from_content(Path::new("gateway.yml"), YAML).expect("should parse yml"));
+        assert!(ConfigFile::from_content(Path::new("gateway.json"), "{}").is_err());
+    }
+
+    #[test]
+    fn reject_unknown_keys() {
+        assert!(ConfigFile::from_content(Path::new("gateway.toml"), "sip_listenn = \"0.0.0.0:5060\"").is_err());
+        assert!(ConfigFile::from_content(Path::new("gateway.toml"), "[limits]\nnode_max_call = 1").is_err());
+        assert!(ConfigFile::from_content(Path::new("gateway.yaml"), "sip_listenn: 0.0.0.0:5060").is_err());
+        assert!(ConfigFile::from_content(Path::new("gateway.yaml"), "address_book:\n  app: []").is_err());
+    }
+
+    #[test]
+    fn reject_invalid_log_level() {
+        let cfg = ConfigFile::from_content(Path::new("gateway.toml"), "log_level = \"loud\"").expect("should parse");
+        assert!(cfg.reloadable().is_err());
+    }
+
+    #[test]
+    fn file_overrides_default() {
+        let matches = command().get_matches_from(["gateway"]);
+        assert_eq!(merged(&matches, Some(5)), 5);
+        assert_eq!(merged(&matches, None), 100);
+    }
+
+    #[test]
+    fn flag_overrides_file() {
+        let matches = command().get_matches_from(["gateway", "--sip-trace-capacity", "7"]);
+        assert_eq!(merged(&matches, Some(5)), 7);
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        // clap only reads env vars of the process, which tests must not change, so the source is given as clap reports it
+        let mut arg = 9;
+        merge_value(Some(ValueSource::EnvVariable), &mut arg, Some(5));
+        assert_eq!(arg, 9);
+        merge_value(Some(ValueSource::DefaultValue), &mut arg, Some(5));
+        assert_eq!(arg, 5);
+    }
+}
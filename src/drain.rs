@@ -0,0 +1,23 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Drain mode of this node, shared between the gateway loop, the call manager and the http server.
+///
+/// Once draining, the node doesn't take new calls and is not ready anymore, it can't go back to normal.
+#[derive(Clone, Default)]
+pub struct DrainState {
+    draining: Arc<AtomicBool>,
+}
+
+impl DrainState {
+    /// Return false if the node is already draining
+    pub fn start(&self) -> bool {
+        !self.draining.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
@@ -1,39 +1,220 @@
-use std::{collections::HashMap, marker::PhantomData};
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    marker::PhantomData,
+    path::Path,
+};
 
+use atm0s_small_p2p::pubsub_service::PubsubServiceRequester;
 use prost::Message;
-use queue::{new_queue, HttpHookRequest};
-use serde::Serialize;
-use tokio::sync::mpsc::UnboundedSender;
+use push::HookPush;
+use queue::{HttpHookQueue, HttpHookRequest};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
+use outbox::HookOutbox;
+
+use crate::{
+    address_book::AddressBookStorage,
+    protocol::{AppId, HookAuth, HookContentType, HookEventKind, HookSecret, HookSubscription, RedactedHeaders},
+};
+
+mod outbox;
+mod push;
 mod queue;
 mod sender;
+mod signature;
 
+pub use outbox::DeadLetter;
+pub use push::PUSH_METHOD_NOTIFY;
+pub use queue::{HttpHookLimit, HttpHookOverflow, HttpHookQueueStats, HttpHookRetry};
 pub use sender::HttpHookSender;
 
+/// Events which can be filtered by kind in hook subscriptions
+pub trait HookEvent {
+    fn kind(&self) -> Option<HookEventKind>;
+}
+
+/// Where headers, auth and signing secrets of a queued hook are resolved from at each try, only this reference is written to the outbox
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HookCredentialsRef {
+    /// Hooks of an outgoing call, signed with the app secret
+    #[default]
+    Call,
+    /// Hooks of an incoming call to a phone number, signed with the number hook secrets
+    Number { number: String },
+}
+
+/// A hook destination with its own content type, headers and event filter, all kinds if events is empty
+#[derive(Clone)]
+pub struct HttpHookTarget {
+    pub endpoint: String,
+    pub content_type: HookContentType,
+    pub headers: HashMap<String, String>,
+    pub auth: Option<HookAuth>,
+    pub secrets: Vec<HookSecret>,
+    pub credentials_ref: HookCredentialsRef,
+    pub events: Vec<HookEventKind>,
+}
+
+impl std::fmt::Debug for HttpHookTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpHookTarget")
+            .field("endpoint", &self.endpoint)
+            .field("content_type", &self.content_type)
+            .field("headers", &RedactedHeaders(&self.headers))
+            .field("auth", &self.auth)
+            .field("secrets", &self.secrets)
+            .field("credentials_ref", &self.credentials_ref)
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+impl HttpHookTarget {
+    pub fn from_subscription(sub: HookSubscription, secrets: Vec<HookSecret>, credentials_ref: HookCredentialsRef) -> Self {
+        Self {
+            endpoint: sub.url,
+            content_type: sub.content_type,
+            headers: sub.headers,
+            auth: sub.auth,
+            secrets,
+            credentials_ref,
+            events: sub.events,
+        }
+    }
+}
+
 pub struct HttpHook<Event> {
-    queues: Vec<UnboundedSender<HttpHookRequest<Event>>>,
+    queues: Vec<HttpHookQueue<Event>>,
+    outbox: HookOutbox<Event>,
+    push: HookPush,
+}
+
+impl<Event> Clone for HttpHook<Event> {
+    fn clone(&self) -> Self {
+        Self {
+            queues: self.queues.clone(),
+            outbox: self.outbox.clone(),
+            push: self.push.clone(),
+        }
+    }
 }
 
 impl<Event> HttpHook<Event>
 where
-    Event: Serialize + Message + Send + Sync + Default + 'static,
+    Event: Serialize + DeserializeOwned + Message + HookEvent + Clone + Send + Sync + Default + 'static,
 {
-    pub fn new(size: usize) -> Self {
+    /// Undelivered events from the outbox in data_dir are queued again.
+    /// Undelivered events which exceed the queue capacity are moved to dead letters
+    /// Hooks of ws-push:// endpoints are sent over the pubsub to push sockets of the app.
+    /// Signing secrets of queued events are resolved from the address book at each try.
+    pub async fn new(size: usize, retry: HttpHookRetry, limit: HttpHookLimit, data_dir: Option<&Path>, pubsub: PubsubServiceRequester, address_book: AddressBookStorage) -> std::io::Result<Self> {
+        let (outbox, pending) = HookOutbox::new(data_dir).await?;
+        let push = HookPush::new(pubsub);
         let mut queues = vec![];
-        for _ in 0..size {
-            let queue_tx = new_queue();
-            queues.push(queue_tx);
+        for index in 0..size {
+            queues.push(HttpHookQueue::new(index, retry, limit, outbox.clone(), push.clone(), address_book.clone()));
+        }
+        let hook = Self { queues, outbox, push };
+        for req in pending {
+            hook.enqueue(req);
         }
-        Self { queues }
+        Ok(hook)
     }
 
-    pub fn new_sender(&self, endpoint: &str, headers: HashMap<String, String>) -> HttpHookSender<Event> {
-        let index = rand::random::<usize>() % self.queues.len();
+    /// Events of a sender are delivered in order to each target, so all events of a key (call_id) must use the same sender
+    pub fn new_sender(&self, key: &str, app_id: &AppId, primary: Option<HttpHookTarget>, subscriptions: Vec<HttpHookTarget>) -> HttpHookSender<Event> {
         HttpHookSender {
-            endpoint: endpoint.to_owned(),
-            headers,
-            tx: self.queues[index].clone(),
+            key: key.to_owned(),
+            app_id: app_id.clone(),
+            primary,
+            subscriptions,
+            queue: self.queue(key).clone(),
+            outbox: self.outbox.clone(),
+            push: self.push.clone(),
             _tmp: PhantomData,
         }
     }
+
+    pub fn dead_letters(&self) -> Vec<DeadLetter<Event>> {
+        self.outbox.dead_letters()
+    }
+
+    /// Queue a dead letter again, None id means all dead letters. Return number of replayed events
+    pub fn replay_dead_letters(&self, id: Option<u64>) -> usize {
+        let ids = id.map(|id| vec![id]).unwrap_or_else(|| self.outbox.dead_letter_ids());
+        let mut count = 0;
+        for id in ids {
+            if let Some(req) = self.outbox.replay(id) {
+                self.enqueue(req);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Remove dead letters, None id means all dead letters. Return number of removed events
+    pub fn purge_dead_letters(&self, id: Option<u64>) -> usize {
+        let ids = id.map(|id| vec![id]).unwrap_or_else(|| self.outbox.dead_letter_ids());
+        ids.into_iter().filter(|id| self.outbox.purge(*id)).count()
+    }
+
+    pub fn queue_stats(&self) -> Vec<HttpHookQueueStats> {
+        self.queues.iter().map(|queue| queue.stats()).collect()
+    }
+
+    fn queue(&self, key: &str) -> &HttpHookQueue<Event> {
+        let mut hasher = DefaultHasher::default();
+        key.hash(&mut hasher);
+        &self.queues[hasher.finish() as usize % self.queues.len()]
+    }
+
+    fn enqueue(&self, req: HttpHookRequest<Event>) {
+        self.queue(&req.key).push(req);
+    }
+}
+
+/// Headers which are set by the gateway and can't be overridden by hook headers
+const RESERVED_HOOK_HEADERS: [&str; 6] = ["content-type", "content-length", "host", "transfer-encoding", "x-hook-signature", "x-hook-timestamp"];
+
+/// Check that hook headers are valid HTTP headers and don't override headers set by the gateway
+pub fn validate_hook_headers(headers: &HashMap<String, String>) -> Result<(), String> {
+    for (name, value) in headers {
+        let name = reqwest::header::HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid hook header name {name}"))?;
+        if RESERVED_HOOK_HEADERS.contains(&name.as_str()) {
+            return Err(format!("reserved hook header {name}"));
+        }
+        reqwest::header::HeaderValue::from_str(value).map_err(|_| format!("invalid value of hook header {name}"))?;
+    }
+    Ok(())
+}
+
+/// Add headers and authentication of a hook to the request
+fn build_request(builder: reqwest::RequestBuilder, headers: &HashMap<String, String>, auth: &Option<HookAuth>) -> reqwest::RequestBuilder {
+    let builder = headers.iter().fold(builder, |builder, (k, v)| builder.header(k, v));
+    match auth {
+        Some(HookAuth::Bearer(auth)) => builder.bearer_auth(&auth.token),
+        Some(HookAuth::Basic(auth)) => builder.basic_auth(&auth.username, Some(&auth.password)),
+        None => builder,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &str, value: &str) -> HashMap<String, String> {
+        HashMap::from([(name.to_owned(), value.to_owned())])
+    }
+
+    #[test]
+    fn validate_hook_headers_rejects_invalid_and_reserved() {
+        assert!(validate_hook_headers(&HashMap::new()).is_ok());
+        assert!(validate_hook_headers(&headers("X-Tenant", "tenant1")).is_ok());
+        assert!(validate_hook_headers(&headers("X Tenant", "tenant1")).is_err());
+        assert!(validate_hook_headers(&headers("X-Tenant", "line\nbreak")).is_err());
+        assert!(validate_hook_headers(&headers("X-Hook-Signature", "v1=00")).is_err());
+        assert!(validate_hook_headers(&headers("content-type", "text/plain")).is_err());
+    }
 }
@@ -0,0 +1,459 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use atm0s_small_p2p::now_ms;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use spin::RwLock;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+};
+
+use super::queue::HttpHookRequest;
+
+const OUTBOX_FILE: &str = "hook_outbox.jsonl";
+/// Journal is rewritten with only live entries after this number of appended ops
+const COMPACT_AFTER_OPS: usize = 10_000;
+/// Oldest dead letters are dropped when this limit is reached
+const MAX_DEAD_LETTERS: usize = 10_000;
+/// Ops which are written and synced to disk together
+const JOURNAL_BATCH: usize = 256;
+
+pub struct DeadLetter<Event> {
+    pub req: HttpHookRequest<Event>,
+    pub error: String,
+    pub failed_at: u64,
+}
+
+/// Journal entry, ops are idempotent so replaying them after a compacted snapshot is safe
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum OutboxOp<Event> {
+    Add { req: Box<HttpHookRequest<Event>> },
+    Done { id: u64 },
+    Dead { id: u64, error: String, failed_at: u64 },
+    Replay { id: u64 },
+    Purge { id: u64 },
+}
+
+/// Op for the journal worker, synced is answered after the op is written and synced to disk
+struct JournalWrite<Event> {
+    op: OutboxOp<Event>,
+    synced: Option<oneshot::Sender<()>>,
+}
+
+/// Persistent outbox of hook events, each event is synced to the journal before queued and removed after delivered.
+/// Other ops are written without waiting, so after a crash a delivered event may be sent again.
+/// Events which are exhausted all retries are kept as dead letters until replayed or purged.
+/// Without data dir the outbox only lives in memory, same for requests which aren't durable.
+pub struct HookOutbox<Event> {
+    state: Arc<RwLock<OutboxState<Event>>>,
+    journal_tx: Option<UnboundedSender<JournalWrite<Event>>>,
+}
+
+impl<Event> Clone for HookOutbox<Event> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            journal_tx: self.journal_tx.clone(),
+        }
+    }
+}
+
+struct OutboxState<Event> {
+    next_id: u64,
+    pending: BTreeMap<u64, HttpHookRequest<Event>>,
+    dead: BTreeMap<u64, DeadLetter<Event>>,
+}
+
+impl<Event> OutboxState<Event> {
+    fn new() -> Self {
+        Self {
+            next_id: 1,
+            pending: BTreeMap::new(),
+            dead: BTreeMap::new(),
+        }
+    }
+
+    fn apply(&mut self, op: OutboxOp<Event>) {
+        match op {
+            OutboxOp::Add { req } => {
+                self.next_id = self.next_id.max(req.id + 1);
+                if !self.dead.contains_key(&req.id) {
+                    self.pending.insert(req.id, *req);
+                }
+            }
+            OutboxOp::Done { id } => {
+                self.pending.remove(&id);
+            }
+            OutboxOp::Dead { id, error, failed_at } => {
+                if let Some(req) = self.pending.remove(&id) {
+                    self.dead.insert(id, DeadLetter { req, error, failed_at });
+                    while self.dead.len() > MAX_DEAD_LETTERS {
+                        self.dead.pop_first();
+                    }
+                }
+            }
+            OutboxOp::Replay { id } => {
+                if let Some(dead) = self.dead.remove(&id) {
+                    self.pending.insert(id, dead.req);
+                }
+            }
+            OutboxOp::Purge { id } => {
+                self.dead.remove(&id);
+            }
+        }
+    }
+}
+
+impl<Event: DeserializeOwned> OutboxState<Event> {
+    /// Apply ops of journal lines, invalid lines like a truncated last line are skipped. Return number of skipped lines
+    fn load(&mut self, content: &str) -> usize {
+        let mut skipped = 0;
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str::<OutboxOp<Event>>(line) {
+                Ok(op) => self.apply(op),
+                Err(e) => {
+                    log::warn!("[HookOutbox] skip invalid journal line {e:?}");
+                    skipped += 1;
+                }
+            }
+        }
+        skipped
+    }
+}
+
+impl<Event> OutboxState<Event>
+where
+    Event: Serialize + Clone,
+{
+    /// Ops which rebuild the current state, used for compacting the journal
+    fn snapshot(&self) -> Vec<OutboxOp<Event>> {
+        let pending = self.pending.values().filter(|req| req.is_durable()).map(|req| OutboxOp::Add { req: Box::new(req.clone()) });
+        let dead = self.dead.values().filter(|dead| dead.req.is_durable()).flat_map(|dead| {
+            [
+                OutboxOp::Add { req: Box::new(dead.req.clone()) },
+                OutboxOp::Dead {
+                    id: dead.req.id,
+                    error: dead.error.clone(),
+                    failed_at: dead.failed_at,
+                },
+            ]
+        });
+        pending.chain(dead).collect()
+    }
+}
+
+impl<Event> HookOutbox<Event>
+where
+    Event: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    /// Load the journal from data dir if provided, pending events are returned for queueing again
+    pub async fn new(data_dir: Option<&Path>) -> std::io::Result<(Self, Vec<HttpHookRequest<Event>>)> {
+        let mut state = OutboxState::new();
+        let path = match data_dir {
+            Some(dir) => {
+                tokio::fs::create_dir_all(dir).await?;
+                Some(dir.join(OUTBOX_FILE))
+            }
+            None => None,
+        };
+
+        if let Some(path) = &path {
+            match tokio::fs::read_to_string(path).await {
+                Ok(content) => {
+                    let skipped = state.load(&content);
+                    log::info!(
+                        "[HookOutbox] loaded {} pending events and {} dead letters from {}, skipped {skipped} invalid lines",
+                        state.pending.len(),
+                        state.dead.len(),
+                        path.display()
+                    );
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let pending = state.pending.values().cloned().collect::<Vec<_>>();
+        let state = Arc::new(RwLock::new(state));
+        let journal_tx = path.map(|path| {
+            let (tx, rx) = unbounded_channel();
+            tokio::spawn(run_journal(path, state.clone(), rx));
+            tx
+        });
+
+        Ok((Self { state, journal_tx }, pending))
+    }
+
+    /// Persist a new event and assign its id, it returns after the event is synced to the journal.
+    /// If the journal can't be written or the event isn't durable, the event is still returned for delivery, but it is lost on restart
+    pub async fn add(&self, mut req: HttpHookRequest<Event>) -> HttpHookRequest<Event> {
+        {
+            let mut state = self.state.write();
+            req.id = state.next_id;
+            state.next_id += 1;
+            state.pending.insert(req.id, req.clone());
+        }
+        if !req.is_durable() {
+            log::debug!("[HookOutbox] hook {} to {} has in-memory headers or auth, not persisted", req.key, req.endpoint);
+            return req;
+        }
+        if let Some(tx) = &self.journal_tx {
+            let (synced_tx, synced_rx) = oneshot::channel();
+            let write = JournalWrite {
+                op: OutboxOp::Add { req: Box::new(req.clone()) },
+                synced: Some(synced_tx),
+            };
+            if tx.send(write).is_err() || synced_rx.await.is_err() {
+                log::error!("[HookOutbox] hook {} to {} is not persisted", req.key, req.endpoint);
+            }
+        }
+        req
+    }
+
+    pub fn done(&self, id: u64) {
+        self.state.write().apply(OutboxOp::Done { id });
+        self.journal(OutboxOp::Done { id });
+    }
+
+    pub fn dead(&self, id: u64, error: String) {
+        let failed_at = now_ms();
+        self.state.write().apply(OutboxOp::Dead { id, error: error.clone(), failed_at });
+        self.journal(OutboxOp::Dead { id, error, failed_at });
+    }
+
+    pub fn dead_letters(&self) -> Vec<DeadLetter<Event>> {
+        self.state
+            .read()
+            .dead
+            .values()
+            .map(|dead| DeadLetter {
+                req: dead.req.clone(),
+                error: dead.error.clone(),
+                failed_at: dead.failed_at,
+            })
+            .collect()
+    }
+
+    pub fn dead_letter_ids(&self) -> Vec<u64> {
+        self.state.read().dead.keys().copied().collect()
+    }
+
+    /// Move a dead letter back to pending, the returned event must be queued again
+    pub fn replay(&self, id: u64) -> Option<HttpHookRequest<Event>> {
+        let mut state = self.state.write();
+        let req = state.dead.get(&id)?.req.clone();
+        state.apply(OutboxOp::Replay { id });
+        drop(state);
+        self.journal(OutboxOp::Replay { id });
+        Some(req)
+    }
+
+    pub fn purge(&self, id: u64) -> bool {
+        let removed = self.state.write().dead.remove(&id).is_some();
+        if removed {
+            self.journal(OutboxOp::Purge { id });
+        }
+        removed
+    }
+
+    fn journal(&self, op: OutboxOp<Event>) {
+        if let Some(tx) = &self.journal_tx {
+            if tx.send(JournalWrite { op, synced: None }).is_err() {
+                log::error!("[HookOutbox] journal worker stopped");
+            }
+        }
+    }
+}
+
+async fn run_journal<Event: Serialize + Clone>(path: PathBuf, state: Arc<RwLock<OutboxState<Event>>>, mut rx: UnboundedReceiver<JournalWrite<Event>>) {
+    // start with a compacted journal
+    let mut ops = COMPACT_AFTER_OPS;
+    let mut file = None;
+    let mut batch = Vec::with_capacity(JOURNAL_BATCH);
+    while rx.recv_many(&mut batch, JOURNAL_BATCH).await > 0 {
+        if ops >= COMPACT_AFTER_OPS {
+            // the snapshot already contains ops of the batch, appending them again is safe because ops are idempotent
+            let snapshot = state.read().snapshot();
+            match compact(&path, &snapshot).await {
+                Ok(f) => {
+                    file = Some(f);
+                    ops = 0;
+                }
+                Err(e) => log::error!("[HookOutbox] compact journal {} error {e:?}", path.display()),
+            }
+        }
+        let f = match &mut file {
+            Some(f) => f,
+            None => match OpenOptions::new().create(true).append(true).open(&path).await {
+                Ok(f) => file.insert(f),
+                Err(e) => {
+                    // dropping the batch drops the synced senders, so waiting callers know their events aren't persisted
+                    log::error!("[HookOutbox] open journal {} error {e:?}", path.display());
+                    batch.clear();
+                    continue;
+                }
+            },
+        };
+        let mut buf = vec![];
+        for write in &batch {
+            serde_json::to_writer(&mut buf, &write.op).expect("should convert to json");
+            buf.push(b'\n');
+        }
+        if let Err(e) = write_synced(f, &buf).await {
+            log::error!("[HookOutbox] write journal {} error {e:?}", path.display());
+            file = None;
+            batch.clear();
+            continue;
+        }
+        ops += batch.len();
+        for write in batch.drain(..) {
+            if let Some(synced) = write.synced {
+                synced.send(()).ok();
+            }
+        }
+    }
+}
+
+async fn write_synced(file: &mut File, buf: &[u8]) -> std::io::Result<()> {
+    file.write_all(buf).await?;
+    file.sync_data().await
+}
+
+/// Write the snapshot to a synced temp file then swap it with the journal
+async fn compact<Event: Serialize>(path: &Path, snapshot: &[OutboxOp<Event>]) -> std::io::Result<File> {
+    let tmp = path.with_extension("jsonl.tmp");
+    let mut buf = vec![];
+    for op in snapshot {
+        serde_json::to_writer(&mut buf, op).expect("should convert to json");
+        buf.push(b'\n');
+    }
+    let mut file = File::create(&tmp).await?;
+    file.write_all(&buf).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await?;
+    OpenOptions::new().append(true).open(path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        hook::HookCredentialsRef,
+        protocol::{HookAuth, HookBearerAuth},
+    };
+
+    use super::*;
+
+    fn req(id: u64) -> HttpHookRequest<String> {
+        HttpHookRequest {
+            id,
+            ..HttpHookRequest::test(&format!("call{id}"), format!("event{id}"))
+        }
+    }
+
+    fn add(id: u64) -> OutboxOp<String> {
+        OutboxOp::Add { req: Box::new(req(id)) }
+    }
+
+    fn dead(id: u64) -> OutboxOp<String> {
+        OutboxOp::Dead {
+            id,
+            error: "status 500".to_owned(),
+            failed_at: 1000,
+        }
+    }
+
+    fn journal(ops: &[OutboxOp<String>]) -> String {
+        ops.iter().map(|op| serde_json::to_string(op).expect("should convert to json") + "\n").collect()
+    }
+
+    fn ids<V>(map: &BTreeMap<u64, V>) -> Vec<u64> {
+        map.keys().copied().collect()
+    }
+
+    fn loaded_state() -> OutboxState<String> {
+        let mut state = OutboxState::new();
+        let skipped = state.load(&journal(&[
+            add(1),
+            add(2),
+            add(3),
+            OutboxOp::Done { id: 1 },
+            dead(2),
+            add(4),
+            dead(4),
+            OutboxOp::Purge { id: 4 },
+            add(5),
+            dead(5),
+            OutboxOp::Replay { id: 5 },
+        ]));
+        assert_eq!(skipped, 0);
+        state
+    }
+
+    #[test]
+    fn load_replays_journal_ops() {
+        let state = loaded_state();
+        assert_eq!(ids(&state.pending), vec![3, 5]);
+        assert_eq!(ids(&state.dead), vec![2]);
+        assert_eq!(state.dead[&2].error, "status 500");
+        assert_eq!(state.next_id, 6);
+    }
+
+    #[test]
+    fn compact_keeps_live_entries_only() {
+        let state = loaded_state();
+        let snapshot = state.snapshot();
+        // pending 3 and 5, then dead letter 2 as add and dead
+        assert_eq!(snapshot.len(), 4);
+        assert!(snapshot.iter().all(|op| matches!(op, OutboxOp::Add { .. } | OutboxOp::Dead { .. })));
+
+        let mut compacted = OutboxState::<String>::new();
+        assert_eq!(compacted.load(&journal(&snapshot)), 0);
+        assert_eq!(ids(&compacted.pending), vec![3, 5]);
+        assert_eq!(ids(&compacted.dead), vec![2]);
+        assert_eq!(compacted.next_id, 6);
+    }
+
+    #[tokio::test]
+    async fn requests_with_call_headers_are_not_recovered() {
+        let dir = std::env::temp_dir().join(format!("hook-outbox-{}", rand::random::<u64>()));
+        let (outbox, _) = HookOutbox::<String>::new(Some(&dir)).await.expect("should create outbox");
+        let mut with_headers = req(0);
+        with_headers.headers.insert("Authorization".to_owned(), "Bearer call-token".to_owned());
+        let mut with_auth = req(0);
+        with_auth.auth = Some(HookAuth::Bearer(HookBearerAuth { token: "call-token".to_owned() }));
+        let mut number = req(0);
+        number.credentials_ref = HookCredentialsRef::Number { number: "100".to_owned() };
+        number.headers.insert("Authorization".to_owned(), "Bearer number-token".to_owned());
+        for req in [req(0), with_headers, with_auth, number] {
+            outbox.add(req).await;
+        }
+        assert_eq!(outbox.state.read().pending.len(), 4);
+
+        let (_, pending) = HookOutbox::<String>::new(Some(&dir)).await.expect("should load outbox");
+        tokio::fs::remove_dir_all(&dir).await.expect("should remove data dir");
+        assert_eq!(pending.iter().map(|req| req.id).collect::<Vec<_>>(), vec![1, 4]);
+        // headers are never written, the number ones are resolved from the address book at each try
+        assert!(pending.iter().all(|req| req.headers.is_empty() && req.auth.is_none()));
+        assert_eq!(pending[1].credentials_ref, HookCredentialsRef::Number { number: "100".to_owned() });
+    }
+
+    #[test]
+    fn load_skips_truncated_last_line() {
+        let mut content = journal(&[add(1), add(2)]);
+        let last = serde_json::to_string(&OutboxOp::<String>::Done { id: 1 }).expect("should convert to json");
+        content.push_str(&last[..last.len() / 2]);
+
+        let mut state = OutboxState::<String>::new();
+        assert_eq!(state.load(&content), 1);
+        assert_eq!(ids(&state.pending), vec![1, 2]);
+    }
+}
@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use atm0s_small_p2p::pubsub_service::PubsubServiceRequester;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::protocol::AppId;
+
+/// Hooks with this scheme are sent to push sockets of the app instead of posted over http, `ws-push://{name}`
+pub const WS_PUSH_SCHEME: &str = "ws-push://";
+/// Rpc method of events which are only acked by the app
+pub const PUSH_METHOD_EVENT: &str = "event";
+/// Rpc method of incoming call notify, which is answered with the call action
+pub const PUSH_METHOD_NOTIFY: &str = "notify";
+/// Timeout of an event until the app acked it, same as http hook requests
+pub const PUSH_EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Push transport over the cluster pubsub, the push socket can be connected to any node
+#[derive(Clone)]
+pub struct HookPush {
+    pubsub: PubsubServiceRequester,
+}
+
+impl HookPush {
+    pub fn new(pubsub: PubsubServiceRequester) -> Self {
+        Self { pubsub }
+    }
+
+    /// Send an event to the push socket of the app and wait for its answer, fails if no socket answered in time
+    pub async fn request<Event: Serialize, Res: DeserializeOwned>(&self, app_id: &AppId, name: &str, method: &str, event: &Event, timeout: Duration) -> anyhow::Result<Res> {
+        self.pubsub
+            .feedback_rpc_as_guest_ob::<_, Res>(app_id.to_hook_push_channel(name), method, event, timeout)
+            .await
+            .map_err(|e| anyhow!("push to {WS_PUSH_SCHEME}{name} error {e:?}"))
+    }
+}
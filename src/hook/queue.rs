@@ -1,79 +1,686 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
+use clap::ValueEnum;
+use opentelemetry::trace::SpanContext;
 use prost::Message;
-use serde::Serialize;
-use tokio::sync::{
-    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-    oneshot,
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use serde::{Deserialize, Serialize};
+use spin::RwLock;
+use tokio::{sync::Notify, time::Instant};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::{
+    address_book::AddressBookStorage,
+    metrics,
+    protocol::{AppId, HookAuth, HookContentType},
 };
 
-use crate::protocol::HookContentType;
+use super::{
+    build_request,
+    outbox::HookOutbox,
+    push::{HookPush, PUSH_EVENT_TIMEOUT, PUSH_METHOD_EVENT, WS_PUSH_SCHEME},
+    signature::sign_headers,
+    HookCredentialsRef,
+};
+
+/// Retry policy of hook delivery, delay of try n is base_delay * 2^(n-1) capped at max_delay with jitter.
+/// A Retry-After header from the server replaces the computed delay, it is also capped at max_delay.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpHookRetry {
+    pub max_retries: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl HttpHookRetry {
+    /// Delay before the next try, full backoff delay is randomized in [delay / 2, delay]
+    fn backoff(&self, tried: usize) -> Duration {
+        let exp = self.base_delay.saturating_mul(1 << tried.saturating_sub(1).min(16) as u32).min(self.max_delay);
+        let half = exp / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+
+    /// Delay before the next try, preferring the delay requested by the server
+    fn delay(&self, tried: usize, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(retry_after) => retry_after.min(self.max_delay),
+            None => self.backoff(tried),
+        }
+    }
+}
+
+/// What to do with a new event when a hook queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HttpHookOverflow {
+    /// Drop the oldest waiting event of the queue
+    DropOldest,
+    /// Drop the new event
+    DropNewest,
+    /// Block the call task until the queue has space or block_timeout passed, then drop the new event
+    Block,
+}
 
-const MAX_RETRY: usize = 5;
+/// Capacity of a hook queue, counted as waiting and in flight events.
+/// Dropped events are moved to dead letters, so they can be replayed later.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpHookLimit {
+    pub capacity: usize,
+    pub overflow: HttpHookOverflow,
+    pub block_timeout: Duration,
+}
 
+#[derive(Debug, Clone, Copy)]
+pub struct HttpHookQueueStats {
+    pub depth: usize,
+    pub in_flight: usize,
+    pub capacity: usize,
+    pub dropped: u64,
+    pub restarts: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct HttpHookRequest<Event> {
+    /// Assigned by the outbox
+    pub id: u64,
+    /// Requests with same key are delivered in order, we use call_id as key
+    pub key: String,
+    /// Used for selecting the push channel of ws-push endpoints
+    #[serde(default)]
+    pub app_id: AppId,
     pub endpoint: String,
+    /// Only kept in memory, headers and auth of numbers are resolved from the address book at each try.
+    /// Requests of outgoing calls with headers or auth are not written to the outbox, see [`Self::is_durable`]
+    #[serde(skip)]
     pub headers: HashMap<String, String>,
+    #[serde(skip)]
+    pub auth: Option<HookAuth>,
+    /// Credentials are resolved from it at each try, so they are never written to the outbox
+    #[serde(default)]
+    pub credentials_ref: HookCredentialsRef,
     pub body: Event,
     pub content_type: HookContentType,
-    pub res_tx: Option<oneshot::Sender<reqwest::Result<reqwest::Response>>>,
+    /// Span of the call which sent it, deliveries are linked to it so the call span isn't kept open by retries.
+    /// Not persisted, so recovered requests are traced alone
+    #[serde(skip)]
+    pub span_context: Option<SpanContext>,
 }
 
-struct HttpHookQueue<Event> {
-    rx: UnboundedReceiver<HttpHookRequest<Event>>,
+impl<Event> HttpHookRequest<Event> {
+    /// Requests of a key are ordered per endpoint, so a slow endpoint doesn't delay others
+    fn order_key(&self) -> String {
+        format!("{}|{}", self.key, self.endpoint)
+    }
+
+    /// Headers and auth of outgoing calls can't be resolved again after a restart, so requests with them are only kept in memory
+    pub fn is_durable(&self) -> bool {
+        self.credentials_ref != HookCredentialsRef::Call || (self.headers.is_empty() && self.auth.is_none())
+    }
 }
 
-impl<Event: Serialize + Message> HttpHookQueue<Event> {
-    async fn send(&mut self, req: &HttpHookRequest<Event>) -> reqwest::Result<reqwest::Response> {
-        let client = reqwest::ClientBuilder::new().timeout(Duration::from_secs(10)).build().expect("should create client");
+#[cfg(test)]
+impl<Event> HttpHookRequest<Event> {
+    /// Request of an outgoing call without headers and auth, before the outbox assigned its id
+    pub fn test(key: &str, body: Event) -> Self {
+        Self {
+            id: 0,
+            key: key.to_owned(),
+            app_id: Default::default(),
+            endpoint: "http://hook".to_owned(),
+            headers: Default::default(),
+            auth: None,
+            credentials_ref: Default::default(),
+            body,
+            content_type: HookContentType::Json,
+            span_context: None,
+        }
+    }
+}
 
-        let (content_type, body) = match req.content_type {
-            HookContentType::Json => ("application/json", serde_json::to_vec(&req.body).expect("should convert to json")),
-            HookContentType::Protobuf => ("application/protobuf", req.body.encode_to_vec()),
-        };
+enum SendError {
+    /// Error which can be retried, with delay requested by the server over Retry-After
+    Retry(String, Option<Duration>),
+    Fatal(String),
+}
+
+/// State is kept outside of the worker task, so a restarted worker continues with it.
+/// Only one request of each key and endpoint pair is in flight, so a pair in backoff doesn't block others.
+/// Waiting requests are indexed by pair, and pairs which can be sent are kept in the ready queue.
+struct QueueState<Event> {
+    /// Waiting requests of each pair with their push sequence
+    waiting: HashMap<String, VecDeque<(u64, HttpHookRequest<Event>)>>,
+    /// Pair of each waiting request by push sequence, for dropping the oldest one
+    order: BTreeMap<u64, String>,
+    /// Pairs with waiting requests which may not be in flight, entries are checked when taken
+    ready: VecDeque<String>,
+    in_flight: HashSet<String>,
+    next_seq: u64,
+}
 
-        let mut builder = client.post(&req.endpoint).body(body).header("Content-Type", content_type);
-        for (k, v) in &req.headers {
-            builder = builder.header(k, v);
+impl<Event> QueueState<Event> {
+    fn new() -> Self {
+        Self {
+            waiting: HashMap::new(),
+            order: BTreeMap::new(),
+            ready: VecDeque::new(),
+            in_flight: HashSet::new(),
+            next_seq: 0,
         }
-        builder.send().await?.error_for_status()
     }
 
-    pub async fn run(&mut self) {
-        let mut req = self.rx.recv().await.expect("should receive");
-        let mut count = 0;
-        while count < MAX_RETRY {
-            count += 1;
-            log::info!("[HttpHookQueue] sending hook to {}, retry {count}/{MAX_RETRY}", req.endpoint);
-            let res = self.send(&req).await;
-            match &res {
-                Ok(_res) => {
-                    log::info!("[HttpHookQueue] sent hook to {} in try {count}/{MAX_RETRY}", req.endpoint);
-                }
-                Err(e) => {
-                    log::error!("[HttpHookQueue] send hook to {} error {e:?} in try {count}/{MAX_RETRY}", req.endpoint);
-                    if count < MAX_RETRY {
-                        log::error!("[HttpHookQueue] connect error => retry in try {count}/{MAX_RETRY}");
-                        continue;
+    fn waiting_len(&self) -> usize {
+        self.order.len()
+    }
+
+    fn depth(&self) -> usize {
+        self.waiting_len() + self.in_flight.len()
+    }
+
+    fn push_back(&mut self, req: HttpHookRequest<Event>) {
+        let key = req.order_key();
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.order.insert(seq, key.clone());
+        let queue = self.waiting.entry(key.clone()).or_default();
+        queue.push_back((seq, req));
+        if queue.len() == 1 && !self.in_flight.contains(&key) {
+            self.ready.push_back(key);
+        }
+    }
+
+    /// Remove the oldest waiting request of all pairs
+    fn pop_oldest(&mut self) -> Option<HttpHookRequest<Event>> {
+        let (_, key) = self.order.pop_first()?;
+        let (req, _) = self.pop_waiting(&key)?;
+        Some(req)
+    }
+
+    /// Take the first waiting request whose key and endpoint pair isn't in flight
+    fn next_ready(&mut self) -> Option<HttpHookRequest<Event>> {
+        while let Some(key) = self.ready.pop_front() {
+            if self.in_flight.contains(&key) {
+                continue;
+            }
+            let Some((req, seq)) = self.pop_waiting(&key) else {
+                continue;
+            };
+            self.order.remove(&seq);
+            self.in_flight.insert(key);
+            return Some(req);
+        }
+        None
+    }
+
+    /// Mark the pair as not in flight, its next waiting request becomes ready
+    fn finish(&mut self, key: &str) {
+        self.in_flight.remove(key);
+        if self.waiting.contains_key(key) {
+            self.ready.push_back(key.to_owned());
+        }
+    }
+
+    fn pop_waiting(&mut self, key: &str) -> Option<(HttpHookRequest<Event>, u64)> {
+        let queue = self.waiting.get_mut(key)?;
+        let (seq, req) = queue.pop_front()?;
+        if queue.is_empty() {
+            self.waiting.remove(key);
+        }
+        Some((req, seq))
+    }
+}
+
+struct QueueShared<Event> {
+    /// index of the queue, used as metrics label
+    label: String,
+    state: RwLock<QueueState<Event>>,
+    limit: HttpHookLimit,
+    /// wakes the worker when a request is added or a delivery is finished
+    worker_notify: Notify,
+    /// wakes senders which are blocked by a full queue
+    space_notify: Notify,
+    dropped: AtomicU64,
+    restarts: AtomicU64,
+    outbox: HookOutbox<Event>,
+    address_book: AddressBookStorage,
+}
+
+/// A bounded queue which delivers hooks of many keys, the worker is restarted if it panics
+pub struct HttpHookQueue<Event> {
+    shared: Arc<QueueShared<Event>>,
+}
+
+impl<Event> Clone for HttpHookQueue<Event> {
+    fn clone(&self) -> Self {
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<Event: Serialize + Message + Send + Sync + 'static> HttpHookQueue<Event> {
+    pub fn new(index: usize, retry: HttpHookRetry, limit: HttpHookLimit, outbox: HookOutbox<Event>, push: HookPush, address_book: AddressBookStorage) -> Self {
+        let shared = Arc::new(QueueShared {
+            label: index.to_string(),
+            state: RwLock::new(QueueState::new()),
+            limit,
+            worker_notify: Notify::new(),
+            space_notify: Notify::new(),
+            dropped: AtomicU64::new(0),
+            restarts: AtomicU64::new(0),
+            outbox,
+            address_book,
+        });
+        let client = reqwest::ClientBuilder::new().timeout(Duration::from_secs(10)).build().expect("should create client");
+        let worker_shared = shared.clone();
+        tokio::spawn(async move {
+            loop {
+                let worker = tokio::spawn(run_worker(worker_shared.clone(), client.clone(), push.clone(), retry));
+                match worker.await {
+                    Err(e) if e.is_panic() => {
+                        log::error!("[HttpHookQueue] worker panicked {e:?} => restart");
+                        worker_shared.restarts.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
                     }
+                    _ => break,
                 }
             }
-            if let Some(tx) = req.res_tx.take() {
-                let _ = tx.send(res);
+        });
+        Self { shared }
+    }
+
+    /// Add a request without waiting, Block policy drops the new request if the queue is full
+    pub fn push(&self, req: HttpHookRequest<Event>) {
+        if let Err(req) = self.try_push(req) {
+            self.drop_request(req);
+        }
+    }
+
+    /// Add a request, Block policy waits for space up to block_timeout before dropping it
+    pub async fn push_wait(&self, mut req: HttpHookRequest<Event>) {
+        if self.shared.limit.overflow == HttpHookOverflow::Block {
+            let deadline = Instant::now() + self.shared.limit.block_timeout;
+            loop {
+                // enabled before checking, so a space notification between check and wait isn't lost
+                let notified = self.shared.space_notify.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                req = match self.try_push(req) {
+                    Ok(()) => return,
+                    Err(req) => req,
+                };
+                if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                    break;
+                }
             }
-            break;
         }
+        self.push(req);
+    }
+
+    pub fn stats(&self) -> HttpHookQueueStats {
+        let state = self.shared.state.read();
+        HttpHookQueueStats {
+            depth: state.depth(),
+            in_flight: state.in_flight.len(),
+            capacity: self.shared.limit.capacity,
+            dropped: self.shared.dropped.load(Ordering::Relaxed),
+            restarts: self.shared.restarts.load(Ordering::Relaxed),
+        }
+    }
+
+    fn try_push(&self, req: HttpHookRequest<Event>) -> Result<(), HttpHookRequest<Event>> {
+        let mut state = self.shared.state.write();
+        if state.depth() < self.shared.limit.capacity {
+            state.push_back(req);
+            drop(state);
+        } else if self.shared.limit.overflow == HttpHookOverflow::DropOldest && state.waiting_len() > 0 {
+            let oldest = state.pop_oldest().expect("should have waiting request");
+            state.push_back(req);
+            drop(state);
+            self.drop_request(oldest);
+        } else {
+            return Err(req);
+        }
+        self.shared.worker_notify.notify_one();
+        Ok(())
+    }
+
+    fn drop_request(&self, req: HttpHookRequest<Event>) {
+        log::warn!("[HttpHookQueue] queue full, drop hook {} to {} => dead letter", req.key, req.endpoint);
+        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        metrics::HOOK_DROPPED.with_label_values(&[&self.shared.label]).inc();
+        self.shared.outbox.dead(req.id, "queue overflow".to_owned());
     }
 }
 
-pub fn new_queue<Event: Serialize + Message + Send + Sync + 'static>() -> UnboundedSender<HttpHookRequest<Event>> {
-    let (tx, rx) = unbounded_channel();
-    let mut queue = HttpHookQueue { rx };
-    tokio::spawn(async move {
+async fn run_worker<Event: Serialize + Message + Send + Sync + 'static>(shared: Arc<QueueShared<Event>>, client: reqwest::Client, push: HookPush, retry: HttpHookRetry) {
+    loop {
         loop {
-            queue.run().await;
+            let Some(req) = shared.state.write().next_ready() else {
+                break;
+            };
+            spawn_deliver(shared.clone(), client.clone(), push.clone(), retry, req);
         }
+        shared.worker_notify.notified().await;
+    }
+}
+
+fn spawn_deliver<Event: Serialize + Message + Send + Sync + 'static>(shared: Arc<QueueShared<Event>>, client: reqwest::Client, push: HookPush, retry: HttpHookRetry, req: HttpHookRequest<Event>) {
+    tokio::spawn(async move {
+        let id = req.id;
+        let order_key = req.order_key();
+        let label = shared.label.clone();
+        let address_book = shared.address_book.clone();
+        let span = deliver_span(&req, &label);
+        // delivery runs in its own task, so a panic there doesn't keep the key in flight forever
+        let res = tokio::spawn(async move { deliver(&client, &push, &address_book, retry, &req, &label).await }.instrument(span))
+            .await
+            .unwrap_or_else(|e| Err(format!("delivery panicked {e}")));
+        match res {
+            Ok(()) => shared.outbox.done(id),
+            Err(e) => {
+                metrics::HOOK_FAILURES.with_label_values(&[&shared.label]).inc();
+                shared.outbox.dead(id, e);
+            }
+        }
+        shared.state.write().finish(&order_key);
+        shared.worker_notify.notify_one();
+        shared.space_notify.notify_waiters();
     });
+}
+
+/// Root span of a delivery with a link to the call span
+fn deliver_span<Event>(req: &HttpHookRequest<Event>, queue: &str) -> tracing::Span {
+    let span = tracing::info_span!(parent: None, "hook_deliver", call_id = %req.key, app_id = %req.app_id, endpoint = %req.endpoint, queue = %queue);
+    if let Some(span_context) = &req.span_context {
+        span.add_link(span_context.clone());
+    }
+    span
+}
+
+/// Error is returned after all tries failed or the endpoint rejected the event
+async fn deliver<Event: Serialize + Message>(
+    client: &reqwest::Client,
+    push: &HookPush,
+    address_book: &AddressBookStorage,
+    retry: HttpHookRetry,
+    req: &HttpHookRequest<Event>,
+    queue: &str,
+) -> Result<(), String> {
+    let (content_type, body) = match req.content_type {
+        HookContentType::Json => ("application/json", serde_json::to_vec(&req.body).expect("should convert to json")),
+        HookContentType::Protobuf => ("application/protobuf", req.body.encode_to_vec()),
+    };
+    let max_tries = retry.max_retries + 1;
+
+    let mut tried = 0;
+    loop {
+        tried += 1;
+        log::info!("[HttpHookQueue] sending hook {} to {}, try {tried}/{max_tries}", req.key, req.endpoint);
+        let started = Instant::now();
+        let res = send(client, push, address_book, req, content_type, body.clone()).await;
+        metrics::HOOK_REQUEST_SECONDS.with_label_values(&[queue]).observe(started.elapsed().as_secs_f64());
+        match res {
+            Ok(()) => {
+                log::info!("[HttpHookQueue] sent hook {} to {} in try {tried}/{max_tries}", req.key, req.endpoint);
+                return Ok(());
+            }
+            Err(SendError::Fatal(e)) => {
+                log::error!("[HttpHookQueue] send hook {} to {} error {e} in try {tried}/{max_tries} => dead letter", req.key, req.endpoint);
+                return Err(e);
+            }
+            Err(SendError::Retry(e, _)) if tried >= max_tries => {
+                log::error!("[HttpHookQueue] send hook {} to {} error {e}, failed after {max_tries} tries => dead letter", req.key, req.endpoint);
+                return Err(e);
+            }
+            Err(SendError::Retry(e, retry_after)) => {
+                let delay = retry.delay(tried, retry_after);
+                log::warn!("[HttpHookQueue] send hook {} to {} error {e} in try {tried}/{max_tries} => retry in {delay:?}", req.key, req.endpoint);
+                metrics::HOOK_RETRIES.with_label_values(&[queue]).inc();
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+async fn send<Event: Serialize>(
+    client: &reqwest::Client,
+    push: &HookPush,
+    address_book: &AddressBookStorage,
+    req: &HttpHookRequest<Event>,
+    content_type: &str,
+    body: Vec<u8>,
+) -> Result<(), SendError> {
+    // push sockets are authenticated by the app secret, so headers and signatures are not used
+    if let Some(name) = req.endpoint.strip_prefix(WS_PUSH_SCHEME) {
+        return push
+            .request::<_, ()>(&req.app_id, name, PUSH_METHOD_EVENT, &req.body, PUSH_EVENT_TIMEOUT)
+            .await
+            .map_err(|e| SendError::Retry(e.to_string(), None));
+    }
+    // signed at each try, so receivers can reject stale timestamps and rotated secrets are used
+    let secrets = address_book.hook_secrets(&req.app_id, &req.credentials_ref);
+    let signature = sign_headers(&secrets, &body);
+    let (headers, auth) = address_book
+        .hook_headers(&req.app_id, &req.credentials_ref, &req.endpoint)
+        .unwrap_or_else(|| (req.headers.clone(), req.auth.clone()));
+    let mut builder = build_request(client.post(&req.endpoint).body(body).header("Content-Type", content_type), &headers, &auth);
+    for (k, v) in signature {
+        builder = builder.header(k, v);
+    }
+    let res = builder.send().await.map_err(|e| SendError::Retry(e.to_string(), None))?;
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    // only timeout, rate limit and server errors are worth retrying
+    if status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        let retry_after = res.headers().get(RETRY_AFTER).and_then(|v| v.to_str().ok()).and_then(|v| parse_retry_after(v, SystemTime::now()));
+        Err(SendError::Retry(format!("status {status}"), retry_after))
+    } else {
+        Err(SendError::Fatal(format!("status {status}")))
+    }
+}
+
+/// Retry-After value in seconds or as HTTP-date, a date in the past means no delay
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(at.duration_since(now).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::BoxFuture;
+    use opentelemetry::trace::{SpanId, TracerProvider as _};
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::telemetry::current_span_context;
+
+    const RETRY: HttpHookRetry = HttpHookRetry {
+        max_retries: 5,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+    };
+
+    #[test]
+    fn backoff_doubles_until_max_delay() {
+        for (tried, max) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (30, 1000)] {
+            let delay = RETRY.backoff(tried);
+            let max = Duration::from_millis(max);
+            assert!(delay >= max / 2 && delay <= max, "try {tried} delay {delay:?}");
+        }
+    }
+
+    #[test]
+    fn retry_after_is_capped_at_max_delay() {
+        assert_eq!(RETRY.delay(1, Some(Duration::from_secs(86400))), RETRY.max_delay);
+        assert_eq!(RETRY.delay(1, Some(Duration::from_millis(300))), Duration::from_millis(300));
+        assert!(RETRY.delay(1, None) <= RETRY.base_delay);
+    }
+
+    /// Queue without a worker, so pushed requests stay waiting
+    async fn queue(capacity: usize, overflow: HttpHookOverflow) -> HttpHookQueue<String> {
+        let (outbox, _) = HookOutbox::new(None).await.expect("should create outbox");
+        HttpHookQueue {
+            shared: Arc::new(QueueShared {
+                label: "0".to_owned(),
+                state: RwLock::new(QueueState::new()),
+                limit: HttpHookLimit {
+                    capacity,
+                    overflow,
+                    block_timeout: Duration::from_millis(100),
+                },
+                worker_notify: Notify::new(),
+                space_notify: Notify::new(),
+                dropped: AtomicU64::new(0),
+                restarts: AtomicU64::new(0),
+                outbox,
+                address_book: AddressBookStorage::new("root"),
+            }),
+        }
+    }
+
+    async fn req(queue: &HttpHookQueue<String>, key: &str) -> HttpHookRequest<String> {
+        queue.shared.outbox.add(HttpHookRequest::test(key, key.to_owned())).await
+    }
+
+    fn waiting_bodies(queue: &HttpHookQueue<String>) -> Vec<String> {
+        let state = queue.shared.state.read();
+        state.order.values().map(|key| key.split('|').next().unwrap_or_default().to_owned()).collect()
+    }
+
+    fn dead_bodies(queue: &HttpHookQueue<String>) -> Vec<String> {
+        queue.shared.outbox.dead_letters().into_iter().map(|dead| dead.req.body).collect()
+    }
+
+    #[test]
+    fn next_ready_keeps_one_request_per_pair_in_flight() {
+        let mut state = QueueState::new();
+        let req = |key: &str, body: &str| HttpHookRequest::test(key, body.to_owned());
+        state.push_back(req("a", "a1"));
+        state.push_back(req("a", "a2"));
+        state.push_back(req("b", "b1"));
+
+        let a1 = state.next_ready().expect("should have a1");
+        assert_eq!(a1.body, "a1");
+        assert_eq!(state.next_ready().map(|req| req.body).as_deref(), Some("b1"));
+        assert!(state.next_ready().is_none());
+        assert_eq!(state.depth(), 3);
+
+        state.finish(&a1.order_key());
+        assert_eq!(state.next_ready().map(|req| req.body).as_deref(), Some("a2"));
+        assert!(state.next_ready().is_none());
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_moves_oldest_to_dead_letters() {
+        let queue = queue(2, HttpHookOverflow::DropOldest).await;
+        for key in ["a", "b", "c"] {
+            queue.push(req(&queue, key).await);
+        }
+        assert_eq!(waiting_bodies(&queue), vec!["b", "c"]);
+        assert_eq!(dead_bodies(&queue), vec!["a"]);
+        assert_eq!(queue.stats().dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_moves_new_to_dead_letters() {
+        let queue = queue(2, HttpHookOverflow::DropNewest).await;
+        for key in ["a", "b", "c"] {
+            queue.push_wait(req(&queue, key).await).await;
+        }
+        assert_eq!(waiting_bodies(&queue), vec!["a", "b"]);
+        assert_eq!(dead_bodies(&queue), vec!["c"]);
+        assert_eq!(queue.stats().dropped, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn block_waits_for_space_until_timeout() {
+        let queue = queue(1, HttpHookOverflow::Block).await;
+        queue.push_wait(req(&queue, "a").await).await;
+
+        // no space within block_timeout => the new request is dropped
+        let started = Instant::now();
+        queue.push_wait(req(&queue, "b").await).await;
+        assert!(started.elapsed() >= queue.shared.limit.block_timeout);
+        assert_eq!(dead_bodies(&queue), vec!["b"]);
+
+        // space freed while blocked => the new request is queued
+        let c = req(&queue, "c").await;
+        let blocked = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push_wait(c).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!blocked.is_finished());
+        let a = queue.shared.state.write().next_ready().expect("should have a");
+        queue.shared.state.write().finish(&a.order_key());
+        queue.shared.space_notify.notify_waiters();
+        blocked.await.expect("should push");
+        assert_eq!(waiting_bodies(&queue), vec!["c"]);
+        assert_eq!(queue.stats().dropped, 1);
+    }
 
-    tx
+    /// Stand-in collector which keeps exported spans
+    #[derive(Debug, Clone, Default)]
+    struct Collector(Arc<std::sync::Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Collector {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().expect("should lock spans").extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    fn deliver_span_links_to_ended_call_span() {
+        let collector = Collector::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().with_simple_exporter(collector.clone()).build();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let call = tracing::info_span!("call");
+            let span_context = call.in_scope(current_span_context);
+            assert!(span_context.is_some());
+            let req = HttpHookRequest {
+                id: 1,
+                span_context,
+                ..HttpHookRequest::test("call1", "event".to_owned())
+            };
+            // the queued request doesn't keep the call span open
+            drop(call);
+            assert_eq!(collector.0.lock().expect("should lock spans").len(), 1);
+            drop(deliver_span(&req, "0"));
+        });
+
+        let spans = collector.0.lock().expect("should lock spans");
+        let (call, deliver) = (&spans[0], &spans[1]);
+        assert_eq!(call.name, "call");
+        assert_eq!(deliver.name, "hook_deliver");
+        assert_eq!(deliver.parent_span_id, SpanId::INVALID);
+        assert_eq!(deliver.links.links.len(), 1);
+        assert_eq!(deliver.links.links[0].span_context, call.span_context);
+    }
+
+    #[test]
+    fn parse_retry_after_values() {
+        let now = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").expect("should parse date");
+        assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:50:37 GMT", now), Some(Duration::from_secs(60)));
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("-1", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("", now), None);
+    }
 }
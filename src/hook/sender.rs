@@ -1,46 +1,93 @@
-use std::{collections::HashMap, marker::PhantomData, time::Duration};
+use std::{marker::PhantomData, time::Duration};
 
 use prost::Message;
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::sync::mpsc::UnboundedSender;
 
-use crate::protocol::HookContentType;
+use crate::{
+    protocol::{AppId, HookContentType, HookEventKind},
+    telemetry::current_span_context,
+};
 
-use super::queue::HttpHookRequest;
+use super::{
+    build_request,
+    outbox::HookOutbox,
+    push::{HookPush, PUSH_METHOD_NOTIFY, WS_PUSH_SCHEME},
+    queue::{HttpHookQueue, HttpHookRequest},
+    signature::sign_headers,
+    HookEvent, HttpHookTarget,
+};
 
 pub struct HttpHookSender<Event> {
-    pub endpoint: String,
-    pub headers: HashMap<String, String>,
-    pub tx: UnboundedSender<HttpHookRequest<Event>>,
+    pub key: String,
+    pub app_id: AppId,
+    /// The hook which decides actions of incoming calls, it also receives all events
+    pub primary: Option<HttpHookTarget>,
+    pub subscriptions: Vec<HttpHookTarget>,
+    pub queue: HttpHookQueue<Event>,
+    pub outbox: HookOutbox<Event>,
+    pub push: HookPush,
     pub _tmp: PhantomData<Event>,
 }
 
-impl<Event: Serialize + Message> HttpHookSender<Event> {
-    pub fn send(&self, content_type: HookContentType, body: Event) {
-        self.tx
-            .send(HttpHookRequest {
-                endpoint: self.endpoint.clone(),
-                headers: self.headers.clone(),
-                body,
-                content_type,
-                res_tx: None,
-            })
-            .expect("should send to queue worker");
-    }
-
-    pub async fn request<Res: DeserializeOwned + Message + Default>(&self, content_type: HookContentType, body: &Event) -> anyhow::Result<Res> {
-        let client = reqwest::ClientBuilder::new().timeout(Duration::from_secs(5)).build().expect("should create client");
-        let (content_type_str, body) = match content_type {
+impl<Event: Serialize + DeserializeOwned + Message + HookEvent + Clone + Send + Sync + 'static> HttpHookSender<Event> {
+    pub fn has_endpoint(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    /// Events are fanned out to all targets which accept the event kind, each copy is synced to the outbox before queued.
+    /// With Block overflow policy this waits while the queue is full.
+    pub async fn send(&self, body: Event) {
+        self.queue_targets(self.primary.iter().chain(self.subscriptions(&body)), &body).await;
+    }
+
+    /// Subscriptions which accept the event kind
+    fn subscriptions<'a>(&'a self, body: &Event) -> impl Iterator<Item = &'a HttpHookTarget> + 'a {
+        let kind = body.kind();
+        self.subscriptions.iter().filter(move |target| accepts(target, kind))
+    }
+
+    async fn queue_targets<'a>(&self, targets: impl Iterator<Item = &'a HttpHookTarget>, body: &Event) {
+        for target in targets {
+            let req = self
+                .outbox
+                .add(HttpHookRequest {
+                    id: 0,
+                    key: self.key.clone(),
+                    app_id: self.app_id.clone(),
+                    endpoint: target.endpoint.clone(),
+                    headers: target.headers.clone(),
+                    auth: target.auth.clone(),
+                    credentials_ref: target.credentials_ref.clone(),
+                    body: body.clone(),
+                    content_type: target.content_type,
+                    span_context: current_span_context(),
+                })
+                .await;
+            self.queue.push_wait(req).await;
+        }
+    }
+
+    /// Request the primary hook and wait for its response, subscriptions which accept the event get queued copies
+    #[tracing::instrument(name = "hook_request", skip_all, fields(call_id = %self.key))]
+    pub async fn request<Res: DeserializeOwned + Message + Default>(&self, body: &Event, timeout: Duration) -> anyhow::Result<Res> {
+        let target = self.primary.as_ref().ok_or(anyhow::anyhow!("missing hook endpoint"))?;
+        self.queue_targets(self.subscriptions(body), body).await;
+        if let Some(name) = target.endpoint.strip_prefix(WS_PUSH_SCHEME) {
+            return self.push.request(&self.app_id, name, PUSH_METHOD_NOTIFY, body, timeout).await;
+        }
+        let client = reqwest::ClientBuilder::new().timeout(timeout).build().expect("should create client");
+        let (content_type_str, body) = match target.content_type {
             HookContentType::Json => ("application/json", serde_json::to_vec(&body).expect("should convert to json")),
             HookContentType::Protobuf => ("application/protobuf", body.encode_to_vec()),
         };
 
-        let mut builder = client.post(&self.endpoint).body(body).header("Content-Type", content_type_str);
-        for (k, v) in &self.headers {
+        let signature = sign_headers(&target.secrets, &body);
+        let mut builder = build_request(client.post(&target.endpoint).body(body).header("Content-Type", content_type_str), &target.headers, &target.auth);
+        for (k, v) in signature {
             builder = builder.header(k, v);
         }
         let res = builder.send().await?.error_for_status()?;
-        match content_type {
+        match target.content_type {
             HookContentType::Json => Ok(res.json::<Res>().await?),
             HookContentType::Protobuf => {
                 let binary = res.bytes().await?;
@@ -49,3 +96,58 @@ impl<Event: Serialize + Message> HttpHookSender<Event> {
         }
     }
 }
+
+/// Targets without an events filter accept all kinds, events without a kind only go to them
+fn accepts(target: &HttpHookTarget, kind: Option<HookEventKind>) -> bool {
+    target.events.is_empty() || kind.is_some_and(|kind| target.events.contains(&kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hook::HookCredentialsRef,
+        protocol::protobuf::sip_gateway::{call_event, CallDetailRecord, CallEvent, IncomingCallNotify},
+    };
+
+    fn target(events: Vec<HookEventKind>) -> HttpHookTarget {
+        HttpHookTarget {
+            endpoint: "http://sub".to_owned(),
+            content_type: HookContentType::Json,
+            headers: Default::default(),
+            auth: None,
+            secrets: vec![],
+            credentials_ref: HookCredentialsRef::Call,
+            events,
+        }
+    }
+
+    fn event(event: Option<call_event::Event>) -> CallEvent {
+        CallEvent {
+            call_id: "call1".to_owned(),
+            timestamp: 0,
+            seq: 0,
+            event,
+        }
+    }
+
+    #[test]
+    fn subscriptions_filter_by_event_kind() {
+        let notify = event(Some(call_event::Event::Notify(IncomingCallNotify::default())));
+        let cdr = event(Some(call_event::Event::Cdr(CallDetailRecord::default())));
+        let unknown = event(None);
+        assert_eq!(notify.kind(), Some(HookEventKind::Notify));
+        assert_eq!(cdr.kind(), Some(HookEventKind::Cdr));
+        assert_eq!(unknown.kind(), None);
+
+        let all = target(vec![]);
+        assert!(accepts(&all, notify.kind()));
+        assert!(accepts(&all, cdr.kind()));
+        assert!(accepts(&all, unknown.kind()));
+
+        let only_notify = target(vec![HookEventKind::Notify]);
+        assert!(accepts(&only_notify, notify.kind()));
+        assert!(!accepts(&only_notify, cdr.kind()));
+        assert!(!accepts(&only_notify, unknown.kind()));
+    }
+}
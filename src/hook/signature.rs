@@ -0,0 +1,61 @@
+use atm0s_small_p2p::now_ms;
+
+use crate::protocol::HookSecret;
+
+pub const TIMESTAMP_HEADER: &str = "X-Hook-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Hook-Signature";
+
+/// Signature headers of a hook request, each active secret adds a `v1=<hex>` entry to the signature header.
+/// The signed payload is `{timestamp}.{body}` with timestamp in seconds, empty if there is no active secret.
+pub fn sign_headers(secrets: &[HookSecret], body: &[u8]) -> Vec<(&'static str, String)> {
+    let now = now_ms();
+    let active = secrets.iter().filter(|s| s.expires_at.is_none_or(|expires_at| expires_at > now)).collect::<Vec<_>>();
+    if active.is_empty() {
+        return vec![];
+    }
+
+    let timestamp = (now / 1000).to_string();
+    let mut payload = Vec::with_capacity(timestamp.len() + 1 + body.len());
+    payload.extend_from_slice(timestamp.as_bytes());
+    payload.push(b'.');
+    payload.extend_from_slice(body);
+
+    let signatures = active
+        .iter()
+        .map(|s| format!("v1={}", to_hex(&hmac_sha256::HMAC::mac(&payload, s.secret.as_bytes()))))
+        .collect::<Vec<_>>();
+    vec![(TIMESTAMP_HEADER, timestamp), (SIGNATURE_HEADER, signatures.join(","))]
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::HookSecret;
+
+    use super::{sign_headers, SIGNATURE_HEADER};
+
+    #[test]
+    fn sign_with_active_secrets_only() {
+        let secrets = vec![
+            HookSecret {
+                secret: "new".to_owned(),
+                expires_at: None,
+            },
+            HookSecret {
+                secret: "old".to_owned(),
+                expires_at: Some(u64::MAX),
+            },
+            HookSecret {
+                secret: "expired".to_owned(),
+                expires_at: Some(1),
+            },
+        ];
+        let headers = sign_headers(&secrets, b"{}");
+        let signature = headers.iter().find(|(k, _)| *k == SIGNATURE_HEADER).map(|(_, v)| v.clone()).expect("should have signature");
+        assert_eq!(signature.split(',').count(), 2);
+        assert!(sign_headers(&secrets[2..], b"{}").is_empty());
+    }
+}
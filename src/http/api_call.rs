@@ -2,43 +2,55 @@ use std::{sync::Arc, time::Duration};
 
 use atm0s_small_p2p::pubsub_service::PubsubServiceRequester;
 use poem::web::Query;
-use poem_openapi::{param::Path, payload::Json, OpenApi};
+use poem_openapi::{
+    param::Path,
+    payload::{Json, PlainText},
+    OpenApi,
+};
 use tokio::sync::{mpsc::Sender, oneshot};
 
 use crate::{
+    cluster::ClusterCallRpc,
     protocol::{
         protobuf::sip_gateway::{
             incoming_call_data::{incoming_call_request, incoming_call_response},
             outgoing_call_data::{outgoing_call_request, outgoing_call_response},
         },
-        CallApiError, CreateCallRequest, CreateCallResponse, IncomingCallActionRequest, IncomingCallActionResponse, OutgoingCallActionRequest, OutgoingCallActionResponse,
+        AppId, CallApiError, CallInfo, CallSipTrace, CreateCallRequest, CreateCallResponse, CreateNotifyTokenRequest, CreateNotifyTokenResponse, IncomingCallActionRequest, IncomingCallActionResponse,
+        OutgoingCallActionRequest, OutgoingCallActionResponse, SipTraceDirection,
     },
-    secure::SecureContext,
+    secure::{NotifyToken, SecureContext},
     sip::MediaApi,
 };
 
-use super::{header_secret::TokenAuthorization, response_result::ApiRes, HttpCommand};
+use super::{
+    header_secret::TokenAuthorization,
+    response_result::{ApiRes, ApiResError},
+    HttpCommand,
+};
 
 const RPC_TIMEOUT_SECONDS: u64 = 2;
+const NOTIFY_TOKEN_TTL_SECONDS: u64 = 86400;
 
 pub struct CallApis {
     pub media_gateway: String,
     pub secure_ctx: Arc<SecureContext>,
     pub tx: Sender<HttpCommand>,
     pub call_pubsub: PubsubServiceRequester,
+    pub call_rpc: ClusterCallRpc,
 }
 
 #[OpenApi]
 impl CallApis {
     #[oai(path = "/outgoing", method = "post")]
     async fn create_call(&self, secret: TokenAuthorization, data: Json<CreateCallRequest>) -> ApiRes<CreateCallResponse, CallApiError> {
-        let _app_id: crate::protocol::AppId = self.secure_ctx.check_secret(&secret.0.token).ok_or::<CallApiError>(CallApiError::WrongSecret)?;
+        let app_id: AppId = self.secure_ctx.check_secret(&secret.0.token).ok_or::<CallApiError>(CallApiError::WrongSecret)?;
         log::info!("create_call: from {:?} to {:?} streaming: {:?}", data.from_number, data.to_number, data.streaming);
         let media_api = MediaApi::new(&self.media_gateway, &secret.0.token);
 
         let (tx, rx) = oneshot::channel();
         self.tx
-            .send(HttpCommand::CreateCall(data.0, media_api, tx))
+            .send(HttpCommand::CreateCall(app_id, data.0, media_api, tx))
             .await
             .map_err(|e| CallApiError::InternalChannel(e.to_string()))?;
 
@@ -46,6 +58,62 @@ impl CallApis {
         Ok(res.into())
     }
 
+    /// Create token for a notify session which receives incoming calls of the app
+    #[oai(path = "/notify_token", method = "post")]
+    async fn create_notify_token(&self, secret: TokenAuthorization, data: Json<CreateNotifyTokenRequest>) -> ApiRes<CreateNotifyTokenResponse, CallApiError> {
+        let app_id: AppId = self.secure_ctx.check_secret(&secret.0.token).ok_or::<CallApiError>(CallApiError::WrongSecret)?;
+        let token = self.secure_ctx.encode_notify_token(
+            NotifyToken {
+                app_id,
+                session_id: data.0.session_id,
+            },
+            data.0.ttl_secs.unwrap_or(NOTIFY_TOKEN_TTL_SECONDS),
+        );
+        Ok(CreateNotifyTokenResponse {
+            notify_ws: format!("/call/notify?token={token}"),
+            token,
+        }
+        .into())
+    }
+
+    #[oai(path = "/", method = "get")]
+    async fn list_calls(&self, secret: TokenAuthorization) -> ApiRes<Vec<CallInfo>, CallApiError> {
+        let app_id = self.secure_ctx.check_secret_scope(&secret.0.token).ok_or::<CallApiError>(CallApiError::WrongSecret)?;
+        let res = self.call_rpc.list_calls(app_id).await?;
+        Ok(res.into())
+    }
+
+    #[oai(path = "/:call_id", method = "get")]
+    async fn get_call(&self, secret: TokenAuthorization, Path(call_id): Path<String>) -> ApiRes<CallInfo, CallApiError> {
+        let app_id = self.secure_ctx.check_secret_scope(&secret.0.token).ok_or::<CallApiError>(CallApiError::WrongSecret)?;
+        let res = self.call_rpc.get_call(call_id.into(), app_id).await?.ok_or(CallApiError::CallNotFound)?;
+        Ok(res.into())
+    }
+
+    /// End a call of any direction, the call must belong to the secret's app
+    #[oai(path = "/:call_id", method = "delete")]
+    async fn end_call(&self, secret: TokenAuthorization, Path(call_id): Path<String>) -> ApiRes<String, CallApiError> {
+        let app_id = self.secure_ctx.check_secret_scope(&secret.0.token).ok_or::<CallApiError>(CallApiError::WrongSecret)?;
+        self.call_rpc.hangup(call_id.into(), app_id).await?;
+        Ok("OK".to_owned().into())
+    }
+
+    /// SIP messages of a call, available while the call is active and for the retention time after it ended
+    #[oai(path = "/:call_id/sip-trace", method = "get")]
+    async fn sip_trace(&self, secret: TokenAuthorization, Path(call_id): Path<String>) -> ApiRes<CallSipTrace, CallApiError> {
+        let app_id = self.secure_ctx.check_secret_scope(&secret.0.token).ok_or::<CallApiError>(CallApiError::WrongSecret)?;
+        let res = self.call_rpc.sip_trace(call_id.into(), app_id).await?.ok_or(CallApiError::CallNotFound)?;
+        Ok(res.into())
+    }
+
+    /// Same as sip-trace but as plain text ladder, for reading or attaching to carrier tickets
+    #[oai(path = "/:call_id/sip-trace/text", method = "get")]
+    async fn sip_trace_text(&self, secret: TokenAuthorization, Path(call_id): Path<String>) -> Result<PlainText<String>, ApiResError<CallApiError>> {
+        let app_id = self.secure_ctx.check_secret_scope(&secret.0.token).ok_or::<CallApiError>(CallApiError::WrongSecret)?;
+        let res = self.call_rpc.sip_trace(call_id.into(), app_id).await?.ok_or(CallApiError::CallNotFound)?;
+        Ok(PlainText(sip_trace_text(&res)))
+    }
+
     #[oai(path = "/outgoing/:call_id/action", method = "post")]
     async fn action_outcall(&self, Path(call_id): Path<String>, Query(token): Query<String>, data: Json<OutgoingCallActionRequest>) -> ApiRes<OutgoingCallActionResponse, CallApiError> {
         let token = if let Some(token) = self.secure_ctx.decode_call_token(&token) {
@@ -134,3 +202,19 @@ impl CallApis {
         Ok("OK".to_owned().into())
     }
 }
+
+fn sip_trace_text(trace: &CallSipTrace) -> String {
+    let mut out = format!("call {}\n", trace.call_id);
+    if trace.dropped > 0 {
+        out.push_str(&format!("{} earlier messages dropped\n", trace.dropped));
+    }
+    for msg in &trace.messages {
+        let remote = msg.remote.as_deref().unwrap_or("unknown");
+        let line = match msg.direction {
+            SipTraceDirection::Received => format!("<<< {} received from {remote}", msg.at),
+            SipTraceDirection::Sent => format!(">>> {} sent to {remote}", msg.at),
+        };
+        out.push_str(&format!("\n{line}\n{}\n", msg.message));
+    }
+    out
+}
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use poem_openapi::{param::Path, OpenApi};
+
+use crate::{
+    hook::HttpHook,
+    protocol::{protobuf::sip_gateway::CallEvent, HookApiError, HookDeadLetter, HookDeadLettersResult, HookQueueStats},
+    secure::SecureContext,
+};
+
+use super::{header_secret::TokenAuthorization, response_result::ApiRes};
+
+/// Admin APIs for dead letters and queues of this node, only root secret is allowed
+pub struct HookApis {
+    pub secure_ctx: Arc<SecureContext>,
+    pub http_hook: HttpHook<CallEvent>,
+}
+
+impl HookApis {
+    fn check_root(&self, secret: &TokenAuthorization) -> Result<(), HookApiError> {
+        match self.secure_ctx.check_secret_scope(&secret.0.token) {
+            Some(None) => Ok(()),
+            _ => Err(HookApiError::WrongSecret),
+        }
+    }
+}
+
+/// Stats of all hook queues of this node, also shown in the node status
+pub fn queue_stats(http_hook: &HttpHook<CallEvent>) -> Vec<HookQueueStats> {
+    http_hook
+        .queue_stats()
+        .into_iter()
+        .enumerate()
+        .map(|(index, stats)| HookQueueStats {
+            index: index as u32,
+            depth: stats.depth as u32,
+            in_flight: stats.in_flight as u32,
+            capacity: stats.capacity as u32,
+            dropped: stats.dropped,
+            restarts: stats.restarts,
+        })
+        .collect()
+}
+
+#[OpenApi]
+impl HookApis {
+    #[oai(path = "/queues", method = "get")]
+    async fn queues(&self, secret: TokenAuthorization) -> ApiRes<Vec<HookQueueStats>, HookApiError> {
+        self.check_root(&secret)?;
+        Ok(queue_stats(&self.http_hook).into())
+    }
+
+    #[oai(path = "/dead_letters", method = "get")]
+    async fn list_dead_letters(&self, secret: TokenAuthorization) -> ApiRes<Vec<HookDeadLetter>, HookApiError> {
+        self.check_root(&secret)?;
+        let res = self
+            .http_hook
+            .dead_letters()
+            .into_iter()
+            .map(|dead| HookDeadLetter {
+                id: dead.req.id,
+                call_id: dead.req.key,
+                endpoint: dead.req.endpoint,
+                content_type: dead.req.content_type,
+                error: dead.error,
+                failed_at: dead.failed_at,
+                event: serde_json::to_value(&dead.req.body).expect("should convert to json"),
+            })
+            .collect::<Vec<_>>();
+        Ok(res.into())
+    }
+
+    #[oai(path = "/dead_letters/replay", method = "post")]
+    async fn replay_all(&self, secret: TokenAuthorization) -> ApiRes<HookDeadLettersResult, HookApiError> {
+        self.check_root(&secret)?;
+        let count = self.http_hook.replay_dead_letters(None);
+        Ok(HookDeadLettersResult { count: count as u32 }.into())
+    }
+
+    #[oai(path = "/dead_letters/:id/replay", method = "post")]
+    async fn replay(&self, secret: TokenAuthorization, Path(id): Path<u64>) -> ApiRes<HookDeadLettersResult, HookApiError> {
+        self.check_root(&secret)?;
+        match self.http_hook.replay_dead_letters(Some(id)) {
+            0 => Err(HookApiError::DeadLetterNotFound.into()),
+            count => Ok(HookDeadLettersResult { count: count as u32 }.into()),
+        }
+    }
+
+    #[oai(path = "/dead_letters", method = "delete")]
+    async fn purge_all(&self, secret: TokenAuthorization) -> ApiRes<HookDeadLettersResult, HookApiError> {
+        self.check_root(&secret)?;
+        let count = self.http_hook.purge_dead_letters(None);
+        Ok(HookDeadLettersResult { count: count as u32 }.into())
+    }
+
+    #[oai(path = "/dead_letters/:id", method = "delete")]
+    async fn purge(&self, secret: TokenAuthorization, Path(id): Path<u64>) -> ApiRes<HookDeadLettersResult, HookApiError> {
+        self.check_root(&secret)?;
+        match self.http_hook.purge_dead_letters(Some(id)) {
+            0 => Err(HookApiError::DeadLetterNotFound.into()),
+            count => Ok(HookDeadLettersResult { count: count as u32 }.into()),
+        }
+    }
+}
@@ -1,8 +1,38 @@
-use atm0s_small_p2p::PeerAddress;
-use poem_openapi::{payload::PlainText, OpenApi};
+use std::{net::SocketAddr, sync::Arc};
 
-pub struct NodeApiCtx {
+use atm0s_small_p2p::{now_ms, PeerAddress, PeerId};
+use poem_openapi::{payload::PlainText, ApiResponse, OpenApi};
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::{
+    address_book::AddressBookStorage,
+    cluster::ClusterCallRpc,
+    drain::DrainState,
+    hook::HttpHook,
+    protocol::{protobuf::sip_gateway::CallEvent, CallDirection, NodeApiError, NodeCallsStatus, NodeDrainStatus, NodeSipListener, NodeStatus},
+    secure::SecureContext,
+};
+
+use super::{api_hook, header_secret::TokenAuthorization, response_result::ApiRes, HttpCommand};
+
+/// Static info of this node
+#[derive(Clone)]
+pub struct NodeInfo {
+    pub peer_id: PeerId,
     pub address: PeerAddress,
+    pub sip_listen: SocketAddr,
+    pub sip_public: SocketAddr,
+}
+
+pub struct NodeApiCtx {
+    pub info: NodeInfo,
+    pub started_at: u64,
+    pub secure_ctx: Arc<SecureContext>,
+    pub address_book: AddressBookStorage,
+    pub tx: Sender<HttpCommand>,
+    pub call_rpc: ClusterCallRpc,
+    pub http_hook: HttpHook<CallEvent>,
+    pub drain: DrainState,
 }
 
 pub struct Apis {
@@ -13,12 +43,88 @@ impl Apis {
     pub fn new(ctx: NodeApiCtx) -> Self {
         Self { ctx }
     }
+
+    fn check_root(&self, secret: &TokenAuthorization) -> Result<(), NodeApiError> {
+        match self.ctx.secure_ctx.check_secret_scope(&secret.0.token) {
+            Some(None) => Ok(()),
+            _ => Err(NodeApiError::WrongSecret),
+        }
+    }
+}
+
+#[derive(ApiResponse)]
+enum ReadyResponse {
+    /// Node takes new calls
+    #[oai(status = 200)]
+    Ready(PlainText<String>),
+    /// Node is draining and doesn't take new calls
+    #[oai(status = 503)]
+    Draining(PlainText<String>),
 }
 
 #[OpenApi]
 impl Apis {
     #[oai(path = "/address", method = "get")]
     async fn get_address(&self) -> PlainText<String> {
-        PlainText(self.ctx.address.to_string())
+        PlainText(self.ctx.info.address.to_string())
+    }
+
+    /// Liveness of the process, it stays healthy while draining
+    #[oai(path = "/health", method = "get")]
+    async fn health(&self) -> PlainText<String> {
+        PlainText("OK".to_string())
+    }
+
+    #[oai(path = "/ready", method = "get")]
+    async fn ready(&self) -> ReadyResponse {
+        if self.ctx.drain.is_draining() {
+            ReadyResponse::Draining(PlainText("DRAINING".to_string()))
+        } else {
+            ReadyResponse::Ready(PlainText("OK".to_string()))
+        }
+    }
+
+    /// Start drain mode like SIGTERM, only root secret is allowed. Calling it again returns the current progress
+    #[oai(path = "/drain", method = "post")]
+    async fn drain(&self, secret: TokenAuthorization) -> ApiRes<NodeDrainStatus, NodeApiError> {
+        self.check_root(&secret)?;
+        let (tx, rx) = oneshot::channel();
+        self.ctx.tx.send(HttpCommand::Drain(tx)).await.map_err(|e| NodeApiError::InternalChannel(e.to_string()))?;
+        let res = rx.await.map_err(|e| NodeApiError::InternalChannel(e.to_string()))?;
+        Ok(res.into())
+    }
+
+    /// Load and state of this node, only root secret is allowed
+    #[oai(path = "/status", method = "get")]
+    async fn status(&self, secret: TokenAuthorization) -> ApiRes<NodeStatus, NodeApiError> {
+        self.check_root(&secret)?;
+        let (tx, rx) = oneshot::channel();
+        self.ctx.tx.send(HttpCommand::ListCalls(None, tx)).await.map_err(|e| NodeApiError::InternalChannel(e.to_string()))?;
+        let calls = rx.await.map_err(|e| NodeApiError::InternalChannel(e.to_string()))?;
+        let outgoing = calls.iter().filter(|call| call.direction == CallDirection::Outgoing).count() as u32;
+
+        let now = now_ms();
+        let info = &self.ctx.info;
+        let res = NodeStatus {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            started_at: self.ctx.started_at,
+            uptime_secs: now.saturating_sub(self.ctx.started_at) / 1000,
+            peer_id: info.peer_id.to_string(),
+            address: info.address.to_string(),
+            draining: self.ctx.drain.is_draining(),
+            peers: self.ctx.call_rpc.peers().into_iter().map(|peer| peer.to_string()).collect(),
+            calls: NodeCallsStatus {
+                outgoing,
+                incoming: calls.len() as u32 - outgoing,
+            },
+            sip_listeners: vec![NodeSipListener {
+                transport: "udp".to_string(),
+                listen: info.sip_listen.to_string(),
+                public: info.sip_public.to_string(),
+            }],
+            address_book: self.ctx.address_book.status(),
+            hook_queues: api_hook::queue_stats(&self.ctx.http_hook),
+        };
+        Ok(res.into())
     }
 }
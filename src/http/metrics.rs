@@ -0,0 +1,48 @@
+use atm0s_small_p2p::now_ms;
+use poem::{handler, web::Data, IntoResponse, Response};
+use prometheus::{Encoder, TextEncoder};
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::{
+    hook::HttpHook,
+    metrics::{self, direction_label, state_label},
+    protocol::protobuf::sip_gateway::CallEvent,
+};
+
+use super::HttpCommand;
+
+#[derive(Clone)]
+pub struct MetricsCtx {
+    pub tx: Sender<HttpCommand>,
+    pub http_hook: HttpHook<CallEvent>,
+}
+
+/// Prometheus text format of this node's metrics
+#[handler]
+pub async fn prometheus_metrics(data: Data<&MetricsCtx>) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+    if data.tx.send(HttpCommand::ListCalls(None, tx)).await.is_ok() {
+        if let Ok(calls) = rx.await {
+            metrics::CALLS_ACTIVE.reset();
+            for call in calls {
+                metrics::CALLS_ACTIVE.with_label_values(&[direction_label(call.direction), state_label(call.state)]).inc();
+            }
+        }
+    }
+
+    for (index, stats) in data.http_hook.queue_stats().into_iter().enumerate() {
+        metrics::HOOK_QUEUE_DEPTH.with_label_values(&[&index.to_string()]).set(stats.depth as i64);
+    }
+
+    let last_sync = metrics::ADDRESS_BOOK_LAST_SYNC.get();
+    if last_sync > 0.0 {
+        metrics::ADDRESS_BOOK_SYNC_AGE.set(now_ms() as f64 / 1000.0 - last_sync);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buf) {
+        log::error!("[HttpMetrics] encode metrics error {e:?}");
+    }
+    Response::builder().content_type(encoder.format_type()).body(buf)
+}
@@ -1,56 +1,97 @@
 use std::{io, net::SocketAddr, sync::Arc};
 
 use crate::{
-    protocol::{CallApiError, CreateCallRequest, CreateCallResponse},
+    address_book::AddressBookStorage,
+    cluster::ClusterCallRpc,
+    drain::DrainState,
+    hook::HttpHook,
+    protocol::{protobuf::sip_gateway::CallEvent, AppId, CallApiError, CallInfo, CreateCallRequest, CreateCallResponse, InternalCallId, NodeDrainStatus},
     secure::SecureContext,
     sip::MediaApi,
 };
-use atm0s_small_p2p::{pubsub_service::PubsubServiceRequester, PeerAddress};
+use atm0s_small_p2p::{now_ms, pubsub_service::PubsubServiceRequester};
 use poem::{get, listener::TcpListener, middleware::Tracing, EndpointExt, Route, Server};
 use poem_openapi::OpenApiService;
-use tokio::sync::{
-    mpsc::{channel, Receiver, Sender},
-    oneshot,
-};
+use tokio::sync::{mpsc::Sender, oneshot};
 
 mod api_call;
+mod api_hook;
 mod api_node;
 mod header_secret;
+mod metrics;
 mod response_result;
+mod sse_call;
+mod ws_format;
+mod ws_hook_push;
 mod ws_in_call;
+mod ws_notify;
 mod ws_out_call;
 
+pub use api_node::NodeInfo;
+
 pub enum HttpCommand {
-    CreateCall(CreateCallRequest, MediaApi, oneshot::Sender<Result<CreateCallResponse, CallApiError>>),
+    CreateCall(AppId, CreateCallRequest, MediaApi, oneshot::Sender<Result<CreateCallResponse, CallApiError>>),
+    /// None app_id means all apps, which is only allowed for root secret
+    ListCalls(Option<AppId>, oneshot::Sender<Vec<CallInfo>>),
+    GetCall(InternalCallId, Option<AppId>, oneshot::Sender<Option<CallInfo>>),
+    /// Start drain mode if not started yet
+    Drain(oneshot::Sender<NodeDrainStatus>),
 }
 
 pub struct HttpServer {
     http_listen: SocketAddr,
-    p2p_addr: PeerAddress,
+    node: NodeInfo,
+    started_at: u64,
     media_gateway: String,
     secure_ctx: Arc<SecureContext>,
+    address_book: AddressBookStorage,
     tx: Sender<HttpCommand>,
     call_pubsub: PubsubServiceRequester,
+    call_rpc: ClusterCallRpc,
+    http_hook: HttpHook<CallEvent>,
+    drain: DrainState,
 }
 
 impl HttpServer {
-    pub fn new(http_listen: SocketAddr, p2p_addr: PeerAddress, media_gateway: &str, secure_ctx: Arc<SecureContext>, call_pubsub: PubsubServiceRequester) -> (Self, Receiver<HttpCommand>) {
-        let (tx, rx) = channel(10);
-        (
-            Self {
-                http_listen,
-                p2p_addr,
-                media_gateway: media_gateway.to_owned(),
-                tx,
-                secure_ctx,
-                call_pubsub,
-            },
-            rx,
-        )
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        http_listen: SocketAddr,
+        node: NodeInfo,
+        media_gateway: &str,
+        secure_ctx: Arc<SecureContext>,
+        address_book: AddressBookStorage,
+        tx: Sender<HttpCommand>,
+        call_pubsub: PubsubServiceRequester,
+        call_rpc: ClusterCallRpc,
+        http_hook: HttpHook<CallEvent>,
+        drain: DrainState,
+    ) -> Self {
+        Self {
+            http_listen,
+            node,
+            started_at: now_ms(),
+            media_gateway: media_gateway.to_owned(),
+            tx,
+            secure_ctx,
+            address_book,
+            call_pubsub,
+            call_rpc,
+            http_hook,
+            drain,
+        }
     }
 
     pub async fn run_loop(&mut self) -> io::Result<()> {
-        let node_api = api_node::Apis::new(api_node::NodeApiCtx { address: self.p2p_addr.clone() });
+        let node_api = api_node::Apis::new(api_node::NodeApiCtx {
+            info: self.node.clone(),
+            started_at: self.started_at,
+            secure_ctx: self.secure_ctx.clone(),
+            address_book: self.address_book.clone(),
+            tx: self.tx.clone(),
+            call_rpc: self.call_rpc.clone(),
+            http_hook: self.http_hook.clone(),
+            drain: self.drain.clone(),
+        });
         let node_service: OpenApiService<_, ()> = OpenApiService::new(node_api, "Node APIs", env!("CARGO_PKG_VERSION")).server("/").url_prefix("/node");
         let node_ui = node_service.swagger_ui();
         let node_spec = node_service.spec();
@@ -60,18 +101,59 @@ impl HttpServer {
             tx: self.tx.clone(),
             secure_ctx: self.secure_ctx.clone(),
             call_pubsub: self.call_pubsub.clone(),
+            call_rpc: self.call_rpc.clone(),
         };
         let call_service: OpenApiService<_, ()> = OpenApiService::new(call_api, "Console call APIs", env!("CARGO_PKG_VERSION")).server("/").url_prefix("/call");
         let call_ui = call_service.swagger_ui();
         let call_spec = call_service.spec();
 
+        let hook_api = api_hook::HookApis {
+            secure_ctx: self.secure_ctx.clone(),
+            http_hook: self.http_hook.clone(),
+        };
+        let hook_service: OpenApiService<_, ()> = OpenApiService::new(hook_api, "Hook admin APIs", env!("CARGO_PKG_VERSION")).server("/").url_prefix("/hook");
+        let hook_ui = hook_service.swagger_ui();
+        let hook_spec = hook_service.spec();
+
         let app = Route::new()
             .nest("/node/", node_service)
             .nest("/call/", call_service)
+            .nest("/hook/", hook_service)
             .nest("/docs/node/", node_ui)
             .nest("/docs/call/", call_ui)
+            .nest("/docs/hook/", hook_ui)
             .at("/docs/call/spec", poem::endpoint::make_sync(move |_| call_spec.clone()))
             .at("/docs/node/spec", poem::endpoint::make_sync(move |_| node_spec.clone()))
+            .at("/docs/hook/spec", poem::endpoint::make_sync(move |_| hook_spec.clone()))
+            .at(
+                "/call/notify",
+                get(ws_notify::ws_notify).data(ws_notify::WebsocketNotifyCtx {
+                    secure_ctx: self.secure_ctx.clone(),
+                    call_pubsub: self.call_pubsub.clone(),
+                    call_rpc: self.call_rpc.clone(),
+                }),
+            )
+            .at(
+                "/metrics",
+                get(metrics::prometheus_metrics).data(metrics::MetricsCtx {
+                    tx: self.tx.clone(),
+                    http_hook: self.http_hook.clone(),
+                }),
+            )
+            .at(
+                "/hook/push",
+                get(ws_hook_push::ws_hook_push).data(ws_hook_push::WebsocketHookPushCtx {
+                    secure_ctx: self.secure_ctx.clone(),
+                    call_pubsub: self.call_pubsub.clone(),
+                }),
+            )
+            .at(
+                "/call/:direction/:call_id/events",
+                get(sse_call::sse_call_events).data(sse_call::SseCallCtx {
+                    secure_ctx: self.secure_ctx.clone(),
+                    call_pubsub: self.call_pubsub.clone(),
+                }),
+            )
             .at(
                 "/call/outgoing/:call_id",
                 get(ws_out_call::ws_single_call).data(ws_out_call::WebsocketCallCtx {
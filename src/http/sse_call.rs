@@ -0,0 +1,128 @@
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use crate::{
+    protocol::{
+        protobuf::sip_gateway::{
+            call_event,
+            incoming_call_data::{incoming_call_event, incoming_call_request, incoming_call_response},
+            outgoing_call_data::{outgoing_call_event, outgoing_call_request, outgoing_call_response},
+            CallEvent,
+        },
+        InternalCallId,
+    },
+    secure::SecureContext,
+};
+
+use atm0s_small_p2p::pubsub_service::{PubsubServiceRequester, SubscriberEventOb};
+use poem::{
+    handler,
+    http::HeaderMap,
+    web::{
+        sse::{Event, SSE},
+        Data, Path, Query,
+    },
+    IntoResponse, Response,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+const RPC_TIMEOUT_SECONDS: u64 = 2;
+const KEEP_ALIVE_SECONDS: u64 = 5;
+
+#[derive(Clone)]
+pub struct SseCallCtx {
+    pub secure_ctx: Arc<SecureContext>,
+    pub call_pubsub: PubsubServiceRequester,
+}
+
+#[derive(Debug, Deserialize)]
+struct SseQuery {
+    token: String,
+}
+
+/// Stream CallEvent of a call as SSE, with event id is the seq of the event.
+/// Events after Last-Event-ID are replayed from the call history before live events.
+#[handler]
+pub async fn sse_call_events(Path((direction, call_id)): Path<(String, String)>, Query(query): Query<SseQuery>, headers: &HeaderMap, data: Data<&SseCallCtx>) -> impl IntoResponse {
+    if direction != "outgoing" && direction != "incoming" {
+        return Response::builder().status(StatusCode::NOT_FOUND).finish();
+    }
+    if let Some(token) = data.secure_ctx.decode_call_token(&query.token) {
+        if *token.call_id != call_id {
+            return Response::builder().status(StatusCode::BAD_REQUEST).finish();
+        }
+    } else {
+        return Response::builder().status(StatusCode::UNAUTHORIZED).finish();
+    }
+
+    let last_seq = headers.get("Last-Event-ID").and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+    let call_id: InternalCallId = call_id.into();
+    let mut subscriber = data.call_pubsub.subscriber(call_id.to_pubsub_channel()).await;
+
+    // subscribe first, then fetch history, live events which are already in history are skipped by seq
+    let timeout = Duration::from_secs(RPC_TIMEOUT_SECONDS);
+    let history = if direction == "outgoing" {
+        let req = outgoing_call_request::Action::History(outgoing_call_request::History { after_seq: last_seq });
+        match subscriber.requester().feedback_rpc_ob::<_, outgoing_call_response::Response>("history", &req, timeout).await {
+            Ok(outgoing_call_response::Response::History(history)) => history.events,
+            res => {
+                log::warn!("[SseCall {call_id}] get history failed {res:?}");
+                vec![]
+            }
+        }
+    } else {
+        let req = incoming_call_request::Action::History(incoming_call_request::History { after_seq: last_seq });
+        match subscriber.requester().feedback_rpc_ob::<_, incoming_call_response::Response>("history", &req, timeout).await {
+            Ok(incoming_call_response::Response::History(history)) => history.events,
+            res => {
+                log::warn!("[SseCall {call_id}] get history failed {res:?}");
+                vec![]
+            }
+        }
+    };
+
+    let state = SseState {
+        pending: history.into(),
+        last_seq,
+        ended: false,
+    };
+    let stream = futures_util::stream::unfold((subscriber, state), |(mut subscriber, mut state)| async move {
+        loop {
+            if state.ended {
+                return None;
+            }
+            if let Some(event) = state.pending.pop_front() {
+                if event.seq <= state.last_seq {
+                    continue;
+                }
+                state.last_seq = event.seq;
+                state.ended = is_ended(&event);
+                let json = serde_json::to_string(&event).expect("should convert to json");
+                return Some((Event::message(json).id(event.seq.to_string()).event_type("call_event"), (subscriber, state)));
+            }
+            match subscriber.recv_ob::<CallEvent>().await {
+                Ok(SubscriberEventOb::Publish(event)) => state.pending.push_back(event),
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        }
+    });
+
+    SSE::new(stream).keep_alive(Duration::from_secs(KEEP_ALIVE_SECONDS)).into_response()
+}
+
+struct SseState {
+    pending: VecDeque<CallEvent>,
+    last_seq: u64,
+    ended: bool,
+}
+
+fn is_ended(event: &CallEvent) -> bool {
+    matches!(
+        &event.event,
+        Some(call_event::Event::Outgoing(e)) if matches!(e.event, Some(outgoing_call_event::Event::Ended(_)))
+    ) || matches!(
+        &event.event,
+        Some(call_event::Event::Incoming(e)) if matches!(e.event, Some(incoming_call_event::Event::Ended(_)))
+    )
+}
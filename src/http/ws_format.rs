@@ -0,0 +1,30 @@
+use poem::web::websocket::Message as WebsocketMessage;
+use prost::Message;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Frame format of websocket sessions, replies are sent in the format of the latest client frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WsFormat {
+    #[default]
+    Protobuf,
+    Json,
+}
+
+impl WsFormat {
+    pub fn encode<T: Message + Serialize>(&self, msg: &T) -> WebsocketMessage {
+        match self {
+            WsFormat::Protobuf => WebsocketMessage::Binary(msg.encode_to_vec()),
+            WsFormat::Json => WebsocketMessage::Text(serde_json::to_string(msg).expect("should convert to json")),
+        }
+    }
+
+    /// Decode a client frame with the format detected from frame type, returns None for control frames
+    pub fn decode<T: Message + DeserializeOwned + Default>(message: WebsocketMessage) -> Option<(Self, Result<T, String>)> {
+        match message {
+            WebsocketMessage::Binary(data) => Some((WsFormat::Protobuf, T::decode(data.as_slice()).map_err(|e| e.to_string()))),
+            WebsocketMessage::Text(text) => Some((WsFormat::Json, serde_json::from_str(&text).map_err(|e| e.to_string()))),
+            _ => None,
+        }
+    }
+}
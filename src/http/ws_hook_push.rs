@@ -0,0 +1,140 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::{
+    error::PrintErrorSimple,
+    hook::PUSH_METHOD_NOTIFY,
+    protocol::protobuf::sip_gateway::{hook_push_data, CallEvent, HookPushData},
+    secure::SecureContext,
+    utils::select3::{self, OrOutput},
+};
+
+use super::ws_format::WsFormat;
+
+use atm0s_small_p2p::pubsub_service::{PublisherEventOb, PubsubServiceRequester};
+use futures_util::{SinkExt, StreamExt};
+use poem::{
+    handler,
+    web::{
+        websocket::{Message as WebsocketMessage, WebSocket},
+        Data, Query,
+    },
+    IntoResponse, Request, Response,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+/// Requests which the app didn't answer in this time are forgotten, the sender has timed out already
+const PENDING_TIMEOUT_SECONDS: u64 = 60;
+
+#[derive(Clone)]
+pub struct WebsocketHookPushCtx {
+    pub secure_ctx: Arc<SecureContext>,
+    pub call_pubsub: PubsubServiceRequester,
+}
+
+#[derive(Debug, Deserialize)]
+struct WsQuery {
+    /// the name in `ws-push://{name}` hook urls
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    format: WsFormat,
+}
+
+/// Push socket of an app, authenticated with the app secret as Bearer token.
+/// It receives hooks of `ws-push://{name}` urls from all nodes and answers them with the same request id
+#[handler]
+pub async fn ws_hook_push(req: &Request, Query(query): Query<WsQuery>, ws: WebSocket, data: Data<&WebsocketHookPushCtx>) -> impl IntoResponse {
+    let app_id = match req
+        .header("Authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .and_then(|secret| data.secure_ctx.check_secret(secret))
+    {
+        Some(app_id) => app_id,
+        None => return Response::builder().status(StatusCode::UNAUTHORIZED).finish(),
+    };
+
+    let name = query.name;
+    let mut publisher = data.call_pubsub.publisher(app_id.to_hook_push_channel(&name)).await;
+    ws.on_upgrade(move |socket| async move {
+        log::info!("[WsHookPush {app_id}/{name}] socket started");
+        let (mut sink, mut stream) = socket.split();
+        let mut format = query.format;
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        let mut next_id = 0;
+        // rpc of requests which are sent to the app, answered when the app responds with the same id
+        let mut pending = HashMap::new();
+        loop {
+            let out = select3::or(publisher.recv_ob::<CallEvent>(), stream.next(), interval.tick()).await;
+            match out {
+                OrOutput::Left(Ok(event)) => match event {
+                    PublisherEventOb::FeedbackRpc(event, rpc_id, method, peer_src) | PublisherEventOb::GuestFeedbackRpc(event, rpc_id, method, peer_src) => {
+                        next_id += 1;
+                        let msg = HookPushData {
+                            data: Some(hook_push_data::Data::Request(hook_push_data::Request {
+                                id: next_id,
+                                event: Some(event),
+                                notify: method == PUSH_METHOD_NOTIFY,
+                            })),
+                        };
+                        if let Err(e) = sink.send(format.encode(&msg)).await {
+                            log::error!("[WsHookPush {app_id}/{name}] send data error {e:?}");
+                            break;
+                        }
+                        pending.insert(next_id, (rpc_id, peer_src, method, Instant::now()));
+                    }
+                    _ => {}
+                },
+                OrOutput::Left(Err(e)) => {
+                    log::error!("[WsHookPush {app_id}/{name}] pubsub error {e:?}");
+                    break;
+                }
+                OrOutput::Middle(Some(Ok(message))) => {
+                    if let Some((msg_format, data)) = WsFormat::decode::<HookPushData>(message) {
+                        format = msg_format;
+                        match data.map(|data| data.data) {
+                            Ok(Some(hook_push_data::Data::Response(res))) => match pending.remove(&res.id) {
+                                Some((rpc_id, peer_src, method, _)) => {
+                                    let requester = publisher.requester();
+                                    let answered = if method == PUSH_METHOD_NOTIFY {
+                                        requester.answer_feedback_rpc_ob(rpc_id, peer_src, &res.notify_response.unwrap_or_default()).await
+                                    } else {
+                                        requester.answer_feedback_rpc_ob(rpc_id, peer_src, &()).await
+                                    };
+                                    answered.print_error("[WsHookPush] answer rpc");
+                                }
+                                None => {
+                                    log::warn!("[WsHookPush {app_id}/{name}] response for unknown or expired request {}", res.id);
+                                }
+                            },
+                            Ok(data) => {
+                                log::error!("[WsHookPush {app_id}/{name}] unsupported data {data:?}");
+                            }
+                            Err(err) => {
+                                log::error!("[WsHookPush {app_id}/{name}] parse data error {err:?}");
+                            }
+                        }
+                    }
+                }
+                OrOutput::Middle(Some(Err(e))) => {
+                    log::error!("[WsHookPush {app_id}/{name}] socket error {e:?}");
+                    break;
+                }
+                OrOutput::Middle(None) => {
+                    log::info!("[WsHookPush {app_id}/{name}] socket closed");
+                    break;
+                }
+                OrOutput::Right(_) => {
+                    pending.retain(|_, (_, _, _, sent_at)| sent_at.elapsed() < Duration::from_secs(PENDING_TIMEOUT_SECONDS));
+                    if let Err(e) = sink.send(WebsocketMessage::Ping(vec![])).await {
+                        log::error!("[WsHookPush {app_id}/{name}] send data error {e:?}");
+                        break;
+                    }
+                }
+            }
+        }
+        log::info!("[WsHookPush {app_id}/{name}] socket ended");
+    })
+    .into_response()
+}
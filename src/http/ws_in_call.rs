@@ -3,8 +3,9 @@ use std::{sync::Arc, time::Duration};
 use crate::{
     protocol::{
         protobuf::sip_gateway::{
-            incoming_call_data::{self, incoming_call_response, IncomingCallEvent, IncomingCallResponse},
-            IncomingCallData,
+            call_event,
+            incoming_call_data::{self, incoming_call_request, incoming_call_response, IncomingCallResponse},
+            CallEvent, IncomingCallData,
         },
         InternalCallId,
     },
@@ -12,6 +13,8 @@ use crate::{
     utils::select2::{self, OrOutput},
 };
 
+use super::ws_format::WsFormat;
+
 use atm0s_small_p2p::pubsub_service::{PubsubServiceRequester, SubscriberEventOb};
 use futures_util::{SinkExt, StreamExt};
 use poem::{
@@ -22,7 +25,6 @@ use poem::{
     },
     IntoResponse, Response,
 };
-use prost::Message;
 use reqwest::StatusCode;
 use serde::Deserialize;
 use tokio::sync::mpsc::unbounded_channel;
@@ -38,6 +40,8 @@ pub struct WebsocketCallCtx {
 #[derive(Debug, Deserialize)]
 struct WsQuery {
     token: String,
+    #[serde(default)]
+    format: WsFormat,
 }
 
 #[handler]
@@ -55,10 +59,31 @@ pub async fn ws_single_call(Path(call_id): Path<String>, Query(query): Query<WsQ
     let mut subscriber = data.call_pubsub.subscriber(call_id.to_pubsub_channel()).await;
     ws.on_upgrade(move |socket| async move {
         let (mut sink, mut stream) = socket.split();
+        let mut format = query.format;
         let (out_tx, mut out_rx) = unbounded_channel();
         let mut interval = tokio::time::interval(Duration::from_secs(5));
+
+        // snapshot is the first message, live events which are already in it are skipped by seq
+        let mut last_seq = 0;
+        let req = incoming_call_request::Action::Snapshot(Default::default());
+        match subscriber
+            .requester()
+            .feedback_rpc_ob::<_, incoming_call_response::Response>("snapshot", &req, Duration::from_secs(RPC_TIMEOUT_SECONDS))
+            .await
+        {
+            Ok(incoming_call_response::Response::Snapshot(snapshot)) => {
+                last_seq = snapshot.events.last().map(|e| e.seq).unwrap_or(0);
+                let _ = out_tx.send(IncomingCallData {
+                    data: Some(incoming_call_data::Data::Snapshot(snapshot)),
+                });
+            }
+            res => {
+                log::warn!("[WsCall {call_id}] get snapshot failed {res:?}");
+            }
+        }
+
         loop {
-            let out = select2::or(select2::or(subscriber.recv_ob::<IncomingCallEvent>(), out_rx.recv()), select2::or(stream.next(), interval.tick())).await;
+            let out = select2::or(select2::or(subscriber.recv_ob::<CallEvent>(), out_rx.recv()), select2::or(stream.next(), interval.tick())).await;
             match out {
                 OrOutput::Left(OrOutput::Left(Ok(event))) => match event {
                     SubscriberEventOb::PeerJoined(peer_src) => {
@@ -69,9 +94,15 @@ pub async fn ws_single_call(Path(call_id): Path<String>, Query(query): Query<WsQ
                     }
                     SubscriberEventOb::Publish(msg) => {
                         log::info!("[WsCall {call_id}] got publisher message {msg:?}");
-                        let _ = out_tx.send(IncomingCallData {
-                            data: Some(incoming_call_data::Data::Event(msg)),
-                        });
+                        if msg.seq <= last_seq {
+                            continue;
+                        }
+                        last_seq = msg.seq;
+                        if let Some(call_event::Event::Incoming(event)) = msg.event {
+                            let _ = out_tx.send(IncomingCallData {
+                                data: Some(incoming_call_data::Data::Event(event)),
+                            });
+                        }
                     }
                     _ => {
                         log::warn!("[WsCall {call_id}] unhandled pubsub event {event:?}");
@@ -82,9 +113,8 @@ pub async fn ws_single_call(Path(call_id): Path<String>, Query(query): Query<WsQ
                 }
                 OrOutput::Left(OrOutput::Right(event)) => match event {
                     Some(msg) => {
-                        let data = msg.encode_to_vec();
                         log::info!("[WsCall {call_id}] emit data {msg:?}");
-                        if let Err(e) = sink.send(WebsocketMessage::Binary(data)).await {
+                        if let Err(e) = sink.send(format.encode(&msg)).await {
                             log::error!("[WsCall {call_id}] send data error {e:?}");
                             break;
                         }
@@ -92,8 +122,9 @@ pub async fn ws_single_call(Path(call_id): Path<String>, Query(query): Query<WsQ
                     None => break,
                 },
                 OrOutput::Right(OrOutput::Left(Some(Ok(message)))) => {
-                    if let WebsocketMessage::Binary(msg) = message {
-                        match IncomingCallData::decode(msg.as_slice()) {
+                    if let Some((msg_format, data)) = WsFormat::decode::<IncomingCallData>(message) {
+                        format = msg_format;
+                        match data {
                             Ok(data) => match data.data {
                                 Some(incoming_call_data::Data::Request(req)) => {
                                     log::info!("[WsCall {call_id}] on incoming req {} {:?}", req.req_id, req.action);
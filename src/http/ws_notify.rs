@@ -0,0 +1,197 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use crate::{
+    cluster::ClusterCallRpc,
+    protocol::{
+        protobuf::sip_gateway::{
+            call_event,
+            incoming_call_data::{incoming_call_response, IncomingCallResponse},
+            incoming_call_notify,
+            notify_session_data::{self, CallRequest, CallResponse},
+            CallEvent, NotifySessionData,
+        },
+        AppId, CallDirection, InternalCallId,
+    },
+    secure::SecureContext,
+    utils::select2::{self, OrOutput},
+};
+
+use super::ws_format::WsFormat;
+
+use atm0s_small_p2p::pubsub_service::{PubsubServiceRequester, SubscriberEventOb};
+use futures_util::{SinkExt, StreamExt};
+use poem::{
+    handler,
+    web::{
+        websocket::{Message as WebsocketMessage, WebSocket},
+        Data, Query,
+    },
+    IntoResponse, Response,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+const RPC_TIMEOUT_SECONDS: u64 = 2;
+
+#[derive(Clone)]
+pub struct WebsocketNotifyCtx {
+    pub secure_ctx: Arc<SecureContext>,
+    pub call_pubsub: PubsubServiceRequester,
+    pub call_rpc: ClusterCallRpc,
+}
+
+#[derive(Debug, Deserialize)]
+struct WsQuery {
+    token: String,
+    #[serde(default)]
+    format: WsFormat,
+}
+
+#[handler]
+pub async fn ws_notify(Query(query): Query<WsQuery>, ws: WebSocket, data: Data<&WebsocketNotifyCtx>) -> impl IntoResponse {
+    let token = if let Some(token) = data.secure_ctx.decode_notify_token(&query.token) {
+        token
+    } else {
+        return Response::builder().status(StatusCode::UNAUTHORIZED).finish();
+    };
+
+    let app_id = token.app_id;
+    let session_id = token.session_id;
+    let call_pubsub = data.call_pubsub.clone();
+    let call_rpc = data.call_rpc.clone();
+    let mut subscriber = data.call_pubsub.subscriber(app_id.to_notify_channel()).await;
+    ws.on_upgrade(move |socket| async move {
+        log::info!("[WsNotify {app_id}/{session_id}] session started");
+        let (mut sink, mut stream) = socket.split();
+        let mut format = query.format;
+        let (out_tx, mut out_rx) = unbounded_channel();
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        // empty numbers means the whole app
+        let mut numbers = HashSet::new();
+        loop {
+            let out = select2::or(select2::or(subscriber.recv_ob::<CallEvent>(), out_rx.recv()), select2::or(stream.next(), interval.tick())).await;
+            match out {
+                OrOutput::Left(OrOutput::Left(Ok(event))) => match event {
+                    SubscriberEventOb::Publish(event) => {
+                        if is_subscribed(&numbers, &event) {
+                            let _ = out_tx.send(NotifySessionData {
+                                data: Some(notify_session_data::Data::Event(event)),
+                            });
+                        }
+                    }
+                    SubscriberEventOb::PeerJoined(_) | SubscriberEventOb::PeerLeaved(_) => {}
+                    _ => {
+                        log::warn!("[WsNotify {app_id}/{session_id}] unhandled pubsub event {event:?}");
+                    }
+                },
+                OrOutput::Left(OrOutput::Left(_)) => {
+                    break;
+                }
+                OrOutput::Left(OrOutput::Right(event)) => match event {
+                    Some(msg) => {
+                        log::info!("[WsNotify {app_id}/{session_id}] emit data {msg:?}");
+                        if let Err(e) = sink.send(format.encode(&msg)).await {
+                            log::error!("[WsNotify {app_id}/{session_id}] send data error {e:?}");
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+                OrOutput::Right(OrOutput::Left(Some(Ok(message)))) => {
+                    if let Some((msg_format, data)) = WsFormat::decode::<NotifySessionData>(message) {
+                        format = msg_format;
+                        match data {
+                            Ok(data) => match data.data {
+                                Some(notify_session_data::Data::Subscribe(subscribe)) => {
+                                    log::info!("[WsNotify {app_id}/{session_id}] subscribe numbers {:?}", subscribe.numbers);
+                                    numbers = subscribe.numbers.into_iter().collect();
+                                }
+                                Some(notify_session_data::Data::Request(req)) => {
+                                    let app_id = app_id.clone();
+                                    let call_pubsub = call_pubsub.clone();
+                                    let call_rpc = call_rpc.clone();
+                                    let out_tx = out_tx.clone();
+                                    tokio::spawn(async move { handle_call_request(app_id, call_pubsub, call_rpc, req, out_tx).await });
+                                }
+                                _ => {
+                                    log::error!("[WsNotify {app_id}/{session_id}] unsupported data {data:?}");
+                                }
+                            },
+                            Err(err) => {
+                                log::error!("[WsNotify {app_id}/{session_id}] parse data error {err:?}");
+                            }
+                        }
+                    }
+                }
+                OrOutput::Right(OrOutput::Left(Some(Err(e)))) => {
+                    log::error!("[WsNotify {app_id}/{session_id}] socket error {e:?}");
+                    break;
+                }
+                OrOutput::Right(OrOutput::Left(None)) => {
+                    log::info!("[WsNotify {app_id}/{session_id}] socket closed");
+                    break;
+                }
+                OrOutput::Right(OrOutput::Right(_)) => {
+                    if let Err(e) = sink.send(WebsocketMessage::Ping(vec![])).await {
+                        log::error!("[WsNotify {app_id}/{session_id}] send data error {e:?}");
+                        break;
+                    }
+                }
+            }
+        }
+        log::info!("[WsNotify {app_id}/{session_id}] session ended");
+    })
+    .into_response()
+}
+
+fn is_subscribed(numbers: &HashSet<String>, event: &CallEvent) -> bool {
+    if numbers.is_empty() {
+        return true;
+    }
+    let call_to = match &event.event {
+        Some(call_event::Event::Notify(notify)) => match &notify.event {
+            Some(incoming_call_notify::Event::Arrived(e)) => &e.call_to,
+            Some(incoming_call_notify::Event::Cancelled(e)) => &e.call_to,
+            Some(incoming_call_notify::Event::Accepted(e)) => &e.call_to,
+            Some(incoming_call_notify::Event::Rejected(e)) => &e.call_to,
+            None => return false,
+        },
+        _ => return false,
+    };
+    numbers.contains(call_to)
+}
+
+/// Forward an action of the session to the call, only incoming calls of the session's app are allowed
+async fn handle_call_request(app_id: AppId, call_pubsub: PubsubServiceRequester, call_rpc: ClusterCallRpc, req: CallRequest, out_tx: UnboundedSender<NotifySessionData>) {
+    let request = if let Some(request) = req.request {
+        request
+    } else {
+        return;
+    };
+    let action = if let Some(action) = request.action {
+        action
+    } else {
+        return;
+    };
+
+    let call_id: InternalCallId = req.call_id.clone().into();
+    let response = match call_rpc.get_call(call_id.clone(), Some(app_id)).await {
+        Ok(Some(call)) if call.direction == CallDirection::Incoming => call_pubsub
+            .feedback_rpc_as_guest_ob::<_, incoming_call_response::Response>(call_id.to_pubsub_channel(), "action", &action, Duration::from_secs(RPC_TIMEOUT_SECONDS))
+            .await
+            .unwrap_or_else(|e| incoming_call_response::Response::Error(incoming_call_response::Error { message: e.to_string() })),
+        Ok(_) => incoming_call_response::Response::Error(incoming_call_response::Error { message: "CallNotFound".to_owned() }),
+        Err(e) => incoming_call_response::Response::Error(incoming_call_response::Error { message: e.to_string() }),
+    };
+
+    let _ = out_tx.send(NotifySessionData {
+        data: Some(notify_session_data::Data::Response(CallResponse {
+            call_id: req.call_id,
+            response: Some(IncomingCallResponse {
+                req_id: request.req_id,
+                response: Some(response),
+            }),
+        })),
+    });
+}
@@ -1,36 +1,64 @@
 use std::{
     io,
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
-use atm0s_small_p2p::{pubsub_service::PubsubService, NetworkAddress, P2pNetwork, P2pNetworkConfig, P2pNetworkEvent, PeerAddress, PeerId, SharedKeyHandshake};
+use atm0s_small_p2p::{now_ms, pubsub_service::PubsubService, NetworkAddress, P2pNetwork, P2pNetworkConfig, P2pNetworkEvent, PeerAddress, PeerId, SharedKeyHandshake};
 use call_manager::CallManager;
+use cdr::{CdrSink, CdrWriter, HttpBatchCdrSink, JsonLinesCdrSink};
 use clap::ValueEnum;
-use hook::HttpHook;
-use http::{HttpCommand, HttpServer};
+use cluster::{ClusterCallCounter, ClusterCallRpc};
+use drain::DrainState;
+use hook::{HttpHook, HttpHookRetry};
+use http::{HttpCommand, HttpServer, NodeInfo};
+use protocol::NodeDrainStatus;
 use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use serde::Deserialize;
+use sip::{HepExporter, SipTrace};
 use thiserror::Error;
-use tokio::sync::mpsc::Receiver;
-use utils::select3;
+use tokio::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::Instant,
+};
+use utils::{select2, select3};
 
 mod address_book;
 mod call_manager;
+mod cdr;
+mod cluster;
+mod config;
+mod drain;
 mod error;
 mod hook;
 mod http;
+mod metrics;
 mod protocol;
 mod secure;
 mod sip;
+mod telemetry;
 mod utils;
 
 pub use address_book::{AddressBookStorage, AddressBookSync};
+pub use config::{merge_config_value, CallLimits, ConfigFile, ConfigWatcher, ReloadableConfig};
+pub use hook::{HttpHookLimit, HttpHookOverflow};
 pub use secure::SecureContext;
+pub use sip::{HepConfig, HepTransport};
+pub use telemetry::{init_tracing, shutdown_tracing, LogLevelHandle};
 
 pub const DEFAULT_CLUSTER_CERT: &[u8] = include_bytes!("../certs/dev.cluster.cert");
 pub const DEFAULT_CLUSTER_KEY: &[u8] = include_bytes!("../certs/dev.cluster.key");
 
+/// While draining, the gateway checks this often if it is drained, since hook deliveries don't wake it
+const DRAIN_CHECK_INTERVAL_MS: u64 = 1000;
+/// How long force ended calls have for sending BYE and their last hooks before the node exits
+const DRAIN_END_CALLS_WAIT_SECS: u64 = 5;
+/// How long pending CDR records can take to be written before the node exits
+const SHUTDOWN_CDR_TIMEOUT_SECS: u64 = 5;
+
 #[derive(Error, Debug)]
 pub enum GatewayError {
     #[error("IoError {0}")]
@@ -51,18 +79,59 @@ pub struct GatewayConfig {
     pub sip_listen: SocketAddr,
     pub address_book: AddressBookStorage,
     pub http_hook_queues: usize,
+    pub http_hook_max_retries: usize,
+    pub http_hook_retry_base: Duration,
+    pub http_hook_retry_max: Duration,
+    pub http_hook_limit: HttpHookLimit,
+    pub hook_data_dir: Option<PathBuf>,
     pub media_gateway: String,
     pub secure_ctx: Arc<SecureContext>,
     pub sdn_peer_id: PeerId,
     pub sdn_listen_addr: SocketAddr,
     pub sdn_seeds: Vec<PeerAddress>,
     pub sdn_secret: String,
+    pub cdr_file: Option<PathBuf>,
+    pub cdr_http_endpoint: Option<String>,
+    pub cdr_http_batch_size: usize,
+    pub cdr_flush_interval: Duration,
+    pub subscriber_grace: Duration,
+    pub sip_trace_capacity: usize,
+    pub sip_trace_retention: Duration,
+    pub hep: Option<HepConfig>,
+    pub drain_timeout: Duration,
+    pub limits: CallLimits,
+}
+
+enum DrainStage {
+    /// Waiting for calls to end by themselves
+    WaitCalls(Instant),
+    /// Remaining calls are ended by the node
+    EndCalls(Instant),
+    /// The node exits even if some calls are still active
+    Expired,
+}
+
+/// Requests to the gateway from outside of its loop, like process signals
+pub enum GatewayControl {
+    /// Start draining, like the drain api
+    Drain,
+    /// Apply reloaded sections of the config file
+    Reload(ReloadableConfig),
+    /// Sent by the gateway itself while draining, for checking the drain deadline
+    DrainCheck,
 }
 
 pub struct Gateway {
     http_rx: Receiver<HttpCommand>,
+    control_tx: Sender<GatewayControl>,
+    control_rx: Receiver<GatewayControl>,
     call_manager: CallManager,
+    call_rpc: ClusterCallRpc,
     p2p: P2pNetwork<SharedKeyHandshake>,
+    address_book: AddressBookStorage,
+    drain: DrainState,
+    drain_timeout: Duration,
+    drain_stage: Option<DrainStage>,
 }
 
 impl Gateway {
@@ -86,38 +155,223 @@ impl Gateway {
 
         let mut pubsub_call = PubsubService::new(p2p.create_service(0.into()));
         let p2p_pubsub_call = pubsub_call.requester();
-        let http_hook = HttpHook::new(cfg.http_hook_queues);
+        let mut pubsub_cluster = PubsubService::new(p2p.create_service(1.into()));
+        let call_counter = ClusterCallCounter::new(cfg.sdn_peer_id.to_string(), pubsub_cluster.requester());
+        let http_hook_retry = HttpHookRetry {
+            max_retries: cfg.http_hook_max_retries,
+            base_delay: cfg.http_hook_retry_base,
+            max_delay: cfg.http_hook_retry_max,
+        };
+        let http_hook = HttpHook::new(
+            cfg.http_hook_queues,
+            http_hook_retry,
+            cfg.http_hook_limit,
+            cfg.hook_data_dir.as_deref(),
+            p2p_pubsub_call.clone(),
+            cfg.address_book.clone(),
+        )
+        .await?;
+
+        let mut cdr_sinks: Vec<Box<dyn CdrSink>> = vec![];
+        if let Some(path) = cfg.cdr_file {
+            cdr_sinks.push(Box::new(JsonLinesCdrSink::new(path)));
+        }
+        if let Some(endpoint) = &cfg.cdr_http_endpoint {
+            cdr_sinks.push(Box::new(HttpBatchCdrSink::new(endpoint, cfg.cdr_http_batch_size)));
+        }
+        let cdr_writer = CdrWriter::new(cdr_sinks, cfg.cdr_flush_interval);
 
-        let (mut http, http_rx) = HttpServer::new(cfg.http_listen, node_addr.clone(), &cfg.media_gateway, cfg.secure_ctx.clone(), p2p_pubsub_call.clone());
+        let hep = cfg.hep.map(|hep| HepExporter::new(hep, SocketAddr::new(cfg.public_ip, cfg.sip_listen.port())));
+        let sip_trace = SipTrace::new(cfg.sip_trace_capacity, cfg.sip_trace_retention, hep);
+        let drain = DrainState::default();
+        let (http_tx, http_rx) = channel(10);
+        let call_rpc = ClusterCallRpc::new(cfg.sdn_peer_id, pubsub_cluster.requester(), p2p_pubsub_call.clone(), http_tx.clone(), sip_trace.clone());
+        let mut http = HttpServer::new(
+            cfg.http_listen,
+            NodeInfo {
+                peer_id: cfg.sdn_peer_id,
+                address: node_addr,
+                sip_listen: cfg.sip_listen,
+                sip_public: SocketAddr::new(cfg.public_ip, cfg.sip_listen.port()),
+            },
+            &cfg.media_gateway,
+            cfg.secure_ctx.clone(),
+            cfg.address_book.clone(),
+            http_tx,
+            p2p_pubsub_call.clone(),
+            call_rpc.clone(),
+            http_hook.clone(),
+            drain.clone(),
+        );
         tokio::spawn(async move { http.run_loop().await });
         tokio::spawn(async move { while pubsub_call.run_loop().await.is_ok() {} });
+        tokio::spawn(async move { while pubsub_cluster.run_loop().await.is_ok() {} });
+        let (control_tx, control_rx) = channel(10);
 
         Ok(Self {
             http_rx,
-            call_manager: CallManager::new(p2p_pubsub_call, cfg.sip_listen, cfg.public_ip, cfg.address_book, cfg.secure_ctx, http_hook, &cfg.media_gateway).await,
+            control_tx,
+            control_rx,
+            call_manager: CallManager::new(
+                p2p_pubsub_call,
+                cfg.sip_listen,
+                cfg.public_ip,
+                cfg.address_book.clone(),
+                cfg.secure_ctx,
+                http_hook,
+                call_counter,
+                cdr_writer,
+                &cfg.media_gateway,
+                cfg.subscriber_grace,
+                sip_trace,
+                drain.clone(),
+                cfg.limits,
+            )
+            .await,
+            call_rpc,
             p2p,
+            address_book: cfg.address_book,
+            drain,
+            drain_timeout: cfg.drain_timeout,
+            drain_stage: None,
         })
     }
 
+    /// Stop taking new calls, the gateway is drained when active calls ended, or after the drain timeout
+    /// when remaining calls are ended by the node
+    pub fn drain(&mut self) -> NodeDrainStatus {
+        if self.drain.start() {
+            log::warn!("[Gateway] start draining with {} calls, timeout {:?}", self.call_manager.calls(), self.drain_timeout);
+            self.drain_stage = Some(DrainStage::WaitCalls(Instant::now() + self.drain_timeout));
+            let control_tx = self.control_tx.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_millis(DRAIN_CHECK_INTERVAL_MS));
+                loop {
+                    interval.tick().await;
+                    if control_tx.send(GatewayControl::DrainCheck).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        let deadline_at = match self.drain_stage {
+            Some(DrainStage::WaitCalls(deadline)) => now_ms() + deadline.saturating_duration_since(Instant::now()).as_millis() as u64,
+            _ => now_ms(),
+        };
+        NodeDrainStatus {
+            calls: self.call_manager.calls() as u32,
+            deadline_at,
+        }
+    }
+
+    /// Apply reloaded limits and static address book entries, active calls are not affected
+    pub fn reload(&mut self, cfg: &ReloadableConfig) {
+        self.call_manager.set_limits(cfg.limits.clone());
+        self.address_book.set_static(cfg.address_book.apps.clone(), cfg.address_book.numbers.clone());
+    }
+
+    /// Sender of requests which are handled inside [`Gateway::recv`]
+    pub fn control(&self) -> Sender<GatewayControl> {
+        self.control_tx.clone()
+    }
+
+    /// The process should exit after the gateway is drained
+    pub fn drained(&self) -> bool {
+        match self.drain_stage {
+            Some(DrainStage::Expired) => true,
+            Some(_) => self.call_manager.is_idle(),
+            None => false,
+        }
+    }
+
+    /// Write pending records before the process exits
+    pub async fn shutdown(self) {
+        if tokio::time::timeout(Duration::from_secs(SHUTDOWN_CDR_TIMEOUT_SECS), self.call_manager.shutdown()).await.is_err() {
+            log::warn!("[Gateway] shutdown timeout => some cdr records may be lost");
+        }
+    }
+
+    fn on_control(&mut self, control: GatewayControl) {
+        match control {
+            GatewayControl::Drain => {
+                self.drain();
+            }
+            GatewayControl::Reload(cfg) => self.reload(&cfg),
+            GatewayControl::DrainCheck => {
+                if let Some(DrainStage::WaitCalls(deadline) | DrainStage::EndCalls(deadline)) = self.drain_stage {
+                    if Instant::now() >= deadline {
+                        self.on_drain_deadline();
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_drain_deadline(&mut self) {
+        match self.drain_stage {
+            Some(DrainStage::WaitCalls(_)) => {
+                log::warn!("[Gateway] drain timeout => end {} remaining calls", self.call_manager.calls());
+                self.call_manager.end_all_calls();
+                self.drain_stage = Some(DrainStage::EndCalls(Instant::now() + Duration::from_secs(DRAIN_END_CALLS_WAIT_SECS)));
+            }
+            _ => {
+                log::warn!("[Gateway] {} calls still active after ending => exit anyway", self.call_manager.calls());
+                self.drain_stage = Some(DrainStage::Expired);
+            }
+        }
+    }
+
+    /// Signals, reloads and drain checks arrive over the control channel like api commands,
+    /// so only channel messages interrupt a pending p2p or call manager receive
     pub async fn recv(&mut self) -> Result<(), GatewayError> {
-        let out = select3::or(self.http_rx.recv(), self.p2p.recv(), self.call_manager.recv()).await;
+        let commands = select2::or(self.control_rx.recv(), self.http_rx.recv());
+        let out = select3::or(commands, self.p2p.recv(), self.call_manager.recv()).await;
         match out {
-            select3::OrOutput::Left(cmd) => match cmd.expect("internal channel error") {
-                HttpCommand::CreateCall(req, media_api, sender) => {
-                    let res = self.call_manager.create_call(req, media_api);
+            select3::OrOutput::Left(select2::OrOutput::Left(control)) => {
+                self.on_control(control.expect("internal channel error"));
+                Ok(())
+            }
+            select3::OrOutput::Left(select2::OrOutput::Right(cmd)) => match cmd.expect("internal channel error") {
+                HttpCommand::CreateCall(app_id, req, media_api, sender) => {
+                    let res = self.call_manager.create_call(app_id, req, media_api);
                     if let Err(e) = sender.send(res) {
                         log::warn!("[Gateway] sending create_call response error {e:?}");
                     }
                     Ok(())
                 }
+                HttpCommand::ListCalls(app_id, sender) => {
+                    let res = self.call_manager.list_calls(app_id.as_ref());
+                    if let Err(e) = sender.send(res) {
+                        log::warn!("[Gateway] sending list_calls response error {e:?}");
+                    }
+                    Ok(())
+                }
+                HttpCommand::GetCall(call_id, app_id, sender) => {
+                    let res = self.call_manager.get_call(&call_id, app_id.as_ref());
+                    if let Err(e) = sender.send(res) {
+                        log::warn!("[Gateway] sending get_call response error {e:?}");
+                    }
+                    Ok(())
+                }
+                HttpCommand::Drain(sender) => {
+                    let res = self.drain();
+                    if let Err(e) = sender.send(res) {
+                        log::warn!("[Gateway] sending drain response error {e:?}");
+                    }
+                    Ok(())
+                }
             },
             select3::OrOutput::Middle(out) => match out? {
                 P2pNetworkEvent::PeerConnected(_, peer_id) => {
                     log::info!("[Gateway] peer {peer_id} connected");
+                    metrics::P2P_PEERS.inc();
+                    self.call_rpc.on_peer_connected(peer_id);
                     Ok(())
                 }
                 P2pNetworkEvent::PeerDisconnected(_, peer_id) => {
                     log::info!("[Gateway] peer {peer_id} disconnected");
+                    metrics::P2P_PEERS.dec();
+                    self.call_rpc.on_peer_disconnected(peer_id);
                     Ok(())
                 }
                 P2pNetworkEvent::Continue => Ok(()),
@@ -127,7 +381,8 @@ impl Gateway {
     }
 }
 
-#[derive(Debug, Clone, ValueEnum)]
+#[derive(Debug, Clone, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum CloudProvider {
     Aws,
     Gcp,
@@ -1,16 +1,28 @@
 use std::{
     net::{IpAddr, SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 
-use atm0s_media_sip_gateway::{fetch_public_ip_from_cloud, AddressBookStorage, AddressBookSync, CloudProvider, Gateway, GatewayConfig, GatewayError, SecureContext};
-use clap::Parser;
+use atm0s_media_sip_gateway::{
+    fetch_public_ip_from_cloud, init_tracing, merge_config_value, shutdown_tracing, AddressBookStorage, AddressBookSync, CloudProvider, ConfigFile, ConfigWatcher, Gateway, GatewayConfig,
+    GatewayControl, GatewayError, HepConfig, HepTransport, HttpHookLimit, HttpHookOverflow, LogLevelHandle, SecureContext,
+};
+use clap::{error::ErrorKind, CommandFactory, FromArgMatches, Parser};
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    sync::mpsc::Sender,
+};
 
 /// Sip Gateway for atm0s-media-server
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Config file in TOML or YAML, flags and env vars override its values
+    #[arg(long, env)]
+    config: Option<PathBuf>,
+
     /// UDP/TCP port for serving QUIC/TCP connection for SDN network
     #[arg(env, long)]
     sdn_peer_id: Option<u64>,
@@ -72,20 +84,168 @@ struct Args {
     #[arg(long, env, default_value_t = 20)]
     http_hook_queues: usize,
 
-    /// MediaServer Gateway
+    /// Max retries of a hook event after the first try
+    #[arg(long, env, default_value_t = 5)]
+    http_hook_max_retries: usize,
+
+    /// Base delay of hook retry backoff, doubled after each try
+    #[arg(long, env, default_value_t = 500)]
+    http_hook_retry_base_ms: u64,
+
+    /// Max delay of hook retry backoff
+    #[arg(long, env, default_value_t = 30_000)]
+    http_hook_retry_max_ms: u64,
+
+    /// Max waiting and in flight events of each hook queue
+    #[arg(long, env, default_value_t = 10_000)]
+    http_hook_queue_capacity: usize,
+
+    /// What to do with a new hook event when its queue is full, dropped events are moved to dead letters
+    #[arg(long, env, value_enum, default_value_t = HttpHookOverflow::DropOldest)]
+    http_hook_overflow: HttpHookOverflow,
+
+    /// Max time a call waits for queue space with the block overflow policy
+    #[arg(long, env, default_value_t = 1_000)]
+    http_hook_block_timeout_ms: u64,
+
+    /// Directory for the persistent hook outbox, undelivered events and dead letters only live in memory if missing
     #[arg(long, env)]
-    media_gateway: String,
+    hook_data_dir: Option<PathBuf>,
+
+    /// MediaServer Gateway, required from flags, env or the config file
+    #[arg(long, env)]
+    media_gateway: Option<String>,
 
     /// MediaServer Apps sync endpoint
     #[arg(long, env)]
     media_app_sync: Option<String>,
+
+    /// Append call detail records as json lines to this file
+    #[arg(long, env)]
+    cdr_file: Option<PathBuf>,
+
+    /// Post call detail records in batches to this http endpoint
+    #[arg(long, env)]
+    cdr_http_endpoint: Option<String>,
+
+    /// Max call detail records in a single http batch
+    #[arg(long, env, default_value_t = 100)]
+    cdr_http_batch_size: usize,
+
+    /// Interval for flushing pending call detail records
+    #[arg(long, env, default_value_t = 5_000)]
+    cdr_flush_interval_ms: u64,
+
+    /// How long a call stays up after all websocket subscribers left, 0 ends the call immediately
+    #[arg(long, env, default_value_t = 0)]
+    subscriber_grace_ms: u64,
+
+    /// Max SIP messages kept per call for the sip-trace api, 0 disables tracing
+    #[arg(long, env, default_value_t = 100)]
+    sip_trace_capacity: usize,
+
+    /// How long SIP traces are kept after calls end, 0 drops them when the call ends
+    #[arg(long, env, default_value_t = 0)]
+    sip_trace_retention_secs: u64,
+
+    /// HEPv3 collector (ex: Homer) which receives a copy of all SIP messages, disabled if not set
+    #[arg(long, env)]
+    hep_collector: Option<SocketAddr>,
+
+    /// Transport to the HEP collector
+    #[arg(long, env, value_enum, default_value_t = HepTransport::Udp)]
+    hep_transport: HepTransport,
+
+    /// Capture agent id of this node in HEP packets
+    #[arg(long, env, default_value_t = 2001)]
+    hep_capture_id: u32,
+
+    /// Auth key of the HEP collector
+    #[arg(long, env)]
+    hep_auth_key: Option<String>,
+
+    /// OTLP gRPC collector for exporting call traces, ex: http://localhost:4317. Tracing export is disabled if not set
+    #[arg(long, env)]
+    otlp_endpoint: Option<String>,
+
+    /// Service name of exported traces
+    #[arg(long, env, default_value = "atm0s-media-sip-gateway")]
+    otlp_service_name: String,
+
+    /// How long a draining node waits for active calls to end, remaining calls are ended after it
+    #[arg(long, env, default_value_t = 60)]
+    drain_timeout_secs: u64,
+}
+
+/// Take values of the config file for args which are not set by flags or env vars.
+/// The first list is args with default values, the second list is optional args
+macro_rules! merge_config {
+    ($args:ident, $matches:ident, $file:ident, [$($field:ident),* $(,)?], [$($opt_field:ident),* $(,)?]) => {
+        $(
+            merge_config_value(&$matches, stringify!($field), &mut $args.$field, $file.$field.take());
+        )*
+        $(
+            merge_config_value(&$matches, stringify!($opt_field), &mut $args.$opt_field, $file.$opt_field.take().map(Some));
+        )*
+    };
 }
 
 #[tokio::main]
 async fn main() -> Result<(), GatewayError> {
     rustls::crypto::ring::default_provider().install_default().expect("should install ring as default");
-    tracing_subscriber::fmt::init();
-    let mut args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let config_path = args.config.clone();
+    let mut file = match &config_path {
+        Some(path) => ConfigFile::load(path).unwrap_or_else(|e| invalid_config(e.context(path.display().to_string()))),
+        None => ConfigFile::default(),
+    };
+    let reloadable = match file.reloadable() {
+        Ok(reloadable) => reloadable,
+        Err(e) => invalid_config(e),
+    };
+    merge_config!(
+        args,
+        matches,
+        file,
+        [
+            sdn_listener,
+            sdn_seeds,
+            sdn_secure_code,
+            http_listen,
+            http_public,
+            sip_listen,
+            public_ip,
+            secret,
+            sync_interval_ms,
+            http_hook_queues,
+            http_hook_max_retries,
+            http_hook_retry_base_ms,
+            http_hook_retry_max_ms,
+            http_hook_queue_capacity,
+            http_hook_overflow,
+            http_hook_block_timeout_ms,
+            cdr_http_batch_size,
+            cdr_flush_interval_ms,
+            subscriber_grace_ms,
+            sip_trace_capacity,
+            sip_trace_retention_secs,
+            hep_transport,
+            hep_capture_id,
+            otlp_service_name,
+            drain_timeout_secs,
+        ],
+        [
+            sdn_peer_id, sdn_seeds_from_url, public_ip_cloud, phone_numbers_sync, apps_sync, hook_data_dir, media_gateway, media_app_sync, cdr_file, cdr_http_endpoint, hep_collector, hep_auth_key,
+            otlp_endpoint,
+        ]
+    );
+    let Some(media_gateway) = args.media_gateway.take() else {
+        Args::command()
+            .error(ErrorKind::MissingRequiredArgument, "--media-gateway is required in flags, env or the config file")
+            .exit();
+    };
+    let log_level = init_tracing(args.otlp_endpoint.as_deref(), &args.otlp_service_name, reloadable.log_level).expect("should init tracing");
 
     let mut other_node_addr = vec![];
     if let Some(sdn_seeds_from_url) = args.sdn_seeds_from_url {
@@ -122,6 +282,7 @@ async fn main() -> Result<(), GatewayError> {
     );
 
     let address_book = AddressBookStorage::new(&args.secret);
+    address_book.set_static(reloadable.address_book.apps, reloadable.address_book.numbers);
     let secure_ctx = Arc::new(SecureContext::new(&args.secret, address_book.clone()));
 
     if let Some(phone_url) = args.phone_numbers_sync {
@@ -140,17 +301,108 @@ async fn main() -> Result<(), GatewayError> {
         sip_listen: args.sip_listen,
         address_book,
         http_hook_queues: args.http_hook_queues,
-        media_gateway: args.media_gateway,
+        http_hook_max_retries: args.http_hook_max_retries,
+        http_hook_retry_base: Duration::from_millis(args.http_hook_retry_base_ms),
+        http_hook_retry_max: Duration::from_millis(args.http_hook_retry_max_ms),
+        http_hook_limit: HttpHookLimit {
+            capacity: args.http_hook_queue_capacity,
+            overflow: args.http_hook_overflow,
+            block_timeout: Duration::from_millis(args.http_hook_block_timeout_ms),
+        },
+        hook_data_dir: args.hook_data_dir,
+        media_gateway,
         secure_ctx,
         sdn_peer_id: args.sdn_peer_id.unwrap_or_else(rand::random).into(),
         sdn_listen_addr: args.sdn_listener,
         sdn_seeds,
         sdn_secret: args.sdn_secure_code,
+        cdr_file: args.cdr_file,
+        cdr_http_endpoint: args.cdr_http_endpoint,
+        cdr_http_batch_size: args.cdr_http_batch_size,
+        cdr_flush_interval: Duration::from_millis(args.cdr_flush_interval_ms),
+        subscriber_grace: Duration::from_millis(args.subscriber_grace_ms),
+        sip_trace_capacity: args.sip_trace_capacity,
+        sip_trace_retention: Duration::from_secs(args.sip_trace_retention_secs),
+        hep: args.hep_collector.map(|collector| HepConfig {
+            collector,
+            transport: args.hep_transport,
+            capture_id: args.hep_capture_id,
+            auth_key: args.hep_auth_key,
+        }),
+        drain_timeout: Duration::from_secs(args.drain_timeout_secs),
+        limits: reloadable.limits,
     };
     let mut gateway = Gateway::new(cfg).await?;
-    loop {
+    let sigterm = signal(SignalKind::terminate())?;
+    let sighup = signal(SignalKind::hangup())?;
+    tokio::spawn(forward_signals(sigterm, sighup, config_path, log_level, gateway.control()));
+    while !gateway.drained() {
         if let Err(e) = gateway.recv().await {
             log::error!("gateway error {e:?}");
         }
     }
+
+    log::info!("gateway drained => exit");
+    gateway.shutdown().await;
+    shutdown_tracing();
+    Ok(())
+}
+
+/// Exit like clap does for invalid flags, with the parse error of the config file
+fn invalid_config(e: anyhow::Error) -> ! {
+    Args::command().error(ErrorKind::InvalidValue, format!("invalid config file {e:#}")).exit()
+}
+
+async fn wait_config_changed(watcher: &mut Option<ConfigWatcher>) {
+    match watcher {
+        Some(watcher) => watcher.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Turn signals and config file changes into gateway controls, they are handled inside the gateway loop
+async fn forward_signals(mut sigterm: Signal, mut sighup: Signal, config_path: Option<PathBuf>, log_level: LogLevelHandle, control: Sender<GatewayControl>) {
+    let mut watcher = config_path.as_deref().map(ConfigWatcher::new);
+    loop {
+        let out = tokio::select! {
+            _ = sigterm.recv() => {
+                log::info!("received SIGTERM => draining");
+                Some(GatewayControl::Drain)
+            }
+            _ = sighup.recv() => {
+                log::info!("received SIGHUP => reload config");
+                reload_config(config_path.as_deref(), &log_level)
+            }
+            _ = wait_config_changed(&mut watcher) => {
+                log::info!("config file changed => reload config");
+                reload_config(config_path.as_deref(), &log_level)
+            }
+        };
+        if let Some(out) = out {
+            if control.send(out).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Load reloadable sections of the config file, the current config is kept if the file is invalid
+fn reload_config(path: Option<&Path>, log_level: &LogLevelHandle) -> Option<GatewayControl> {
+    let Some(path) = path else {
+        log::warn!("no config file => nothing to reload");
+        return None;
+    };
+    match ConfigFile::load(path).and_then(|cfg| cfg.reloadable()) {
+        Ok(cfg) => {
+            if let Err(e) = log_level.set(cfg.log_level) {
+                log::error!("set log level {} error {e:?}", cfg.log_level);
+            }
+            log::info!("reloaded config from {}", path.display());
+            Some(GatewayControl::Reload(cfg))
+        }
+        Err(e) => {
+            log::error!("reload config from {} error {e:?} => keep current config", path.display());
+            None
+        }
+    }
 }
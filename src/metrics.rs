@@ -0,0 +1,87 @@
+use std::sync::LazyLock;
+
+use prometheus::{register_gauge, register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Gauge, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec};
+
+use crate::protocol::{
+    protobuf::sip_gateway::{call_detail_record, CallDetailRecord, CallEndReason},
+    CallDirection, CallState,
+};
+
+// Prometheus metrics of this node, served at /metrics.
+// Counters and histograms are updated where things happen, gauges of current state are refreshed on scrape.
+
+const CALL_SETUP_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+const REQUEST_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub static CALLS_ACTIVE: LazyLock<IntGaugeVec> =
+    LazyLock::new(|| register_int_gauge_vec!("sip_gateway_calls_active", "Active calls of this node", &["direction", "state"]).expect("should register metric"));
+pub static CALLS_CREATED: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!("sip_gateway_calls_created_total", "Created calls", &["direction"]).expect("should register metric"));
+pub static CALLS_ANSWERED: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!("sip_gateway_calls_answered_total", "Answered calls", &["direction"]).expect("should register metric"));
+pub static CALLS_FAILED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "sip_gateway_calls_failed_total",
+        "Calls which ended without answer, except the ones cancelled by caller",
+        &["direction", "sip_code"]
+    )
+    .expect("should register metric")
+});
+pub static CALL_SETUP_SECONDS: LazyLock<HistogramVec> =
+    LazyLock::new(|| register_histogram_vec!("sip_gateway_call_setup_seconds", "Time from call created to answered", &["direction"], CALL_SETUP_BUCKETS.to_vec()).expect("should register metric"));
+
+pub static HOOK_REQUEST_SECONDS: LazyLock<HistogramVec> =
+    LazyLock::new(|| register_histogram_vec!("sip_gateway_hook_request_seconds", "Latency of each hook delivery try", &["queue"], REQUEST_BUCKETS.to_vec()).expect("should register metric"));
+pub static HOOK_RETRIES: LazyLock<IntCounterVec> =
+    LazyLock::new(|| register_int_counter_vec!("sip_gateway_hook_retries_total", "Hook delivery tries which are retried", &["queue"]).expect("should register metric"));
+pub static HOOK_FAILURES: LazyLock<IntCounterVec> =
+    LazyLock::new(|| register_int_counter_vec!("sip_gateway_hook_failures_total", "Hook events moved to dead letters after delivery failed", &["queue"]).expect("should register metric"));
+pub static HOOK_DROPPED: LazyLock<IntCounterVec> =
+    LazyLock::new(|| register_int_counter_vec!("sip_gateway_hook_dropped_total", "Hook events dropped because the queue was full", &["queue"]).expect("should register metric"));
+pub static HOOK_QUEUE_DEPTH: LazyLock<IntGaugeVec> =
+    LazyLock::new(|| register_int_gauge_vec!("sip_gateway_hook_queue_depth", "Waiting and in flight hook events", &["queue"]).expect("should register metric"));
+
+pub static MEDIA_API_SECONDS: LazyLock<HistogramVec> =
+    LazyLock::new(|| register_histogram_vec!("sip_gateway_media_api_seconds", "Latency of media server api requests", &["method"], REQUEST_BUCKETS.to_vec()).expect("should register metric"));
+pub static MEDIA_API_ERRORS: LazyLock<IntCounterVec> =
+    LazyLock::new(|| register_int_counter_vec!("sip_gateway_media_api_errors_total", "Failed media server api requests", &["method"]).expect("should register metric"));
+
+pub static ADDRESS_BOOK_SYNCS: LazyLock<IntCounterVec> =
+    LazyLock::new(|| register_int_counter_vec!("sip_gateway_address_book_syncs_total", "Address book syncs", &["result"]).expect("should register metric"));
+pub static ADDRESS_BOOK_LAST_SYNC: LazyLock<Gauge> =
+    LazyLock::new(|| register_gauge!("sip_gateway_address_book_last_sync_timestamp_seconds", "Unix time of the last successful address book sync").expect("should register metric"));
+pub static ADDRESS_BOOK_SYNC_AGE: LazyLock<Gauge> =
+    LazyLock::new(|| register_gauge!("sip_gateway_address_book_sync_age_seconds", "Seconds since the last successful address book sync").expect("should register metric"));
+
+pub static P2P_PEERS: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!("sip_gateway_p2p_peers_connected", "Connected p2p peers").expect("should register metric"));
+
+pub fn direction_label(direction: CallDirection) -> &'static str {
+    match direction {
+        CallDirection::Outgoing => "outgoing",
+        CallDirection::Incoming => "incoming",
+    }
+}
+
+pub fn state_label(state: CallState) -> &'static str {
+    match state {
+        CallState::Calling => "calling",
+        CallState::Early => "early",
+        CallState::Wait => "wait",
+        CallState::Talking => "talking",
+        CallState::Canceling => "canceling",
+    }
+}
+
+/// Count the result of an ended call from its record
+pub fn on_call_ended(record: &CallDetailRecord) {
+    let direction = match record.direction() {
+        call_detail_record::Direction::Outgoing => "outgoing",
+        call_detail_record::Direction::Incoming => "incoming",
+    };
+    if let Some(answered_at) = record.answered_at {
+        CALLS_ANSWERED.with_label_values(&[direction]).inc();
+        CALL_SETUP_SECONDS
+            .with_label_values(&[direction])
+            .observe(answered_at.saturating_sub(record.created_at) as f64 / 1000.0);
+    } else if record.end_reason() != CallEndReason::Cancelled {
+        CALLS_FAILED.with_label_values(&[direction, &record.sip_code.to_string()]).inc();
+    }
+}
@@ -1,18 +1,25 @@
-use std::hash::{Hash, Hasher};
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+};
 
 use atm0s_small_p2p::pubsub_service::PubsubChannelId;
 use derive_more::derive::{Deref, Display, From, Into};
-use poem_openapi::Object;
+use poem_openapi::{Enum, Object, Union};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 mod address_book;
+mod hook;
 mod incoming;
+mod node;
 mod outgoing;
 pub mod protobuf;
 
 pub use address_book::*;
+pub use hook::*;
 pub use incoming::*;
+pub use node::*;
 pub use outgoing::*;
 
 /// Note that his call_id is from internal state and not a SipCallID
@@ -31,13 +38,62 @@ impl InternalCallId {
     }
 }
 
-#[derive(Debug, Clone, Object, Deserialize)]
+#[derive(Clone, Object, Deserialize)]
 pub struct SipAuth {
     pub username: String,
     pub password: String,
 }
 
-#[derive(Debug, Object, Serialize, Deserialize)]
+impl std::fmt::Debug for SipAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SipAuth").field("username", &self.username).field("password", &"***").finish()
+    }
+}
+
+/// Authentication of hook requests, secrets are redacted in Debug
+#[derive(Debug, Clone, Union, Serialize, Deserialize)]
+#[oai(discriminator_name = "type")]
+#[serde(tag = "type")]
+pub enum HookAuth {
+    #[oai(mapping = "Bearer")]
+    Bearer(HookBearerAuth),
+    #[oai(mapping = "Basic")]
+    Basic(HookBasicAuth),
+}
+
+#[derive(Clone, Object, Serialize, Deserialize)]
+pub struct HookBearerAuth {
+    pub token: String,
+}
+
+impl std::fmt::Debug for HookBearerAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookBearerAuth").field("token", &"***").finish()
+    }
+}
+
+#[derive(Clone, Object, Serialize, Deserialize)]
+pub struct HookBasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for HookBasicAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookBasicAuth").field("username", &self.username).field("password", &"***").finish()
+    }
+}
+
+/// Debug of hook headers with redacted values, since they often carry credentials
+pub struct RedactedHeaders<'a>(pub &'a HashMap<String, String>);
+
+impl std::fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.0.keys().map(|name| (name, "***"))).finish()
+    }
+}
+
+#[derive(Debug, Clone, Object, Serialize, Deserialize)]
 pub struct StreamingInfo {
     pub room: String,
     pub peer: String,
@@ -56,10 +112,70 @@ pub enum CallApiError {
     WrongToken,
     #[error("SipError {0}")]
     SipError(String),
+    #[error("CallLimitReached")]
+    CallLimitReached,
+    #[error("CallNotFound")]
+    CallNotFound,
+    #[error("NodeDraining")]
+    NodeDraining,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Enum)]
 pub enum CallDirection {
     Outgoing,
     Incoming,
 }
+
+/// Sip state of a call, outgoing calls go through Calling/Early/Talking/Canceling
+/// while incoming calls only have Wait/Talking
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Enum)]
+pub enum CallState {
+    Calling,
+    Early,
+    Wait,
+    Talking,
+    Canceling,
+}
+
+#[derive(Debug, Clone, Object, Serialize, Deserialize)]
+pub struct CallInfo {
+    pub call_id: String,
+    pub app_id: String,
+    pub direction: CallDirection,
+    pub state: CallState,
+    pub from_number: String,
+    pub to_number: String,
+    pub created_at: u64,
+    pub answered_at: Option<u64>,
+    /// Milliseconds since the call was created
+    pub duration_ms: u64,
+    /// Milliseconds since the call was answered
+    pub talk_duration_ms: Option<u64>,
+    pub streaming: Option<StreamingInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Enum)]
+pub enum SipTraceDirection {
+    Received,
+    Sent,
+}
+
+/// A SIP message of a call, as seen by the gateway
+#[derive(Debug, Clone, Object, Serialize, Deserialize)]
+pub struct SipTraceMessage {
+    /// Unix time in milliseconds
+    pub at: u64,
+    pub direction: SipTraceDirection,
+    /// Address of the other side
+    pub remote: Option<String>,
+    pub message: String,
+}
+
+/// SIP ladder of a call, oldest messages are dropped when the buffer is full
+#[derive(Debug, Clone, Object, Serialize, Deserialize)]
+pub struct CallSipTrace {
+    pub call_id: String,
+    /// Count of messages dropped from the start of the trace
+    pub dropped: u64,
+    pub messages: Vec<SipTraceMessage>,
+}
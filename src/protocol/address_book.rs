@@ -1,30 +1,152 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use atm0s_small_p2p::pubsub_service::PubsubChannelId;
 use derive_more::derive::{Deref, Display, From, Into};
 use ipnet::IpNet;
 use poem_openapi::Enum;
 use serde::{Deserialize, Serialize};
 
-use super::SipAuth;
+use super::{HookAuth, HookSubscription, RedactedHeaders, SipAuth};
 
-#[derive(Debug, Display, Clone, From, Into, Deref, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Display, Clone, Default, From, Into, Deref, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AppId(String);
 
+impl AppId {
+    /// Channel which incoming calls of the app are announced to notify sessions
+    pub fn to_notify_channel(&self) -> PubsubChannelId {
+        let mut hasher = DefaultHasher::default();
+        ("notify", &self.0).hash(&mut hasher);
+        hasher.finish().into()
+    }
+
+    /// Channel which hooks of `ws-push://{name}` urls are sent to, published by push sockets of the app
+    pub fn to_hook_push_channel(&self, name: &str) -> PubsubChannelId {
+        let mut hasher = DefaultHasher::default();
+        ("hook_push", &self.0, name).hash(&mut hasher);
+        hasher.finish().into()
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct AppInfo {
     pub app_id: String,
     pub app_secret: String,
+    /// Maximum active calls of this app across the whole cluster, unlimited if missing
+    #[serde(default)]
+    pub max_calls: Option<u32>,
+    /// How long a call stays up after all websocket subscribers left, using the gateway default if missing
+    #[serde(default)]
+    pub subscriber_grace_ms: Option<u64>,
+    /// Secret before the last rotation, hooks are also signed with it until previous_expires_at.
+    /// Set by the address book when the secret changes, if the source doesn't provide it
+    #[serde(default)]
+    pub previous_app_secret: Option<String>,
+    /// Timestamp in milliseconds, the previous secret is used until it is removed if missing
+    #[serde(default)]
+    pub previous_expires_at: Option<u64>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl AppInfo {
+    /// Secrets for signing hooks which fall back to the app secret
+    pub fn hook_secrets(&self) -> Vec<HookSecret> {
+        let current = HookSecret {
+            secret: self.app_secret.clone(),
+            expires_at: None,
+        };
+        let previous = self.previous_app_secret.as_ref().map(|secret| HookSecret {
+            secret: secret.clone(),
+            expires_at: self.previous_expires_at,
+        });
+        std::iter::once(current).chain(previous).collect()
+    }
+}
+
+#[derive(Clone, Deserialize)]
 pub struct PhoneNumber {
     pub number: String,
     pub subnets: Vec<IpNet>,
     pub auth: Option<SipAuth>,
     pub app_id: String,
-    pub hook: String,
+    /// Optional when the app handles incoming calls with notify sessions
+    #[serde(default)]
+    pub hook: Option<String>,
     pub hook_content_type: HookContentType,
+    /// Extra headers of hook requests
+    #[serde(default)]
+    pub hook_headers: HashMap<String, String>,
+    #[serde(default)]
+    pub hook_auth: Option<HookAuth>,
+    /// Extra hook destinations with event filters
+    #[serde(default)]
+    pub hook_subscriptions: Vec<HookSubscription>,
+    /// Secrets for signing hook requests, the app secret is used if empty
+    #[serde(default)]
+    pub hook_secrets: Vec<HookSecret>,
+    /// Timeout of the incoming call notify hook, 5 seconds if missing
+    #[serde(default)]
+    pub hook_timeout_ms: Option<u64>,
+    /// What to do when the notify hook fails, the call is rejected with 406 if missing
+    #[serde(default)]
+    pub hook_fallback: Option<HookFallback>,
+}
+
+impl std::fmt::Debug for PhoneNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PhoneNumber")
+            .field("number", &self.number)
+            .field("subnets", &self.subnets)
+            .field("auth", &self.auth)
+            .field("app_id", &self.app_id)
+            .field("hook", &self.hook)
+            .field("hook_content_type", &self.hook_content_type)
+            .field("hook_headers", &RedactedHeaders(&self.hook_headers))
+            .field("hook_auth", &self.hook_auth)
+            .field("hook_subscriptions", &self.hook_subscriptions)
+            .field("hook_secrets", &self.hook_secrets)
+            .field("hook_timeout_ms", &self.hook_timeout_ms)
+            .field("hook_fallback", &self.hook_fallback)
+            .finish()
+    }
+}
+
+/// Fallback of an incoming call when its notify hook times out or errors
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum HookFallback {
+    /// Reject the call with the given SIP code, between 300 and 699
+    Reject { code: u16 },
+    /// Keep ringing and wait for websocket clients or notify sessions to handle the call
+    Ring,
+    /// Accept the call into a default room
+    Accept {
+        room: String,
+        peer: String,
+        #[serde(default)]
+        record: bool,
+    },
+    /// Redirect the call to a backup SIP URI with 302 Moved Temporarily, the caller side must follow it
+    Forward { uri: String },
+}
+
+/// Hook signing secret, for rotating keep the old secret with expires_at until receivers switched to the new one
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HookSecret {
+    pub secret: String,
+    /// Timestamp in milliseconds, the secret isn't used for signing after it
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+impl std::fmt::Debug for HookSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookSecret").field("secret", &"***").field("expires_at", &self.expires_at).finish()
+    }
 }
 
-#[derive(Debug, Enum, Clone, Copy, Deserialize)]
+#[derive(Debug, Enum, Clone, Copy, Serialize, Deserialize)]
 pub enum HookContentType {
     Json,
     Protobuf,
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::hook::HookEvent;
+
+use super::{
+    protobuf::sip_gateway::{call_event, incoming_call_data::incoming_call_event, outgoing_call_data::outgoing_call_event, CallEvent},
+    HookAuth, HookContentType, RedactedHeaders,
+};
+
+#[derive(Error, Debug)]
+pub enum HookApiError {
+    #[error("WrongSecret")]
+    WrongSecret,
+    #[error("DeadLetterNotFound")]
+    DeadLetterNotFound,
+}
+
+/// Hook event which is exhausted all retries on this node
+#[derive(Debug, Object)]
+pub struct HookDeadLetter {
+    pub id: u64,
+    pub call_id: String,
+    pub endpoint: String,
+    pub content_type: HookContentType,
+    pub error: String,
+    pub failed_at: u64,
+    pub event: serde_json::Value,
+}
+
+#[derive(Debug, Object)]
+pub struct HookDeadLettersResult {
+    pub count: u32,
+}
+
+/// Hook delivery queue of this node, depth counts waiting and in flight events
+#[derive(Debug, Object)]
+pub struct HookQueueStats {
+    pub index: u32,
+    pub depth: u32,
+    pub in_flight: u32,
+    pub capacity: u32,
+    /// Events dropped because the queue was full, they are moved to dead letters
+    pub dropped: u64,
+    /// Times the queue worker was restarted after a panic
+    pub restarts: u64,
+}
+
+/// Kind of a hook event, used for filtering events of hook subscriptions.
+/// App actions which end the call (reject, cancel, terminate) are counted as Sip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum, Serialize, Deserialize)]
+pub enum HookEventKind {
+    Notify,
+    Sip,
+    Accepted,
+    Ended,
+    Error,
+    Cdr,
+}
+
+/// Extra hook destination which only receives events of selected kinds, all kinds if events is empty
+#[derive(Clone, Object, Serialize, Deserialize)]
+pub struct HookSubscription {
+    pub url: String,
+    pub content_type: HookContentType,
+    #[oai(default)]
+    #[serde(default)]
+    pub events: Vec<HookEventKind>,
+    #[oai(default)]
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub auth: Option<HookAuth>,
+}
+
+impl std::fmt::Debug for HookSubscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookSubscription")
+            .field("url", &self.url)
+            .field("content_type", &self.content_type)
+            .field("events", &self.events)
+            .field("headers", &RedactedHeaders(&self.headers))
+            .field("auth", &self.auth)
+            .finish()
+    }
+}
+
+impl HookEvent for CallEvent {
+    fn kind(&self) -> Option<HookEventKind> {
+        let kind = match self.event.as_ref()? {
+            call_event::Event::Notify(_) => HookEventKind::Notify,
+            call_event::Event::Cdr(_) => HookEventKind::Cdr,
+            call_event::Event::Outgoing(event) => match event.event.as_ref()? {
+                outgoing_call_event::Event::Sip(sip) if matches!(sip.event, Some(outgoing_call_event::sip_event::Event::Accepted(_))) => HookEventKind::Accepted,
+                outgoing_call_event::Event::Sip(_) | outgoing_call_event::Event::Cancelled(_) | outgoing_call_event::Event::Terminated(_) => HookEventKind::Sip,
+                outgoing_call_event::Event::Ended(_) => HookEventKind::Ended,
+                outgoing_call_event::Event::Err(_) => HookEventKind::Error,
+            },
+            call_event::Event::Incoming(event) => match event.event.as_ref()? {
+                incoming_call_event::Event::Sip(_) | incoming_call_event::Event::Rejected(_) => HookEventKind::Sip,
+                incoming_call_event::Event::Accepted(_) => HookEventKind::Accepted,
+                incoming_call_event::Event::Ended(_) => HookEventKind::Ended,
+                incoming_call_event::Event::Err(_) => HookEventKind::Error,
+            },
+        };
+        Some(kind)
+    }
+}
@@ -73,3 +73,16 @@ pub fn is_sip_incoming_rejected(event: &Option<incoming_call_event::Event>) -> O
         None
     }
 }
+
+#[derive(Debug, Object, Serialize, Deserialize)]
+pub struct CreateNotifyTokenRequest {
+    pub session_id: String,
+    /// Token lifetime in seconds, default 1 day
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Object, Serialize, Deserialize)]
+pub struct CreateNotifyTokenResponse {
+    pub token: String,
+    pub notify_ws: String,
+}
@@ -0,0 +1,66 @@
+use poem_openapi::Object;
+use thiserror::Error;
+
+use super::HookQueueStats;
+
+#[derive(Error, Debug)]
+pub enum NodeApiError {
+    #[error("WrongSecret")]
+    WrongSecret,
+    #[error("InternalChannel {0}")]
+    InternalChannel(String),
+}
+
+/// Drain progress of this node
+#[derive(Debug, Object)]
+pub struct NodeDrainStatus {
+    /// Calls which are still active on this node
+    pub calls: u32,
+    /// Remaining calls are ended at this time, in milliseconds
+    pub deadline_at: u64,
+}
+
+/// Status of this node, for ops dashboards
+#[derive(Debug, Object)]
+pub struct NodeStatus {
+    pub version: String,
+    /// Start time of the node, in milliseconds
+    pub started_at: u64,
+    pub uptime_secs: u64,
+    pub peer_id: String,
+    /// P2P address which other nodes use for connecting to this node
+    pub address: String,
+    pub draining: bool,
+    /// Connected p2p peers
+    pub peers: Vec<String>,
+    pub calls: NodeCallsStatus,
+    pub sip_listeners: Vec<NodeSipListener>,
+    pub address_book: AddressBookStatus,
+    pub hook_queues: Vec<HookQueueStats>,
+}
+
+/// Active calls of this node
+#[derive(Debug, Object)]
+pub struct NodeCallsStatus {
+    pub outgoing: u32,
+    pub incoming: u32,
+}
+
+#[derive(Debug, Object)]
+pub struct NodeSipListener {
+    pub transport: String,
+    pub listen: String,
+    /// Address which remote SIP servers see, used in the Contact header
+    pub public: String,
+}
+
+/// Apps and numbers which are synced to this node
+#[derive(Debug, Object)]
+pub struct AddressBookStatus {
+    pub apps: u32,
+    pub numbers: u32,
+    /// Time of the last successful sync in milliseconds, empty if never synced
+    pub last_sync_at: Option<u64>,
+    /// Error of the last sync, cleared by a successful sync
+    pub last_error: Option<String>,
+}
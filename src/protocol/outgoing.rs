@@ -1,12 +1,14 @@
+use std::collections::HashMap;
+
 use poem_openapi::{Enum, Object};
 use serde::{Deserialize, Serialize};
 
 use super::{
     protobuf::sip_gateway::outgoing_call_data::{outgoing_call_request, outgoing_call_response},
-    HookContentType, SipAuth, StreamingInfo,
+    HookAuth, HookContentType, HookSubscription, RedactedHeaders, SipAuth, StreamingInfo,
 };
 
-#[derive(Debug, Object)]
+#[derive(Object)]
 pub struct CreateCallRequest {
     pub sip_server: String,
     pub sip_proxy: Option<String>,
@@ -15,7 +17,33 @@ pub struct CreateCallRequest {
     pub to_number: String,
     pub hook: String,
     pub hook_content_type: HookContentType,
+    /// Extra headers of hook requests
+    pub hook_headers: Option<HashMap<String, String>>,
+    pub hook_auth: Option<HookAuth>,
+    /// Extra hook destinations with event filters
+    pub hook_subscriptions: Option<Vec<HookSubscription>>,
     pub streaming: StreamingInfo,
+    /// How long the call stays up after all websocket subscribers left, overriding the app setting
+    pub subscriber_grace_ms: Option<u64>,
+}
+
+impl std::fmt::Debug for CreateCallRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CreateCallRequest")
+            .field("sip_server", &self.sip_server)
+            .field("sip_proxy", &self.sip_proxy)
+            .field("sip_auth", &self.sip_auth)
+            .field("from_number", &self.from_number)
+            .field("to_number", &self.to_number)
+            .field("hook", &self.hook)
+            .field("hook_content_type", &self.hook_content_type)
+            .field("hook_headers", &self.hook_headers.as_ref().map(RedactedHeaders))
+            .field("hook_auth", &self.hook_auth)
+            .field("hook_subscriptions", &self.hook_subscriptions)
+            .field("streaming", &self.streaming)
+            .field("subscriber_grace_ms", &self.subscriber_grace_ms)
+            .finish()
+    }
 }
 
 #[derive(Debug, Object)]
@@ -56,7 +84,7 @@ impl TryFrom<outgoing_call_response::Response> for OutgoingCallActionResponse {
     fn try_from(value: outgoing_call_response::Response) -> Result<Self, Self::Error> {
         match value {
             outgoing_call_response::Response::Error(error) => Err(error.message),
-            outgoing_call_response::Response::End(_end) => Ok(OutgoingCallActionResponse {}),
+            outgoing_call_response::Response::End(_) | outgoing_call_response::Response::History(_) | outgoing_call_response::Response::Snapshot(_) => Ok(OutgoingCallActionResponse {}),
         }
     }
 }
@@ -46,7 +46,10 @@ pub mod incoming_call_data {
         pub struct Rejected {}
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
-        pub struct Ended {}
+        pub struct Ended {
+            #[prost(enumeration = "super::super::CallEndReason", tag = "1")]
+            pub reason: i32,
+        }
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, PartialEq, ::prost::Message)]
         pub struct Error {
@@ -73,7 +76,7 @@ pub mod incoming_call_data {
     pub struct IncomingCallRequest {
         #[prost(uint32, tag = "1")]
         pub req_id: u32,
-        #[prost(oneof = "incoming_call_request::Action", tags = "10, 11, 12")]
+        #[prost(oneof = "incoming_call_request::Action", tags = "10, 11, 12, 13, 14")]
         pub action: ::core::option::Option<incoming_call_request::Action>,
     }
     /// Nested message and enum types in `IncomingCallRequest`.
@@ -94,6 +97,16 @@ pub mod incoming_call_data {
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
         pub struct End {}
+        /// recent events of the call with seq greater than after_seq
+        #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct History {
+            #[prost(uint64, tag = "1")]
+            pub after_seq: u64,
+        }
+        #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct Snapshot {}
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Action {
@@ -103,6 +116,10 @@ pub mod incoming_call_data {
             Accept(Accept),
             #[prost(message, tag = "12")]
             End(End),
+            #[prost(message, tag = "13")]
+            History(History),
+            #[prost(message, tag = "14")]
+            Snapshot(Snapshot),
         }
     }
     #[derive(serde::Serialize, serde::Deserialize)]
@@ -153,7 +170,7 @@ pub mod incoming_call_data {
     pub struct IncomingCallResponse {
         #[prost(uint32, tag = "1")]
         pub req_id: u32,
-        #[prost(oneof = "incoming_call_response::Response", tags = "10, 11, 12, 13, 14")]
+        #[prost(oneof = "incoming_call_response::Response", tags = "10, 11, 12, 13, 14, 15, 16")]
         pub response: ::core::option::Option<incoming_call_response::Response>,
     }
     /// Nested message and enum types in `IncomingCallResponse`.
@@ -177,6 +194,12 @@ pub mod incoming_call_data {
             pub message: ::prost::alloc::string::String,
         }
         #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct History {
+            #[prost(message, repeated, tag = "1")]
+            pub events: ::prost::alloc::vec::Vec<super::super::CallEvent>,
+        }
+        #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Response {
             #[prost(message, tag = "10")]
@@ -189,6 +212,10 @@ pub mod incoming_call_data {
             End(End),
             #[prost(message, tag = "14")]
             Continue(Continue),
+            #[prost(message, tag = "15")]
+            History(History),
+            #[prost(message, tag = "16")]
+            Snapshot(super::super::CallSnapshot),
         }
     }
     #[derive(serde::Serialize, serde::Deserialize)]
@@ -200,6 +227,9 @@ pub mod incoming_call_data {
         Request(IncomingCallRequest),
         #[prost(message, tag = "3")]
         Response(IncomingCallResponse),
+        /// first message after websocket connected
+        #[prost(message, tag = "4")]
+        Snapshot(super::CallSnapshot),
     }
 }
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -278,7 +308,10 @@ pub mod outgoing_call_data {
         pub struct Terminated {}
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
-        pub struct Ended {}
+        pub struct Ended {
+            #[prost(enumeration = "super::super::CallEndReason", tag = "1")]
+            pub reason: i32,
+        }
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, PartialEq, ::prost::Message)]
         pub struct Error {
@@ -305,7 +338,7 @@ pub mod outgoing_call_data {
     pub struct OutgoingCallRequest {
         #[prost(uint32, tag = "1")]
         pub req_id: u32,
-        #[prost(oneof = "outgoing_call_request::Action", tags = "10")]
+        #[prost(oneof = "outgoing_call_request::Action", tags = "10, 11, 12")]
         pub action: ::core::option::Option<outgoing_call_request::Action>,
     }
     /// Nested message and enum types in `OutgoingCallRequest`.
@@ -313,11 +346,25 @@ pub mod outgoing_call_data {
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
         pub struct End {}
+        /// recent events of the call with seq greater than after_seq
+        #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct History {
+            #[prost(uint64, tag = "1")]
+            pub after_seq: u64,
+        }
+        #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct Snapshot {}
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Oneof)]
         pub enum Action {
             #[prost(message, tag = "10")]
             End(End),
+            #[prost(message, tag = "11")]
+            History(History),
+            #[prost(message, tag = "12")]
+            Snapshot(Snapshot),
         }
     }
     #[derive(serde::Serialize, serde::Deserialize)]
@@ -325,7 +372,7 @@ pub mod outgoing_call_data {
     pub struct OutgoingCallResponse {
         #[prost(uint32, tag = "1")]
         pub req_id: u32,
-        #[prost(oneof = "outgoing_call_response::Response", tags = "10, 11")]
+        #[prost(oneof = "outgoing_call_response::Response", tags = "10, 11, 12, 13")]
         pub response: ::core::option::Option<outgoing_call_response::Response>,
     }
     /// Nested message and enum types in `OutgoingCallResponse`.
@@ -340,12 +387,22 @@ pub mod outgoing_call_data {
             pub message: ::prost::alloc::string::String,
         }
         #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct History {
+            #[prost(message, repeated, tag = "1")]
+            pub events: ::prost::alloc::vec::Vec<super::super::CallEvent>,
+        }
+        #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Response {
             #[prost(message, tag = "10")]
             Error(Error),
             #[prost(message, tag = "11")]
             End(End),
+            #[prost(message, tag = "12")]
+            History(History),
+            #[prost(message, tag = "13")]
+            Snapshot(super::super::CallSnapshot),
         }
     }
     #[derive(serde::Serialize, serde::Deserialize)]
@@ -357,6 +414,9 @@ pub mod outgoing_call_data {
         Request(OutgoingCallRequest),
         #[prost(message, tag = "3")]
         Response(OutgoingCallResponse),
+        /// first message after websocket connected
+        #[prost(message, tag = "4")]
+        Snapshot(super::CallSnapshot),
     }
 }
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -418,12 +478,92 @@ pub mod incoming_call_notify {
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CallDetailRecord {
+    #[prost(string, tag = "1")]
+    pub call_id: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub app_id: ::prost::alloc::string::String,
+    #[prost(enumeration = "call_detail_record::Direction", tag = "3")]
+    pub direction: i32,
+    #[prost(string, tag = "4")]
+    pub from: ::prost::alloc::string::String,
+    #[prost(string, tag = "5")]
+    pub to: ::prost::alloc::string::String,
+    #[prost(string, tag = "6")]
+    pub trunk: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "7")]
+    pub created_at: u64,
+    #[prost(uint64, optional, tag = "8")]
+    pub ringing_at: ::core::option::Option<u64>,
+    #[prost(uint64, optional, tag = "9")]
+    pub answered_at: ::core::option::Option<u64>,
+    #[prost(uint64, tag = "10")]
+    pub ended_at: u64,
+    #[prost(uint32, tag = "11")]
+    pub sip_code: u32,
+    #[prost(enumeration = "CallEndReason", tag = "12")]
+    pub end_reason: i32,
+    #[prost(enumeration = "CallParty", tag = "13")]
+    pub hangup_by: i32,
+    #[prost(string, tag = "14")]
+    pub room: ::prost::alloc::string::String,
+    #[prost(string, tag = "15")]
+    pub peer: ::prost::alloc::string::String,
+    /// path of the call SIP trace on the node which handled it, only set when traces are kept after calls end
+    #[prost(string, optional, tag = "16")]
+    pub sip_trace: ::core::option::Option<::prost::alloc::string::String>,
+}
+/// Nested message and enum types in `CallDetailRecord`.
+pub mod call_detail_record {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum Direction {
+        Outgoing = 0,
+        Incoming = 1,
+    }
+    impl Direction {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Outgoing => "DIRECTION_OUTGOING",
+                Self::Incoming => "DIRECTION_INCOMING",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "DIRECTION_OUTGOING" => Some(Self::Outgoing),
+                "DIRECTION_INCOMING" => Some(Self::Incoming),
+                _ => None,
+            }
+        }
+    }
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CallEvent {
     #[prost(uint64, tag = "1")]
     pub timestamp: u64,
     #[prost(string, tag = "2")]
     pub call_id: ::prost::alloc::string::String,
-    #[prost(oneof = "call_event::Event", tags = "10, 11, 12")]
+    /// sequence of the event inside the call starting from 1, 0 for events which are not published to call channel
+    #[prost(uint64, tag = "3")]
+    pub seq: u64,
+    #[prost(oneof = "call_event::Event", tags = "10, 11, 12, 13")]
     pub event: ::core::option::Option<call_event::Event>,
 }
 /// Nested message and enum types in `CallEvent`.
@@ -437,5 +577,244 @@ pub mod call_event {
         Outgoing(super::outgoing_call_data::OutgoingCallEvent),
         #[prost(message, tag = "12")]
         Incoming(super::incoming_call_data::IncomingCallEvent),
+        #[prost(message, tag = "13")]
+        Cdr(super::CallDetailRecord),
+    }
+}
+/// current state and recent events of a call
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CallSnapshot {
+    #[prost(enumeration = "call_snapshot::State", tag = "1")]
+    pub state: i32,
+    #[prost(uint64, tag = "2")]
+    pub created_at: u64,
+    #[prost(uint64, optional, tag = "3")]
+    pub answered_at: ::core::option::Option<u64>,
+    #[prost(message, repeated, tag = "4")]
+    pub events: ::prost::alloc::vec::Vec<CallEvent>,
+}
+/// Nested message and enum types in `CallSnapshot`.
+pub mod call_snapshot {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum State {
+        Calling = 0,
+        Early = 1,
+        Wait = 2,
+        Talking = 3,
+        Canceling = 4,
+    }
+    impl State {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Calling => "STATE_CALLING",
+                Self::Early => "STATE_EARLY",
+                Self::Wait => "STATE_WAIT",
+                Self::Talking => "STATE_TALKING",
+                Self::Canceling => "STATE_CANCELING",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "STATE_CALLING" => Some(Self::Calling),
+                "STATE_EARLY" => Some(Self::Early),
+                "STATE_WAIT" => Some(Self::Wait),
+                "STATE_TALKING" => Some(Self::Talking),
+                "STATE_CANCELING" => Some(Self::Canceling),
+                _ => None,
+            }
+        }
+    }
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct NotifySessionData {
+    #[prost(oneof = "notify_session_data::Data", tags = "1, 2, 3, 4")]
+    pub data: ::core::option::Option<notify_session_data::Data>,
+}
+/// Nested message and enum types in `NotifySessionData`.
+pub mod notify_session_data {
+    /// subscribe to numbers of the app, empty numbers means the whole app
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Subscribe {
+        #[prost(string, repeated, tag = "1")]
+        pub numbers: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    }
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct CallRequest {
+        #[prost(string, tag = "1")]
+        pub call_id: ::prost::alloc::string::String,
+        #[prost(message, optional, tag = "2")]
+        pub request: ::core::option::Option<super::incoming_call_data::IncomingCallRequest>,
+    }
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct CallResponse {
+        #[prost(string, tag = "1")]
+        pub call_id: ::prost::alloc::string::String,
+        #[prost(message, optional, tag = "2")]
+        pub response: ::core::option::Option<super::incoming_call_data::IncomingCallResponse>,
+    }
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Data {
+        #[prost(message, tag = "1")]
+        Subscribe(Subscribe),
+        #[prost(message, tag = "2")]
+        Event(super::CallEvent),
+        #[prost(message, tag = "3")]
+        Request(CallRequest),
+        #[prost(message, tag = "4")]
+        Response(CallResponse),
+    }
+}
+/// Frames of the hook push WebSocket, which receives the hooks of ws-push:// urls
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HookPushData {
+    #[prost(oneof = "hook_push_data::Data", tags = "1, 2")]
+    pub data: ::core::option::Option<hook_push_data::Data>,
+}
+/// Nested message and enum types in `HookPushData`.
+pub mod hook_push_data {
+    /// gateway to app, the app must answer with a response of the same id
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Request {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(message, optional, tag = "2")]
+        pub event: ::core::option::Option<super::CallEvent>,
+        /// the event is the arrived notify of an incoming call and the response must contain the call action
+        #[prost(bool, tag = "3")]
+        pub notify: bool,
+    }
+    /// app to gateway
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Response {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(message, optional, tag = "2")]
+        pub notify_response: ::core::option::Option<super::incoming_call_data::IncomingCallNotifyResponse>,
+    }
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Data {
+        #[prost(message, tag = "1")]
+        Request(Request),
+        #[prost(message, tag = "2")]
+        Response(Response),
+    }
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum CallEndReason {
+    Unspecified = 0,
+    /// call was answered then terminated by one side
+    Hangup = 1,
+    /// caller cancelled before the call was answered
+    Cancelled = 2,
+    /// callee or app rejected the call
+    Rejected = 3,
+    /// remote sip server responded with a failure code
+    SipFailure = 4,
+    /// gateway internal error, media error or hook error
+    Error = 5,
+    /// all websocket subscribers left and none reconnected in the grace period
+    SubscribersLost = 6,
+    /// notify hook failed and the call was rejected by the hook fallback
+    HookFallbackRejected = 7,
+    /// notify hook failed and the call was redirected by the hook fallback
+    HookFallbackForwarded = 8,
+}
+impl CallEndReason {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Unspecified => "CALL_END_REASON_UNSPECIFIED",
+            Self::Hangup => "CALL_END_REASON_HANGUP",
+            Self::Cancelled => "CALL_END_REASON_CANCELLED",
+            Self::Rejected => "CALL_END_REASON_REJECTED",
+            Self::SipFailure => "CALL_END_REASON_SIP_FAILURE",
+            Self::Error => "CALL_END_REASON_ERROR",
+            Self::SubscribersLost => "CALL_END_REASON_SUBSCRIBERS_LOST",
+            Self::HookFallbackRejected => "CALL_END_REASON_HOOK_FALLBACK_REJECTED",
+            Self::HookFallbackForwarded => "CALL_END_REASON_HOOK_FALLBACK_FORWARDED",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "CALL_END_REASON_UNSPECIFIED" => Some(Self::Unspecified),
+            "CALL_END_REASON_HANGUP" => Some(Self::Hangup),
+            "CALL_END_REASON_CANCELLED" => Some(Self::Cancelled),
+            "CALL_END_REASON_REJECTED" => Some(Self::Rejected),
+            "CALL_END_REASON_SIP_FAILURE" => Some(Self::SipFailure),
+            "CALL_END_REASON_ERROR" => Some(Self::Error),
+            "CALL_END_REASON_SUBSCRIBERS_LOST" => Some(Self::SubscribersLost),
+            "CALL_END_REASON_HOOK_FALLBACK_REJECTED" => Some(Self::HookFallbackRejected),
+            "CALL_END_REASON_HOOK_FALLBACK_FORWARDED" => Some(Self::HookFallbackForwarded),
+            _ => None,
+        }
+    }
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum CallParty {
+    Unspecified = 0,
+    /// remote sip side
+    Sip = 1,
+    /// app side, over http api or websocket
+    App = 2,
+    /// gateway itself
+    Gateway = 3,
+}
+impl CallParty {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Unspecified => "CALL_PARTY_UNSPECIFIED",
+            Self::Sip => "CALL_PARTY_SIP",
+            Self::App => "CALL_PARTY_APP",
+            Self::Gateway => "CALL_PARTY_GATEWAY",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "CALL_PARTY_UNSPECIFIED" => Some(Self::Unspecified),
+            "CALL_PARTY_SIP" => Some(Self::Sip),
+            "CALL_PARTY_APP" => Some(Self::App),
+            "CALL_PARTY_GATEWAY" => Some(Self::Gateway),
+            _ => None,
+        }
     }
 }
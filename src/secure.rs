@@ -7,6 +7,7 @@ use crate::{
 };
 
 const CALL_ISSUER: &str = "call";
+const NOTIFY_ISSUER: &str = "notify";
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct CallToken {
@@ -14,6 +15,12 @@ pub struct CallToken {
     pub call_id: InternalCallId,
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct NotifyToken {
+    pub app_id: AppId,
+    pub session_id: String,
+}
+
 pub struct SecureContext {
     address_book: AddressBookStorage,
     key: HS256Key,
@@ -32,6 +39,16 @@ impl SecureContext {
         Some(app.app_id.into())
     }
 
+    /// Returns None for root secret, which is allowed to access all apps
+    pub fn check_secret_scope(&self, secret: &str) -> Option<Option<AppId>> {
+        let app_id = self.check_secret(secret)?;
+        if self.address_book.is_root_secret(secret) {
+            Some(None)
+        } else {
+            Some(Some(app_id))
+        }
+    }
+
     pub fn encode_call_token(&self, token: CallToken, duration_secs: u64) -> String {
         self.encode_token(token, CALL_ISSUER, duration_secs)
     }
@@ -40,6 +57,14 @@ impl SecureContext {
         self.decode_token(token, CALL_ISSUER)
     }
 
+    pub fn encode_notify_token(&self, token: NotifyToken, duration_secs: u64) -> String {
+        self.encode_token(token, NOTIFY_ISSUER, duration_secs)
+    }
+
+    pub fn decode_notify_token(&self, token: &str) -> Option<NotifyToken> {
+        self.decode_token(token, NOTIFY_ISSUER)
+    }
+
     fn encode_token<T: Serialize + DeserializeOwned>(&self, token: T, issuer: &str, duration_secs: u64) -> String {
         let claims = Claims::with_custom_claims(token, Duration::from_secs(duration_secs)).with_issuer(issuer);
         self.key.authenticate(claims).expect("Should create jwt")
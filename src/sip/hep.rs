@@ -0,0 +1,307 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+    time::Instant,
+};
+
+use crate::protocol::SipTraceDirection;
+
+/// Packets waiting for the collector, newer packets are dropped when it's full
+const HEP_QUEUE_SIZE: usize = 1000;
+const TCP_CONNECT_TIMEOUT_SECONDS: u64 = 2;
+const TCP_RECONNECT_DELAY_SECONDS: u64 = 5;
+
+// HEPv3 chunk types, all with vendor id 0 (generic)
+const CHUNK_IP_FAMILY: u16 = 0x0001;
+const CHUNK_IP_PROTOCOL: u16 = 0x0002;
+const CHUNK_IPV4_SRC: u16 = 0x0003;
+const CHUNK_IPV4_DST: u16 = 0x0004;
+const CHUNK_IPV6_SRC: u16 = 0x0005;
+const CHUNK_IPV6_DST: u16 = 0x0006;
+const CHUNK_SRC_PORT: u16 = 0x0007;
+const CHUNK_DST_PORT: u16 = 0x0008;
+const CHUNK_TIMESTAMP_SEC: u16 = 0x0009;
+const CHUNK_TIMESTAMP_USEC: u16 = 0x000a;
+const CHUNK_PROTOCOL_TYPE: u16 = 0x000b;
+const CHUNK_CAPTURE_ID: u16 = 0x000c;
+const CHUNK_AUTH_KEY: u16 = 0x000e;
+const CHUNK_PAYLOAD: u16 = 0x000f;
+const CHUNK_CORRELATION_ID: u16 = 0x0011;
+
+const AF_INET: u8 = 2;
+const AF_INET6: u8 = 10;
+const IPPROTO_UDP: u8 = 17;
+const PROTOCOL_SIP: u8 = 1;
+
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HepTransport {
+    Udp,
+    Tcp,
+}
+
+#[derive(Debug, Clone)]
+pub struct HepConfig {
+    pub collector: SocketAddr,
+    pub transport: HepTransport,
+    /// Identifies this node in the collector
+    pub capture_id: u32,
+    pub auth_key: Option<String>,
+}
+
+/// A SIP message as it goes over the wire
+struct HepPacket<'a> {
+    src: SocketAddr,
+    dst: SocketAddr,
+    time: SystemTime,
+    correlation_id: &'a str,
+    payload: &'a [u8],
+}
+
+/// Mirrors SIP messages of the endpoint to a HEPv3 collector like Homer.
+///
+/// Packets are sent from a background task, so a slow or down collector never delays SIP processing.
+#[derive(Clone)]
+pub struct HepExporter {
+    local: SocketAddr,
+    capture_id: u32,
+    auth_key: Option<String>,
+    tx: Sender<Vec<u8>>,
+}
+
+impl HepExporter {
+    /// `local` is the SIP address which remote sides see, used as source of sent messages
+    pub fn new(cfg: HepConfig, local: SocketAddr) -> Self {
+        log::info!("[HepExporter] mirror sip messages of {local} to {:?} collector {}", cfg.transport, cfg.collector);
+        let (tx, rx) = channel(HEP_QUEUE_SIZE);
+        tokio::spawn(run_sender(cfg.collector, cfg.transport, rx));
+        Self {
+            local,
+            capture_id: cfg.capture_id,
+            auth_key: cfg.auth_key,
+            tx,
+        }
+    }
+
+    /// The message is sent as captured on the wire, without correlation id when it has no Call-ID
+    pub fn send(&self, direction: SipTraceDirection, remote: SocketAddr, correlation_id: &str, message: &[u8]) {
+        let (src, dst) = match direction {
+            SipTraceDirection::Received => (remote, self.local),
+            SipTraceDirection::Sent => (self.local, remote),
+        };
+        let packet = HepPacket {
+            src,
+            dst,
+            time: SystemTime::now(),
+            correlation_id,
+            payload: message,
+        };
+        let Some(encoded) = encode_hep3(&packet, self.capture_id, self.auth_key.as_deref()) else {
+            log::warn!("[HepExporter] message of {} bytes doesn't fit a HEPv3 packet => drop packet of {correlation_id}", message.len());
+            return;
+        };
+        match self.tx.try_send(encoded) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => log::warn!("[HepExporter] queue full => drop packet of {correlation_id}"),
+            Err(TrySendError::Closed(_)) => log::error!("[HepExporter] sender task stopped => drop packet of {correlation_id}"),
+        }
+    }
+}
+
+/// Encode a HEPv3 packet, IPv4 addresses are mapped to IPv6 when the other side is IPv6.
+/// Returns None when the packet exceeds the 16 bits total length
+fn encode_hep3(packet: &HepPacket, capture_id: u32, auth_key: Option<&str>) -> Option<Vec<u8>> {
+    let mut buf = Vec::with_capacity(packet.payload.len() + 128);
+    buf.extend_from_slice(b"HEP3");
+    buf.extend_from_slice(&[0, 0]); // total length, set at the end
+
+    match (packet.src.ip(), packet.dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            push_chunk(&mut buf, CHUNK_IP_FAMILY, &[AF_INET]);
+            push_chunk(&mut buf, CHUNK_IPV4_SRC, &src.octets());
+            push_chunk(&mut buf, CHUNK_IPV4_DST, &dst.octets());
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            push_chunk(&mut buf, CHUNK_IP_FAMILY, &[AF_INET6]);
+            push_chunk(&mut buf, CHUNK_IPV6_SRC, &to_v6(src).octets());
+            push_chunk(&mut buf, CHUNK_IPV6_DST, &to_v6(dst).octets());
+        }
+    }
+    push_chunk(&mut buf, CHUNK_IP_PROTOCOL, &[IPPROTO_UDP]);
+    push_chunk(&mut buf, CHUNK_SRC_PORT, &packet.src.port().to_be_bytes());
+    push_chunk(&mut buf, CHUNK_DST_PORT, &packet.dst.port().to_be_bytes());
+
+    let since_epoch = packet.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    push_chunk(&mut buf, CHUNK_TIMESTAMP_SEC, &(since_epoch.as_secs() as u32).to_be_bytes());
+    push_chunk(&mut buf, CHUNK_TIMESTAMP_USEC, &since_epoch.subsec_micros().to_be_bytes());
+    push_chunk(&mut buf, CHUNK_PROTOCOL_TYPE, &[PROTOCOL_SIP]);
+    push_chunk(&mut buf, CHUNK_CAPTURE_ID, &capture_id.to_be_bytes());
+    if let Some(auth_key) = auth_key {
+        push_chunk(&mut buf, CHUNK_AUTH_KEY, auth_key.as_bytes());
+    }
+    if !packet.correlation_id.is_empty() {
+        push_chunk(&mut buf, CHUNK_CORRELATION_ID, packet.correlation_id.as_bytes());
+    }
+    push_chunk(&mut buf, CHUNK_PAYLOAD, packet.payload);
+
+    let len = u16::try_from(buf.len()).ok()?;
+    buf[4..6].copy_from_slice(&len.to_be_bytes());
+    Some(buf)
+}
+
+/// A chunk length only wraps when the chunk alone exceeds the total length, so such packets are rejected by encode_hep3
+fn push_chunk(buf: &mut Vec<u8>, chunk_type: u16, value: &[u8]) {
+    buf.extend_from_slice(&0u16.to_be_bytes());
+    buf.extend_from_slice(&chunk_type.to_be_bytes());
+    buf.extend_from_slice(&((value.len() + 6) as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+async fn run_sender(collector: SocketAddr, transport: HepTransport, mut rx: Receiver<Vec<u8>>) {
+    match transport {
+        HepTransport::Udp => {
+            let bind_addr = match collector {
+                SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+                SocketAddr::V6(_) => "[::]:0".parse().expect("should parse ipv6 any address"),
+            };
+            let socket = match UdpSocket::bind(bind_addr).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    log::error!("[HepExporter] bind udp socket error {e:?} => stop exporting");
+                    return;
+                }
+            };
+            while let Some(packet) = rx.recv().await {
+                if let Err(e) = socket.send_to(&packet, collector).await {
+                    log::warn!("[HepExporter] send to {collector} error {e:?}");
+                }
+            }
+        }
+        HepTransport::Tcp => {
+            let mut stream: Option<TcpStream> = None;
+            // packets are dropped while the collector is down, instead of connecting for each of them
+            let mut retry_at = Instant::now();
+            while let Some(packet) = rx.recv().await {
+                if stream.is_none() {
+                    if Instant::now() < retry_at {
+                        continue;
+                    }
+                    match tokio::time::timeout(Duration::from_secs(TCP_CONNECT_TIMEOUT_SECONDS), TcpStream::connect(collector)).await {
+                        Ok(Ok(connected)) => {
+                            log::info!("[HepExporter] connected to {collector}");
+                            stream = Some(connected);
+                        }
+                        Ok(Err(e)) => log::warn!("[HepExporter] connect to {collector} error {e:?}"),
+                        Err(_) => log::warn!("[HepExporter] connect to {collector} timeout"),
+                    }
+                }
+                let Some(connected) = stream.as_mut() else {
+                    retry_at = Instant::now() + Duration::from_secs(TCP_RECONNECT_DELAY_SECONDS);
+                    continue;
+                };
+                if let Err(e) = connected.write_all(&packet).await {
+                    log::warn!("[HepExporter] send to {collector} error {e:?} => reconnect");
+                    stream = None;
+                    retry_at = Instant::now() + Duration::from_secs(TCP_RECONNECT_DELAY_SECONDS);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use tokio::net::UdpSocket;
+
+    use crate::protocol::SipTraceDirection;
+
+    use super::{encode_hep3, HepConfig, HepExporter, HepPacket, HepTransport};
+
+    /// Find a chunk value by type, chunks start after the 6 bytes header
+    fn chunk(packet: &[u8], chunk_type: u16) -> Option<&[u8]> {
+        let mut pos = 6;
+        while pos + 6 <= packet.len() {
+            let found_type = u16::from_be_bytes([packet[pos + 2], packet[pos + 3]]);
+            let len = u16::from_be_bytes([packet[pos + 4], packet[pos + 5]]) as usize;
+            if found_type == chunk_type {
+                return Some(&packet[pos + 6..pos + len]);
+            }
+            pos += len;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn export_to_local_udp_collector() {
+        let collector = UdpSocket::bind("127.0.0.1:0").await.expect("should bind collector");
+        let cfg = HepConfig {
+            collector: collector.local_addr().expect("should have local addr"),
+            transport: HepTransport::Udp,
+            capture_id: 2001,
+            auth_key: None,
+        };
+        let exporter = HepExporter::new(cfg, "10.0.0.1:5060".parse().expect("should parse"));
+        exporter.send(
+            SipTraceDirection::Sent,
+            "10.0.0.2:5070".parse().expect("should parse"),
+            "call-1@host",
+            b"INVITE sip:100@10.0.0.2 SIP/2.0\r\n\r\n",
+        );
+
+        let mut buf = vec![0; 2048];
+        let len = tokio::time::timeout(Duration::from_secs(2), collector.recv(&mut buf))
+            .await
+            .expect("should receive before timeout")
+            .expect("should receive packet");
+        let packet = &buf[..len];
+
+        assert_eq!(&packet[0..4], b"HEP3");
+        assert_eq!(u16::from_be_bytes([packet[4], packet[5]]) as usize, len);
+        assert_eq!(chunk(packet, 0x0003), Some(&[10, 0, 0, 1][..]));
+        assert_eq!(chunk(packet, 0x0004), Some(&[10, 0, 0, 2][..]));
+        assert_eq!(chunk(packet, 0x0007), Some(&5060u16.to_be_bytes()[..]));
+        assert_eq!(chunk(packet, 0x0008), Some(&5070u16.to_be_bytes()[..]));
+        assert_eq!(chunk(packet, 0x000c), Some(&2001u32.to_be_bytes()[..]));
+        assert_eq!(chunk(packet, 0x0011), Some(&b"call-1@host"[..]));
+        assert_eq!(chunk(packet, 0x000f), Some(&b"INVITE sip:100@10.0.0.2 SIP/2.0\r\n\r\n"[..]));
+    }
+
+    #[test]
+    fn encode_rejects_packets_over_total_length() {
+        let encode = |payload: &[u8]| {
+            let packet = HepPacket {
+                src: "10.0.0.1:5060".parse().expect("should parse"),
+                dst: "10.0.0.2:5060".parse().expect("should parse"),
+                time: SystemTime::now(),
+                correlation_id: "call-1@host",
+                payload,
+            };
+            encode_hep3(&packet, 2001, Some("key"))
+        };
+        let overhead = encode(&[]).expect("should encode empty payload").len();
+
+        let max = vec![b'a'; u16::MAX as usize - overhead];
+        let packet = encode(&max).expect("should encode max payload");
+        assert_eq!(packet.len(), u16::MAX as usize);
+        assert_eq!(u16::from_be_bytes([packet[4], packet[5]]), u16::MAX);
+        assert_eq!(chunk(&packet, 0x000f).map(|payload| payload.len()), Some(max.len()));
+
+        assert!(encode(&vec![b'a'; max.len() + 1]).is_none());
+        // a datagram of the largest size which the transport accepts
+        assert!(encode(&vec![b'a'; u16::MAX as usize]).is_none());
+    }
+}
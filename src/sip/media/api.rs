@@ -1,8 +1,13 @@
-use std::time::Duration;
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
 
 use serde::Deserialize;
 use thiserror::Error;
 
+use crate::metrics;
+
 #[derive(Debug, Error)]
 pub enum MediaApiError {
     #[error("HttpError ({0})")]
@@ -44,38 +49,25 @@ impl MediaApi {
     }
 
     pub async fn create_rtpengine_token(&self, room: &str, peer: &str, record: bool) -> Result<String> {
-        let res: CreateTokenResponse = reqwest::ClientBuilder::new()
-            .timeout(Duration::from_secs(3))
-            .build()
-            .expect("Should create client")
-            .post(format!("{}/token/rtpengine", self.gateway))
-            .header("Authorization", format!("Bearer {}", self.app_secret))
-            .json(&serde_json::json!({
-                "room": room,
-                "peer": peer,
-                "ttl": 3600,
-                "record": record
-            }))
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
-
-        if let Some(data) = res.data {
-            Ok(data.token)
-        } else {
-            Err(MediaApiError::Media(res.error.unwrap_or_else(|| "Unknown".to_string())))
-        }
+        self.create_token("rtpengine", room, peer, record).await
     }
 
     #[allow(unused)]
     pub async fn create_webrtc_token(&self, room: &str, peer: &str, record: bool) -> Result<String> {
+        self.create_token("webrtc", room, peer, record).await
+    }
+
+    #[tracing::instrument(name = "media_create_token", skip(self))]
+    async fn create_token(&self, kind: &str, room: &str, peer: &str, record: bool) -> Result<String> {
+        observe_request(&format!("token_{kind}"), self.request_token(kind, room, peer, record)).await
+    }
+
+    async fn request_token(&self, kind: &str, room: &str, peer: &str, record: bool) -> Result<String> {
         let res: CreateTokenResponse = reqwest::ClientBuilder::new()
             .timeout(Duration::from_secs(3))
             .build()
             .expect("Should create client")
-            .post(format!("{}/token/webrtc", self.gateway))
+            .post(format!("{}/token/{kind}", self.gateway))
             .header("Authorization", format!("Bearer {}", self.app_secret))
             .json(&serde_json::json!({
                 "room": room,
@@ -96,3 +88,14 @@ impl MediaApi {
         }
     }
 }
+
+/// Record latency and failure of a media server request with the method label
+pub(super) async fn observe_request<T, E>(method: &str, request: impl Future<Output = std::result::Result<T, E>>) -> std::result::Result<T, E> {
+    let started = Instant::now();
+    let res = request.await;
+    metrics::MEDIA_API_SECONDS.with_label_values(&[method]).observe(started.elapsed().as_secs_f64());
+    if res.is_err() {
+        metrics::MEDIA_API_ERRORS.with_label_values(&[method]).inc();
+    }
+    res
+}
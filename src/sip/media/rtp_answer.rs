@@ -4,7 +4,7 @@ use bytes::Bytes;
 
 use crate::protocol::StreamingInfo;
 
-use super::{MediaApi, MediaEngineError};
+use super::{api::observe_request, MediaApi, MediaEngineError};
 
 pub struct MediaRtpEngineAnswer {
     api: MediaApi,
@@ -17,12 +17,20 @@ impl MediaRtpEngineAnswer {
         Self { api, offer, created: None }
     }
 
+    #[tracing::instrument(name = "rtpengine_answer", skip_all, fields(room = %stream.room, peer = %stream.peer))]
     pub async fn create_answer(&mut self, stream: &StreamingInfo) -> Result<Bytes, MediaEngineError> {
         assert!(self.created.is_none(), "should not call create_answer twice");
         log::info!("[MediaRtpEngineAnswer] creating token");
         let token = self.api.create_rtpengine_token(&stream.room, &stream.peer, stream.record).await?;
         log::info!("[MediaRtpEngineAnswer] created token");
         log::info!("[MediaRtpEngineAnswer] creating answer");
+        let (location, sdp) = observe_request("rtpengine_answer", self.request_answer(&token)).await?;
+        log::info!("[MediaRtpEngineAnswer] created answer {location}");
+        self.created = Some((location, sdp.clone()));
+        Ok(sdp)
+    }
+
+    async fn request_answer(&self, token: &str) -> Result<(String, Bytes), MediaEngineError> {
         let res = reqwest::ClientBuilder::new()
             .timeout(Duration::from_secs(3))
             .build()
@@ -40,9 +48,7 @@ impl MediaRtpEngineAnswer {
             let endpoint = res.headers().get("Location").ok_or(MediaEngineError::MissingLocation)?;
             let location = endpoint.to_str().map_err(|_e| MediaEngineError::InvalidLocation)?.to_string();
             let sdp: Bytes = res.bytes().await?;
-            log::info!("[MediaRtpEngineAnswer] created answer {location}");
-            self.created = Some((location, sdp.clone()));
-            Ok(sdp)
+            Ok((location, sdp))
         } else {
             let response = res.text().await?;
             log::error!("[MediaRtpEngineAnswer] create answer error {status}, {response}");
@@ -55,7 +61,7 @@ impl Drop for MediaRtpEngineAnswer {
     fn drop(&mut self) {
         if let Some((location, _)) = self.created.take() {
             let url = format!("{}{}", self.api.gateway(), location);
-            tokio::spawn(async move {
+            tokio::spawn(observe_request("rtpengine_delete", async move {
                 log::info!("[MediaRtpEngineAnswer] destroying {url}");
                 let res = reqwest::ClientBuilder::new()
                     .timeout(Duration::from_secs(3))
@@ -73,7 +79,7 @@ impl Drop for MediaRtpEngineAnswer {
                     log::error!("[MediaRtpEngineAnswer] destroy error {url} {status}");
                     Err(MediaEngineError::InvalidStatus(status))
                 }
-            });
+            }));
         }
     }
 }
@@ -4,7 +4,7 @@ use bytes::Bytes;
 
 use crate::protocol::StreamingInfo;
 
-use super::{MediaApi, MediaEngineError};
+use super::{api::observe_request, MediaApi, MediaEngineError};
 
 pub struct MediaRtpEngineOffer {
     api: MediaApi,
@@ -31,12 +31,20 @@ impl MediaRtpEngineOffer {
         self.answered
     }
 
+    #[tracing::instrument(name = "rtpengine_offer", skip_all, fields(room = %self.stream.room, peer = %self.stream.peer))]
     pub async fn create_offer(&mut self) -> Result<Bytes, MediaEngineError> {
         assert!(self.offer.is_none(), "should not call create_offer twice");
         log::info!("[RtpEngineOffer] creating token");
         let token = self.api.create_rtpengine_token(&self.stream.room, &self.stream.peer, self.stream.record).await?;
         log::info!("[RtpEngineOffer] created token");
         log::info!("[RtpEngineOffer] creating offer");
+        let (location, sdp) = observe_request("rtpengine_offer", self.request_offer(&token)).await?;
+        log::info!("[RtpEngineOffer] created offer {location}");
+        self.offer = Some((location, sdp.clone()));
+        Ok(sdp)
+    }
+
+    async fn request_offer(&self, token: &str) -> Result<(String, Bytes), MediaEngineError> {
         let res = reqwest::ClientBuilder::new()
             .timeout(Duration::from_secs(3))
             .build()
@@ -52,39 +60,42 @@ impl MediaRtpEngineOffer {
             let endpoint = res.headers().get("Location").ok_or(MediaEngineError::MissingLocation)?;
             let location = endpoint.to_str().map_err(|_e| MediaEngineError::InvalidLocation)?.to_string();
             let sdp = res.bytes().await?;
-            log::info!("[RtpEngineOffer] created offer {location}");
-            self.offer = Some((location, sdp.clone()));
-            Ok(sdp)
+            Ok((location, sdp))
         } else {
             log::error!("[RtpEngineOffer] create offer error {status}");
             Err(MediaEngineError::InvalidStatus(status))
         }
     }
 
+    #[tracing::instrument(name = "rtpengine_set_answer", skip_all)]
     pub async fn set_answer(&mut self, sdp: Bytes) -> Result<(), MediaEngineError> {
         let (location, _) = self.offer.as_ref().expect("should call after create_offer success");
         let url = format!("{}{}", self.api.gateway(), location);
         log::info!("[RtpEngineOffer] sending answer {url}");
 
-        let res = reqwest::ClientBuilder::new()
-            .timeout(Duration::from_secs(3))
-            .build()
-            .expect("Should create client")
-            .patch(&url)
-            .header("Content-Type", "application/sdp")
-            .body(sdp)
-            .send()
-            .await?;
+        observe_request("rtpengine_set_answer", async {
+            let res = reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(3))
+                .build()
+                .expect("Should create client")
+                .patch(&url)
+                .header("Content-Type", "application/sdp")
+                .body(sdp)
+                .send()
+                .await?;
 
-        let status = res.status().as_u16();
-        if status == 200 {
-            log::info!("[RtpEngineOffer] sent answer {url}");
-            self.answered = true;
-            Ok(())
-        } else {
-            log::error!("[RtpEngineOffer] send answer error {url} {status}");
-            Err(MediaEngineError::InvalidStatus(status))
-        }
+            let status = res.status().as_u16();
+            if status == 200 {
+                log::info!("[RtpEngineOffer] sent answer {url}");
+                Ok(())
+            } else {
+                log::error!("[RtpEngineOffer] send answer error {url} {status}");
+                Err(MediaEngineError::InvalidStatus(status))
+            }
+        })
+        .await?;
+        self.answered = true;
+        Ok(())
     }
 }
 
@@ -92,7 +103,7 @@ impl Drop for MediaRtpEngineOffer {
     fn drop(&mut self) {
         if let Some((location, _)) = self.offer.take() {
             let url = format!("{}{}", self.api.gateway(), location);
-            tokio::spawn(async move {
+            tokio::spawn(observe_request("rtpengine_delete", async move {
                 log::info!("[RtpEngineOffer] destroying {url}");
                 let res = reqwest::ClientBuilder::new()
                     .timeout(Duration::from_secs(3))
@@ -110,7 +121,7 @@ impl Drop for MediaRtpEngineOffer {
                     log::error!("[RtpEngineOffer] destroy error {url} {status}");
                     Err(MediaEngineError::InvalidStatus(status))
                 }
-            });
+            }));
         }
     }
 }
@@ -1,5 +1,10 @@
+mod hep;
 mod media;
 mod server;
+mod trace;
+mod transport;
 
+pub use hep::{HepConfig, HepExporter, HepTransport};
 pub use media::{MediaApi, MediaEngineError, MediaRtpEngineOffer};
-pub use server::{SipIncomingCall, SipIncomingCallOut, SipOutgoingCall, SipOutgoingCallOut, SipServer, SipServerError, SipServerOut};
+pub use server::{SipIncomingCall, SipIncomingCallError, SipIncomingCallOut, SipOutgoingCall, SipOutgoingCallOut, SipServer, SipServerError, SipServerOut};
+pub use trace::SipTrace;
@@ -3,7 +3,7 @@ use std::{
     net::{IpAddr, SocketAddr},
 };
 
-use ezk_sip_core::{transport::udp::Udp, Endpoint, LayerKey};
+use ezk_sip_core::{Endpoint, LayerKey};
 use ezk_sip_types::{
     header::typed::Contact,
     uri::{sip::SipUri, NameAddr},
@@ -15,10 +15,12 @@ use tokio::sync::mpsc::{channel, Receiver};
 
 use crate::protocol::{SipAuth, StreamingInfo};
 
+use super::{trace::SipTrace, transport::TracedUdp};
+
 mod incoming;
 mod outgoing;
 
-pub use incoming::{SipIncomingCall, SipIncomingCallOut};
+pub use incoming::{SipIncomingCall, SipIncomingCallError, SipIncomingCallOut};
 pub use outgoing::{SipOutgoingCall, SipOutgoingCallError, SipOutgoingCallOut};
 
 use super::MediaApi;
@@ -39,10 +41,11 @@ pub struct SipServer {
     dialog_layer: LayerKey<DialogLayer>,
     invite_layer: LayerKey<InviteLayer>,
     incoming_rx: Receiver<SipIncomingCall>,
+    trace: SipTrace,
 }
 
 impl SipServer {
-    pub async fn new(addr: SocketAddr, public_ip: IpAddr) -> io::Result<Self> {
+    pub async fn new(addr: SocketAddr, public_ip: IpAddr, trace: SipTrace) -> io::Result<Self> {
         let mut builder = Endpoint::builder();
 
         let dialog_layer = builder.add_layer(DialogLayer::default());
@@ -52,9 +55,9 @@ impl SipServer {
         let contact = Contact::new(NameAddr::uri(contact));
 
         let (incoming_tx, incoming_rx) = channel(10);
-        builder.add_layer(InviteAcceptLayer::new(incoming_tx, contact.clone(), dialog_layer, invite_layer));
+        builder.add_layer(InviteAcceptLayer::new(incoming_tx, contact.clone(), dialog_layer, invite_layer, trace.clone()));
 
-        Udp::spawn(&mut builder, addr, Some(SocketAddr::new(public_ip, addr.port()))).await?;
+        TracedUdp::spawn(&mut builder, addr, Some(SocketAddr::new(public_ip, addr.port())), trace.clone()).await?;
 
         // Build endpoint to start the SIP Stack
         let endpoint = builder.build();
@@ -65,6 +68,7 @@ impl SipServer {
             dialog_layer,
             invite_layer,
             incoming_rx,
+            trace,
         })
     }
 
@@ -80,6 +84,7 @@ impl SipServer {
             self.contact.clone(),
             auth,
             stream,
+            &self.trace,
         )
     }
 
@@ -4,8 +4,11 @@ use anyhow::anyhow;
 use ezk_sip_core::{Endpoint, IncomingRequest, Layer, LayerKey, MayTake};
 use ezk_sip_types::{
     header::typed::Contact,
-    uri::sip::{SipUri, UserPart},
-    Method,
+    uri::{
+        sip::{SipUri, UserPart},
+        NameAddr,
+    },
+    Code, Method,
 };
 use ezk_sip_ua::{
     dialog::{Dialog, DialogLayer},
@@ -17,8 +20,11 @@ use tokio::sync::{mpsc::Sender, Notify};
 use wait_state::WaitState;
 
 use crate::{
-    protocol::{protobuf::sip_gateway::incoming_call_data::IncomingCallEvent, InternalCallId, StreamingInfo},
-    sip::{MediaApi, MediaEngineError},
+    protocol::{protobuf::sip_gateway::incoming_call_data::IncomingCallEvent, CallState, InternalCallId, StreamingInfo},
+    sip::{
+        trace::{SipCallTrace, SipTrace},
+        MediaApi, MediaEngineError,
+    },
 };
 
 mod talking_state;
@@ -30,15 +36,17 @@ pub struct InviteAcceptLayer {
     dialog_layer: LayerKey<DialogLayer>,
     invite_layer: LayerKey<InviteLayer>,
     incoming_tx: Sender<SipIncomingCall>,
+    trace: SipTrace,
 }
 
 impl InviteAcceptLayer {
-    pub fn new(incoming_tx: Sender<SipIncomingCall>, contact: Contact, dialog_layer: LayerKey<DialogLayer>, invite_layer: LayerKey<InviteLayer>) -> Self {
+    pub fn new(incoming_tx: Sender<SipIncomingCall>, contact: Contact, dialog_layer: LayerKey<DialogLayer>, invite_layer: LayerKey<InviteLayer>, trace: SipTrace) -> Self {
         Self {
             contact,
             dialog_layer,
             invite_layer,
             incoming_tx,
+            trace,
         }
     }
 
@@ -57,6 +65,11 @@ impl InviteAcceptLayer {
         let remote = invite.tp_info.source;
         let offer_sdp = invite.body.clone();
 
+        let call_id = InternalCallId::random();
+        let trace = self.trace.start(&call_id);
+        trace.bind(&invite.base_headers.call_id.0);
+        trace.received_invite(remote, &invite.tp_info.buffer);
+
         let invite = request.take();
         let dialog = Dialog::new_server(endpoint.clone(), self.dialog_layer, &invite, self.contact.clone()).unwrap();
 
@@ -72,12 +85,12 @@ impl InviteAcceptLayer {
         )?;
 
         let call = SipIncomingCall {
-            call_id: InternalCallId::random(),
+            call_id,
             state: State::Wait(WaitState::new(acceptor, offer_sdp, cancelled)),
             remote,
             from,
             to,
-            ctx: Ctx {},
+            ctx: Ctx { trace: Arc::new(trace) },
         };
         self.incoming_tx.send(call).await.expect("should send call to main loop");
         Ok(())
@@ -114,7 +127,16 @@ pub enum SipIncomingCallOut {
     Continue,
 }
 
-struct Ctx {}
+struct Ctx {
+    /// Shared with the task which sends the reject response of a killed call, so the response is still stored
+    trace: Arc<SipCallTrace>,
+}
+
+/// Extra header of the reject response of a killed call
+enum RejectHeader {
+    Contact(Contact),
+    RetryAfter(u32),
+}
 
 enum StateOut {
     Event(IncomingCallEvent),
@@ -127,7 +149,7 @@ trait StateLogic {
     fn send_ringing(&mut self, ctx: &mut Ctx) -> impl std::future::Future<Output = Result<(), SipIncomingCallError>>;
     fn accept(&mut self, ctx: &mut Ctx, api: MediaApi, stream: StreamingInfo) -> impl std::future::Future<Output = Result<(), SipIncomingCallError>>;
     fn end(&mut self, ctx: &mut Ctx) -> impl std::future::Future<Output = Result<(), SipIncomingCallError>>;
-    fn kill_because_validate_failed(self, ctx: &mut Ctx);
+    fn kill(self, ctx: &mut Ctx, code: Code, extra: Option<RejectHeader>);
     fn recv(&mut self, ctx: &mut Ctx) -> impl std::future::Future<Output = Result<Option<StateOut>, SipIncomingCallError>>;
 }
 
@@ -165,10 +187,10 @@ impl StateLogic for State {
         }
     }
 
-    fn kill_because_validate_failed(self, ctx: &mut Ctx) {
+    fn kill(self, ctx: &mut Ctx, code: Code, extra: Option<RejectHeader>) {
         match self {
-            State::Wait(state) => state.kill_because_validate_failed(ctx),
-            State::Talking(state) => state.kill_because_validate_failed(ctx),
+            State::Wait(state) => state.kill(ctx, code, extra),
+            State::Talking(state) => state.kill(ctx, code, extra),
         }
     }
 
@@ -206,24 +228,66 @@ impl SipIncomingCall {
         &self.to
     }
 
+    pub fn state(&self) -> CallState {
+        match self.state {
+            State::Wait(_) => CallState::Wait,
+            State::Talking(_) => CallState::Talking,
+        }
+    }
+
+    #[tracing::instrument(name = "sip_send_trying", skip_all)]
     pub async fn send_trying(&mut self) -> Result<(), SipIncomingCallError> {
         self.state.send_trying(&mut self.ctx).await
     }
 
+    #[tracing::instrument(name = "sip_send_ringing", skip_all)]
     pub async fn send_ringing(&mut self) -> Result<(), SipIncomingCallError> {
         self.state.send_ringing(&mut self.ctx).await
     }
 
+    #[tracing::instrument(name = "sip_accept", skip_all)]
     pub async fn accept(&mut self, api: MediaApi, stream: StreamingInfo) -> Result<(), SipIncomingCallError> {
         self.state.accept(&mut self.ctx, api, stream).await
     }
 
+    #[tracing::instrument(name = "sip_end", skip_all)]
     pub async fn end(&mut self) -> Result<(), SipIncomingCallError> {
         self.state.end(&mut self.ctx).await
     }
 
-    pub fn kill_because_validate_failed(mut self) {
-        self.state.kill_because_validate_failed(&mut self.ctx);
+    pub fn kill_because_validate_failed(self) {
+        self.kill(Code::NOT_ACCEPTABLE, None);
+    }
+
+    pub fn kill_because_limit_reached(self) {
+        self.kill(Code::BUSY_HERE, None);
+    }
+
+    pub fn kill_because_unavailable(self) {
+        self.kill(Code::TEMPORARILY_UNAVAILABLE, None);
+    }
+
+    /// Reject with 503 Service Unavailable while the node is draining, the caller can retry to other nodes
+    pub fn kill_because_draining(self, retry_after_secs: u32) {
+        self.kill(Code::SERVICE_UNAVAILABLE, Some(RejectHeader::RetryAfter(retry_after_secs)));
+    }
+
+    /// Reject with 503 Service Unavailable when the node reached its call limit
+    pub fn kill_because_overloaded(self) {
+        self.kill(Code::SERVICE_UNAVAILABLE, None);
+    }
+
+    pub fn kill_with_code(self, code: u16) {
+        self.kill(Code::from(code), None);
+    }
+
+    /// Reject with 302 Moved Temporarily, the caller is expected to retry to the target
+    pub fn redirect(self, target: SipUri) {
+        self.kill(Code::MOVED_TEMPORARILY, Some(RejectHeader::Contact(Contact::new(NameAddr::uri(target)))));
+    }
+
+    fn kill(mut self, code: Code, extra: Option<RejectHeader>) {
+        self.state.kill(&mut self.ctx, code, extra);
     }
 
     pub async fn recv(&mut self) -> Result<Option<SipIncomingCallOut>, SipIncomingCallError> {
@@ -231,7 +295,9 @@ impl SipIncomingCall {
             Some(out) => match out {
                 StateOut::Event(event) => Ok(Some(SipIncomingCallOut::Event(event))),
                 StateOut::Switch(state, event) => {
+                    let from = self.state();
                     self.state = state;
+                    tracing::info!(from = ?from, to = ?self.state(), "sip state switched");
                     Ok(Some(SipIncomingCallOut::Event(event)))
                 }
                 StateOut::Continue => Ok(Some(SipIncomingCallOut::Continue)),
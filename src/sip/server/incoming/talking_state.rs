@@ -1,3 +1,4 @@
+use ezk_sip_types::Code;
 use ezk_sip_ua::invite::session::Session;
 
 use crate::{
@@ -11,7 +12,7 @@ use crate::{
     sip::{media::MediaRtpEngineAnswer, MediaApi},
 };
 
-use super::{Ctx, SipIncomingCallError, StateLogic, StateOut};
+use super::{Ctx, RejectHeader, SipIncomingCallError, StateLogic, StateOut};
 
 pub struct TalkingState {
     session: Session,
@@ -43,7 +44,7 @@ impl StateLogic for TalkingState {
         Ok(())
     }
 
-    fn kill_because_validate_failed(self, _ctx: &mut Ctx) {
+    fn kill(self, _ctx: &mut Ctx, _code: Code, _extra: Option<RejectHeader>) {
         panic!("should not call on talking state")
     }
 
@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use bytes::Bytes;
 use bytesstr::BytesStr;
-use ezk_sip_types::{header::typed::ContentType, Code};
+use ezk_sip_types::{header::typed::ContentType, Code, Name};
 use ezk_sip_ua::invite::acceptor::Acceptor;
 use tokio::sync::{
     mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
@@ -22,7 +22,7 @@ use crate::{
     utils::select2,
 };
 
-use super::{talking_state::TalkingState, Ctx, SipIncomingCallError, State, StateLogic, StateOut};
+use super::{talking_state::TalkingState, Ctx, RejectHeader, SipIncomingCallError, State, StateLogic, StateOut};
 
 pub struct WaitState {
     cancelled: Arc<Notify>,
@@ -94,10 +94,12 @@ impl StateLogic for WaitState {
         Ok(())
     }
 
-    fn kill_because_validate_failed(mut self, _ctx: &mut Ctx) {
+    fn kill(mut self, ctx: &mut Ctx, code: Code, extra: Option<RejectHeader>) {
         let acceptor = self.acceptor.take().expect("should have acceptor when kill called");
+        let trace = ctx.trace.clone();
         tokio::spawn(async move {
-            reject_call(acceptor, Code::NOT_ACCEPTABLE).await.print_error("[SipIncoming] reject call");
+            reject_call(acceptor, code, extra).await.print_error("[SipIncoming] reject call");
+            drop(trace);
         });
     }
 
@@ -118,8 +120,13 @@ impl StateLogic for WaitState {
     }
 }
 
-async fn reject_call(acceptor: Acceptor, code: Code) -> anyhow::Result<()> {
-    let response = acceptor.create_response(code, None).await?;
+async fn reject_call(acceptor: Acceptor, code: Code, extra: Option<RejectHeader>) -> anyhow::Result<()> {
+    let mut response = acceptor.create_response(code, None).await?;
+    match extra {
+        Some(RejectHeader::Contact(contact)) => response.msg.headers.insert_named(&contact),
+        Some(RejectHeader::RetryAfter(secs)) => response.msg.headers.insert(Name::RETRY_AFTER, secs.to_string()),
+        None => {}
+    }
     acceptor.respond_failure(response).await?;
     Ok(())
 }
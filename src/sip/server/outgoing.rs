@@ -25,9 +25,12 @@ use crate::{
             outgoing_call_event::{self, sip_event},
             OutgoingCallEvent,
         },
-        InternalCallId, SipAuth, StreamingInfo,
+        CallState, InternalCallId, SipAuth, StreamingInfo,
+    },
+    sip::{
+        trace::{SipCallTrace, SipTrace},
+        MediaApi, MediaEngineError, MediaRtpEngineOffer,
     },
-    sip::{MediaApi, MediaEngineError, MediaRtpEngineOffer},
 };
 
 mod calling_state;
@@ -113,6 +116,7 @@ struct Ctx {
     initiator: Initiator,
     auth: Option<OutgoingAuth>,
     rtp: MediaRtpEngineOffer,
+    trace: SipCallTrace,
 }
 
 pub struct SipOutgoingCall {
@@ -133,6 +137,7 @@ impl SipOutgoingCall {
         contact: Contact,
         auth: Option<SipAuth>,
         stream: StreamingInfo,
+        trace: &SipTrace,
     ) -> Result<Self, SipOutgoingCallError> {
         let call_id: InternalCallId = InternalCallId::random();
         log::info!("[SipOutgoingCall {call_id}] create with {from} => {to}, auth {:?}", auth);
@@ -157,6 +162,7 @@ impl SipOutgoingCall {
             }
         });
 
+        let call_trace = trace.start(&call_id);
         Ok(Self {
             ctx: Ctx {
                 initiator,
@@ -164,6 +170,7 @@ impl SipOutgoingCall {
                 auth,
                 call_id,
                 rtp: MediaRtpEngineOffer::new(media_api, stream),
+                trace: call_trace,
             },
             state: State::Calling(CallingState::default()),
         })
@@ -173,10 +180,21 @@ impl SipOutgoingCall {
         self.ctx.call_id.clone()
     }
 
+    pub fn state(&self) -> CallState {
+        match self.state {
+            State::Calling(_) => CallState::Calling,
+            State::Early(_) => CallState::Early,
+            State::Talking(_) => CallState::Talking,
+            State::Canceling(_) => CallState::Canceling,
+        }
+    }
+
+    #[tracing::instrument(name = "sip_start", skip_all)]
     pub async fn start(&mut self) -> Result<(), SipOutgoingCallError> {
         self.state.start(&mut self.ctx).await
     }
 
+    #[tracing::instrument(name = "sip_end", skip_all)]
     pub async fn end(&mut self) -> Result<(), SipOutgoingCallError> {
         self.state.end(&mut self.ctx).await
     }
@@ -186,7 +204,9 @@ impl SipOutgoingCall {
             Some(out) => match out {
                 StateOut::Event(event) => Ok(Some(SipOutgoingCallOut::Event(event))),
                 StateOut::Switch(state, event) => {
+                    let from = self.state();
                     self.state = state;
+                    tracing::info!(from = ?from, to = ?self.state(), "sip state switched");
                     Ok(Some(SipOutgoingCallOut::Event(event)))
                 }
                 StateOut::Continue => Ok(Some(SipOutgoingCallOut::Continue)),
@@ -1,5 +1,5 @@
 use bytesstr::BytesStr;
-use ezk_sip_types::header::typed::ContentType;
+use ezk_sip_types::header::typed::{CallID, ContentType};
 use ezk_sip_ua::invite::{create_ack, initiator::Response};
 
 use crate::{
@@ -38,6 +38,9 @@ impl StateLogic for CallingState {
             auth.session.authorize_request(&mut invite.headers);
         }
 
+        if let Ok(sip_call_id) = invite.headers.get_named::<CallID>() {
+            ctx.trace.bind(&sip_call_id.0);
+        }
         log::info!("[CallingState] send invite");
         ctx.initiator.send_invite(invite).await?;
         Ok(())
@@ -0,0 +1,303 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    str::from_utf8,
+    sync::Arc,
+    time::Duration,
+};
+
+use atm0s_small_p2p::now_ms;
+use spin::RwLock;
+use tokio::time::Instant;
+
+use crate::protocol::{AppId, CallSipTrace, InternalCallId, SipTraceDirection, SipTraceMessage};
+
+use super::hep::HepExporter;
+
+#[derive(Default)]
+struct CallTrace {
+    app_id: Option<AppId>,
+    sip_call_ids: Vec<String>,
+    messages: VecDeque<SipTraceMessage>,
+    dropped: u64,
+    ended_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct TraceState {
+    calls: HashMap<InternalCallId, CallTrace>,
+    /// SIP Call-ID to call, for messages captured by the transport
+    sip_calls: HashMap<String, InternalCallId>,
+}
+
+impl TraceState {
+    fn remove(&mut self, call_id: &InternalCallId) {
+        if let Some(trace) = self.calls.remove(call_id) {
+            for sip_call_id in trace.sip_call_ids {
+                self.sip_calls.remove(&sip_call_id);
+            }
+        }
+    }
+
+    fn remove_expired(&mut self, retention: Duration) {
+        let expired: Vec<_> = self
+            .calls
+            .iter()
+            .filter(|(_, trace)| trace.ended_at.is_some_and(|ended_at| ended_at.elapsed() >= retention))
+            .map(|(call_id, _)| call_id.clone())
+            .collect();
+        for call_id in expired {
+            self.remove(&call_id);
+        }
+    }
+}
+
+/// Per-call ring buffers of SIP messages, and the optional HEP mirror of all captured messages.
+///
+/// Each call keeps its last `capacity` messages, 0 disables tracing. Traces of ended calls are kept for `retention`,
+/// expired ones are removed when a call starts or a trace is read.
+#[derive(Clone)]
+pub struct SipTrace {
+    capacity: usize,
+    retention: Duration,
+    state: Arc<RwLock<TraceState>>,
+    hep: Option<HepExporter>,
+}
+
+impl SipTrace {
+    pub fn new(capacity: usize, retention: Duration, hep: Option<HepExporter>) -> Self {
+        Self {
+            capacity,
+            retention,
+            state: Default::default(),
+            hep,
+        }
+    }
+
+    /// Traces are readable after the call ended
+    pub fn retained(&self) -> bool {
+        self.capacity > 0 && !self.retention.is_zero()
+    }
+
+    /// Start the trace of a call, it ends when the returned handle is dropped
+    pub fn start(&self, call_id: &InternalCallId) -> SipCallTrace {
+        if self.capacity > 0 {
+            let mut state = self.state.write();
+            state.remove_expired(self.retention);
+            state.calls.insert(call_id.clone(), CallTrace::default());
+        }
+        SipCallTrace {
+            trace: self.clone(),
+            call_id: call_id.clone(),
+        }
+    }
+
+    /// Traces without app are only readable with the root secret
+    pub fn set_app(&self, call_id: &InternalCallId, app_id: &AppId) {
+        if let Some(trace) = self.state.write().calls.get_mut(call_id) {
+            trace.app_id = Some(app_id.clone());
+        }
+    }
+
+    /// Get the trace of a call, None app_id means any app
+    pub fn get(&self, call_id: &InternalCallId, app_id: Option<&AppId>) -> Option<CallSipTrace> {
+        let mut state = self.state.write();
+        state.remove_expired(self.retention);
+        let trace = state.calls.get(call_id)?;
+        if app_id.is_some_and(|app_id| trace.app_id.as_ref() != Some(app_id)) {
+            return None;
+        }
+        Some(CallSipTrace {
+            call_id: call_id.to_string(),
+            dropped: trace.dropped,
+            messages: trace.messages.iter().cloned().collect(),
+        })
+    }
+
+    /// Capture a message on the wire, it is mirrored as is and stored when its Call-ID is bound to a call
+    pub fn capture(&self, direction: SipTraceDirection, remote: SocketAddr, message: &[u8]) {
+        let sip_call_id = header_call_id(message);
+        if let Some(hep) = &self.hep {
+            hep.send(direction, remote, sip_call_id.unwrap_or_default(), message);
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        let Some(call_id) = sip_call_id.and_then(|sip_call_id| self.state.read().sip_calls.get(sip_call_id).cloned()) else {
+            return;
+        };
+        self.store(&call_id, direction, remote, String::from_utf8_lossy(message).into_owned());
+    }
+
+    fn bind(&self, call_id: &InternalCallId, sip_call_id: &str) {
+        let mut state = self.state.write();
+        if let Some(trace) = state.calls.get_mut(call_id) {
+            if !trace.sip_call_ids.iter().any(|id| id == sip_call_id) {
+                trace.sip_call_ids.push(sip_call_id.to_owned());
+                state.sip_calls.insert(sip_call_id.to_owned(), call_id.clone());
+            }
+        }
+    }
+
+    fn store(&self, call_id: &InternalCallId, direction: SipTraceDirection, remote: SocketAddr, message: String) {
+        let mut state = self.state.write();
+        if let Some(trace) = state.calls.get_mut(call_id) {
+            if trace.messages.len() >= self.capacity {
+                trace.messages.pop_front();
+                trace.dropped += 1;
+            }
+            trace.messages.push_back(SipTraceMessage {
+                at: now_ms(),
+                direction,
+                remote: Some(remote.to_string()),
+                message,
+            });
+        }
+    }
+
+    fn end(&self, call_id: &InternalCallId) {
+        let mut state = self.state.write();
+        if self.retention.is_zero() {
+            state.remove(call_id);
+        } else if let Some(trace) = state.calls.get_mut(call_id) {
+            trace.ended_at.get_or_insert_with(Instant::now);
+        }
+    }
+}
+
+/// Trace handle of a single call, owned by its SIP state machine
+pub struct SipCallTrace {
+    trace: SipTrace,
+    call_id: InternalCallId,
+}
+
+impl SipCallTrace {
+    /// Messages of this SIP Call-ID are stored into the call trace when the transport captures them
+    pub fn bind(&self, sip_call_id: &str) {
+        self.trace.bind(&self.call_id, sip_call_id);
+    }
+
+    /// The INVITE of an incoming call, which the transport captured before the call was bound
+    pub fn received_invite(&self, remote: SocketAddr, message: &[u8]) {
+        self.trace.store(&self.call_id, SipTraceDirection::Received, remote, String::from_utf8_lossy(message).into_owned());
+    }
+}
+
+impl Drop for SipCallTrace {
+    fn drop(&mut self) {
+        self.trace.end(&self.call_id);
+    }
+}
+
+/// Call-ID header value of a raw message, also in its compact form `i`
+fn header_call_id(message: &[u8]) -> Option<&str> {
+    for line in message.split(|b| *b == b'\n').skip(1) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            break;
+        }
+        let Some(colon) = line.iter().position(|b| *b == b':') else {
+            continue;
+        };
+        let name = line[..colon].trim_ascii();
+        if name.eq_ignore_ascii_case(b"call-id") || name.eq_ignore_ascii_case(b"i") {
+            return from_utf8(line[colon + 1..].trim_ascii()).ok();
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use crate::sip::{HepConfig, HepTransport};
+
+    use super::*;
+
+    fn message(sip_call_id: &str, cseq: u32) -> Vec<u8> {
+        format!("OPTIONS sip:100@10.0.0.2 SIP/2.0\r\nCall-ID: {sip_call_id}\r\nCSeq: {cseq} OPTIONS\r\n\r\n").into_bytes()
+    }
+
+    fn remote() -> SocketAddr {
+        "10.0.0.2:5060".parse().expect("should parse")
+    }
+
+    #[test]
+    fn header_call_id_in_full_and_compact_form() {
+        assert_eq!(header_call_id(&message("abc@host", 1)), Some("abc@host"));
+        assert_eq!(header_call_id(b"SIP/2.0 200 OK\r\ni:  def@host \r\n\r\n"), Some("def@host"));
+        assert_eq!(header_call_id(b"SIP/2.0 200 OK\r\nTo: <sip:100@host>\r\n\r\nCall-ID: body"), None);
+    }
+
+    #[tokio::test]
+    async fn mirrors_messages_of_untraced_calls() {
+        let collector = UdpSocket::bind("127.0.0.1:0").await.expect("should bind collector");
+        let cfg = HepConfig {
+            collector: collector.local_addr().expect("should have local addr"),
+            transport: HepTransport::Udp,
+            capture_id: 2001,
+            auth_key: None,
+        };
+        let hep = HepExporter::new(cfg, "10.0.0.1:5060".parse().expect("should parse"));
+        let trace = SipTrace::new(0, Duration::ZERO, Some(hep));
+
+        trace.capture(SipTraceDirection::Received, remote(), &message("abc@host", 1));
+
+        let mut buf = vec![0; 2048];
+        let len = tokio::time::timeout(Duration::from_secs(2), collector.recv(&mut buf))
+            .await
+            .expect("should receive before timeout")
+            .expect("should receive packet");
+        assert!(buf[..len].ends_with(&message("abc@host", 1)));
+    }
+
+    #[test]
+    fn ring_buffer_drops_oldest_messages() {
+        let trace = SipTrace::new(2, Duration::ZERO, None);
+        let call_id = InternalCallId::random();
+        let call_trace = trace.start(&call_id);
+        call_trace.bind("abc@host");
+
+        for cseq in 1..=3 {
+            trace.capture(SipTraceDirection::Sent, remote(), &message("abc@host", cseq));
+        }
+        trace.capture(SipTraceDirection::Received, remote(), &message("other@host", 1));
+
+        let got = trace.get(&call_id, None).expect("should have trace");
+        assert_eq!(got.dropped, 1);
+        assert_eq!(got.messages.len(), 2);
+        assert!(got.messages[0].message.contains("CSeq: 2 OPTIONS"));
+        assert!(got.messages[1].message.contains("CSeq: 3 OPTIONS"));
+        assert_eq!(got.messages[0].remote.as_deref(), Some("10.0.0.2:5060"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ended_traces_are_removed_after_retention() {
+        let trace = SipTrace::new(10, Duration::from_millis(50), None);
+        let call_id = InternalCallId::random();
+        let call_trace = trace.start(&call_id);
+        call_trace.bind("abc@host");
+        drop(call_trace);
+
+        // still readable and capturing late messages during the retention
+        trace.capture(SipTraceDirection::Received, remote(), &message("abc@host", 2));
+        assert_eq!(trace.get(&call_id, None).expect("should keep ended trace").messages.len(), 1);
+
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert!(trace.get(&call_id, None).is_none());
+        assert!(!trace.state.read().sip_calls.contains_key("abc@host"));
+    }
+
+    #[test]
+    fn ended_traces_are_removed_without_retention() {
+        let trace = SipTrace::new(10, Duration::ZERO, None);
+        let call_id = InternalCallId::random();
+        let call_trace = trace.start(&call_id);
+        call_trace.bind("abc@host");
+        drop(call_trace);
+
+        assert!(trace.get(&call_id, None).is_none());
+        assert!(trace.state.read().sip_calls.is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+use std::{fmt, io, net::SocketAddr, str::from_utf8, sync::Arc};
+
+use bytes::Bytes;
+use ezk_sip_core::{
+    transport::{Direction, ReceivedMessage, TpHandle, Transport},
+    Endpoint, EndpointBuilder,
+};
+use ezk_sip_types::{
+    header::typed::ContentLength,
+    msg::{Line, MessageLine, PullParser},
+    parse::{ParseCtx, Parser},
+    Headers,
+};
+use tokio::{net::UdpSocket, sync::broadcast};
+
+use crate::protocol::SipTraceDirection;
+
+use super::trace::SipTrace;
+
+const UDP: &str = "UDP";
+const MAX_MSG_SIZE: usize = u16::MAX as usize;
+
+#[derive(Debug)]
+struct Inner {
+    bound: SocketAddr,
+    sent_by: SocketAddr,
+    socket: UdpSocket,
+}
+
+/// UDP transport of the SIP endpoint which captures every message on the wire into [`SipTrace`].
+///
+/// Capturing here instead of in the call state machines also covers the messages which the SIP stack sends or answers on its own,
+/// like in-dialog BYE, responses to in-dialog requests and the 487 of a cancelled INVITE.
+pub struct TracedUdp {
+    inner: Arc<Inner>,
+    trace: SipTrace,
+}
+
+impl fmt::Debug for TracedUdp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TracedUdp").field("inner", &self.inner).finish()
+    }
+}
+
+impl fmt::Display for TracedUdp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "udp:bound={}", self.inner.bound)
+    }
+}
+
+impl TracedUdp {
+    /// `public` is the address which remote sides see, used as sent-by of Via headers
+    pub async fn spawn(builder: &mut EndpointBuilder, addr: SocketAddr, public: Option<SocketAddr>, trace: SipTrace) -> io::Result<TpHandle> {
+        let socket = UdpSocket::bind(addr).await?;
+        let bound = socket.local_addr()?;
+        log::info!("[TracedUdp] bound to {bound}, public {public:?}");
+
+        let inner = Arc::new(Inner {
+            bound,
+            sent_by: public.unwrap_or(bound),
+            socket,
+        });
+        let handle = TpHandle::new(TracedUdp {
+            inner: inner.clone(),
+            trace: trace.clone(),
+        });
+        tokio::spawn(receive_task(builder.subscribe(), inner, handle.clone(), trace));
+        builder.add_unmanaged_transport(handle.clone());
+        Ok(handle)
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for TracedUdp {
+    fn name(&self) -> &'static str {
+        UDP
+    }
+
+    fn secure(&self) -> bool {
+        false
+    }
+
+    fn reliable(&self) -> bool {
+        false
+    }
+
+    fn bound(&self) -> SocketAddr {
+        self.inner.bound
+    }
+
+    fn sent_by(&self) -> SocketAddr {
+        self.inner.sent_by
+    }
+
+    fn direction(&self) -> Direction {
+        Direction::None
+    }
+
+    async fn send(&self, message: &[u8], target: SocketAddr) -> io::Result<()> {
+        self.trace.capture(SipTraceDirection::Sent, target, message);
+        self.inner.socket.send_to(message, target).await.map(|_| ())
+    }
+}
+
+async fn receive_task(mut endpoint: broadcast::Receiver<Endpoint>, inner: Arc<Inner>, handle: TpHandle, trace: SipTrace) {
+    let Ok(endpoint) = endpoint.recv().await else {
+        return;
+    };
+
+    let mut buffer = vec![0u8; MAX_MSG_SIZE];
+    loop {
+        let (len, remote) = match inner.socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                log::error!("[TracedUdp] recv error {e:?}");
+                continue;
+            }
+        };
+        let bytes = &buffer[..len];
+
+        match bytes {
+            b"\r\n\r\n" => {
+                if let Err(e) = inner.socket.send_to(b"\r\n", remote).await {
+                    log::error!("[TracedUdp] send keepalive response to {remote} error {e:?}");
+                }
+            }
+            b"\r\n" => {}
+            _ => {
+                trace.capture(SipTraceDirection::Received, remote, bytes);
+                if let Some((line, headers, body, buffer)) = parse_message(endpoint.parser(), bytes) {
+                    endpoint.receive(ReceivedMessage::new(remote, buffer, handle.clone(), line, headers, body));
+                }
+            }
+        }
+    }
+}
+
+/// Parse a complete SIP message from a datagram, the body is the rest of the datagram when Content-Length is missing
+fn parse_message(parser: Parser, bytes: &[u8]) -> Option<(MessageLine, Headers, Bytes, Bytes)> {
+    let buffer = Bytes::copy_from_slice(bytes);
+    let mut lines = PullParser::new(&buffer, 0);
+    let mut message_line = None;
+    let mut headers = Headers::new();
+
+    for line in &mut lines {
+        let Ok(line) = line else {
+            log::warn!("[TracedUdp] incomplete sip message");
+            return None;
+        };
+        let Ok(line) = from_utf8(line) else {
+            log::warn!("[TracedUdp] invalid utf8 in sip message head");
+            return None;
+        };
+
+        if message_line.is_none() {
+            match MessageLine::parse(ParseCtx::new(&buffer, parser))(line) {
+                Ok((_, parsed)) => message_line = Some(parsed),
+                Err(_) => {
+                    log::warn!("[TracedUdp] invalid request/status line {line:?}");
+                    return None;
+                }
+            }
+        } else {
+            match Line::parse(&buffer, line) {
+                Ok((_, parsed)) => headers.insert(parsed.name, parsed.value),
+                Err(_) => {
+                    log::warn!("[TracedUdp] malformed header line {line:?}");
+                    return None;
+                }
+            }
+        }
+    }
+
+    let head_end = lines.head_end();
+    let body = match headers.get_named::<ContentLength>() {
+        Ok(len) if len.0 == 0 => Bytes::new(),
+        Ok(len) if buffer.len() >= head_end + len.0 => buffer.slice(head_end..head_end + len.0),
+        Ok(_) => {
+            log::warn!("[TracedUdp] incomplete sip message body");
+            return None;
+        }
+        Err(_) => buffer.slice(head_end..),
+    };
+
+    Some((message_line?, headers, body, buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::protocol::InternalCallId;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn captures_requests_and_responses_of_the_stack() {
+        let trace = SipTrace::new(10, Duration::ZERO, None);
+        let call_id = InternalCallId::random();
+        let call_trace = trace.start(&call_id);
+        call_trace.bind("abc@host");
+
+        let mut builder = Endpoint::builder();
+        let handle = TracedUdp::spawn(&mut builder, "127.0.0.1:0".parse().expect("should parse"), None, trace.clone())
+            .await
+            .expect("should bind");
+        let _endpoint = builder.build();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.expect("should bind client");
+        let client_addr = client.local_addr().expect("should have local addr");
+        let bye = format!(
+            "BYE sip:atm0s@{server} SIP/2.0\r\n\
+             Via: SIP/2.0/UDP {client_addr};branch=z9hG4bK-test\r\n\
+             From: <sip:100@{client_addr}>;tag=a\r\n\
+             To: <sip:atm0s@{server}>;tag=b\r\n\
+             Call-ID: abc@host\r\n\
+             CSeq: 2 BYE\r\n\
+             Content-Length: 0\r\n\r\n",
+            server = handle.bound()
+        );
+        client.send_to(bye.as_bytes(), handle.bound()).await.expect("should send bye");
+
+        // the dialog doesn't exist, so the SIP stack answers on its own
+        let mut buf = vec![0; 2048];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf))
+            .await
+            .expect("should receive before timeout")
+            .expect("should receive response");
+        assert!(buf[..len].starts_with(b"SIP/2.0 481"));
+
+        let got = trace.get(&call_id, None).expect("should have trace");
+        assert_eq!(got.messages.len(), 2);
+        assert_eq!(got.messages[0].direction, SipTraceDirection::Received);
+        assert_eq!(got.messages[0].message, bye);
+        assert_eq!(got.messages[1].direction, SipTraceDirection::Sent);
+        assert!(got.messages[1].message.starts_with("SIP/2.0 481"));
+        assert_eq!(got.messages[1].remote, Some(client_addr.to_string()));
+    }
+}
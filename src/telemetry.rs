@@ -0,0 +1,68 @@
+use opentelemetry::{
+    global,
+    trace::{SpanContext, TraceContextExt, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::LevelFilter, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, Registry};
+
+/// Change the log level at runtime, from config reloads
+#[derive(Clone)]
+pub struct LogLevelHandle(reload::Handle<LevelFilter, Registry>);
+
+impl LogLevelHandle {
+    pub fn set(&self, level: LevelFilter) -> anyhow::Result<()> {
+        self.0.reload(level)?;
+        // logs of the log crate are filtered before they are bridged to tracing
+        log::set_max_level(log_level(level));
+        Ok(())
+    }
+}
+
+/// Init logging, and span export over OTLP gRPC when an endpoint is configured.
+/// Without an endpoint spans are only used as log context, so there is no export overhead
+pub fn init_tracing(otlp_endpoint: Option<&str>, service_name: &str, level: LevelFilter) -> anyhow::Result<LogLevelHandle> {
+    let otel_layer = match otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = SpanExporter::builder().with_tonic().with_endpoint(endpoint).build()?;
+            let provider = TracerProvider::builder()
+                .with_batch_exporter(exporter, runtime::Tokio)
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", service_name.to_string())]))
+                .build();
+            let tracer = provider.tracer(service_name.to_string());
+            global::set_tracer_provider(provider);
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+
+    let (level_layer, handle) = reload::Layer::new(level);
+    tracing_subscriber::registry().with(level_layer).with(fmt::layer()).with(otel_layer).try_init()?;
+    log::set_max_level(log_level(level));
+    Ok(LogLevelHandle(handle))
+}
+
+/// Context of the current span for linking work which outlives it, None when spans are not exported
+pub fn current_span_context() -> Option<SpanContext> {
+    let context = tracing::Span::current().context();
+    let span_context = context.span().span_context().clone();
+    span_context.is_valid().then_some(span_context)
+}
+
+/// Export spans which are still buffered, called before the process exits
+pub fn shutdown_tracing() {
+    global::shutdown_tracer_provider();
+}
+
+fn log_level(level: LevelFilter) -> log::LevelFilter {
+    match level {
+        LevelFilter::OFF => log::LevelFilter::Off,
+        LevelFilter::ERROR => log::LevelFilter::Error,
+        LevelFilter::WARN => log::LevelFilter::Warn,
+        LevelFilter::INFO => log::LevelFilter::Info,
+        LevelFilter::DEBUG => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
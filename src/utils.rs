@@ -1,5 +1,7 @@
 use std::{future::Future, marker::PhantomData};
 
+use tokio::{sync::watch, time::Instant};
+
 #[allow(unused)]
 pub mod select2;
 #[allow(unused)]
@@ -18,6 +20,21 @@ impl<O> Future for DummyFuture<O> {
     }
 }
 
+/// Resolve at the deadline, never resolve without deadline
+pub async fn wait_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolve when the value is changed, never resolve after the sender is dropped
+pub async fn wait_changed<T>(rx: &mut watch::Receiver<T>) {
+    if rx.changed().await.is_err() {
+        std::future::pending().await
+    }
+}
+
 impl<T1, T2, T3> From<select2::OrOutput<T1, T2>> for select3::OrOutput<T1, T2, T3> {
     fn from(value: select2::OrOutput<T1, T2>) -> Self {
         match value {